@@ -0,0 +1,92 @@
+//! run the bots against an in-process grid once per movement encoding and compare the bytes they receive,
+//! `cargo run -p client --example move_bandwidth -- [bots] [seconds]`
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use client::{ClientConfig, start_client};
+use grid::config::{ServerConfig, StorageConfig};
+use grid::server::serve;
+use protocol::movement::MoveSettings;
+use protocol::transport::{BoxConnection, memory_transport};
+
+/// counts the bytes read from the server
+struct Counted {
+    inner: BoxConnection,
+    read: Arc<AtomicU64>,
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.read.fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// bytes received by all the bots of a fresh world in `duration`
+async fn measure(bots: usize, duration: Duration, movement: MoveSettings) -> anyhow::Result<u64> {
+    let (listener, connector) = memory_transport();
+    let config = ServerConfig { storage: StorageConfig::Memory, ..Default::default() };
+    serve(&config, vec![(Box::new(listener), None)], None)?;
+    let read = Arc::new(AtomicU64::new(0));
+    let mut handles = vec![];
+    for _ in 0..bots {
+        let connection = Counted { inner: connector.connect()?, read: read.clone() };
+        handles.push(tokio::spawn(start_client(Box::new(connection), ClientConfig { movement, ..Default::default() })));
+    }
+    tokio::time::sleep(duration).await;
+    let received = read.load(Ordering::Relaxed);
+    for handle in handles {
+        handle.abort();
+    }
+    Ok(received)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let bots = std::env::args().nth(1).map(|n| n.parse()).transpose()?.unwrap_or(8);
+    let seconds = std::env::args().nth(2).map(|n| n.parse()).transpose()?.unwrap_or(5);
+    let duration = Duration::from_secs(seconds);
+    println!("{} bots for {}s, bytes received from the server", bots, seconds);
+    let mut full = None;
+    let modes = [
+        ("full", MoveSettings::default()),
+        ("quantized", MoveSettings::quantized(false)),
+        ("relative", MoveSettings::quantized(true)),
+        ("snapshot", MoveSettings::snapshot()),
+    ];
+    for (name, movement) in modes {
+        let received = measure(bots, duration, movement).await?;
+        let full = *full.get_or_insert(received);
+        println!(
+            "{:<10} {:>10} bytes {:>8.0} B/s per bot {:>6.1}% of full",
+            name,
+            received,
+            received as f64 / seconds as f64 / bots as f64,
+            received as f64 * 100. / full as f64,
+        );
+    }
+    Ok(())
+}
@@ -1,29 +1,28 @@
 use std::collections::LinkedList;
-use std::time::SystemTime;
+use std::time::Duration;
 
 use futures::SinkExt;
-use futures::stream::SplitSink;
 use log::{info, warn};
-use protobuf::{MessageDyn, MessageField, MessageFull};
+use protobuf::{MessageDyn, MessageField};
 use rand::{Rng, thread_rng};
-use tokio_kcp::KcpStream;
-use tokio_util::codec::Framed;
 
-use protocol::codec::ProtoCodec;
-use protocol::mapper::cast;
-use protocol::test::{PlayerMoveNotify, PlayerState, SCPlayerMoveNotify};
+use protocol::codec::{Compression, Packet, PacketSink};
+use protocol::message::{DirectionMessage, ScMessage};
+use protocol::movement::{MoveDeltaDecoder, MoveSettings, QuantizedState};
+use protocol::snapshot::SnapshotDecoder;
+use protocol::rpc::Rpc;
+use protocol::test::{DatagramChannel, LoginReq, MoveDeltaAck, PingAck, PlayerMoveNotify, PlayerState, SCPlayerMoveDelta, SCPing, SCPlayerMoveNotify, SCWorldSnapshot, SnapshotAck};
 
+use crate::datagram::DatagramClient;
 use crate::TICK_DURATION;
 
-const MOVE_SPEED: f32 = 20.;
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
 
-const HORIZONTAL_BOUNDARY: f32 = 10.;
+/// ticks between two binds of the datagram channel
+const DATAGRAM_BIND_TICKS: u64 = 10;
 
-const VERTICAL_BOUNDARY: f32 = 10.;
-
-type Tx = tokio::sync::mpsc::UnboundedSender<ClientMessage>;
+pub type Tx = tokio::sync::mpsc::UnboundedSender<ClientMessage>;
 type Rx = tokio::sync::mpsc::UnboundedReceiver<ClientMessage>;
-type MessageSink = SplitSink<Framed<KcpStream, ProtoCodec>, Box<dyn MessageDyn>>;
 
 pub enum ClientMessage {
     Proto(Box<dyn MessageDyn>),
@@ -32,19 +31,43 @@ pub enum ClientMessage {
 
 pub struct Client {
     pub player_id: i32,
-    pub conn: MessageSink,
-    pub tx: Tx,
+    pub resume_token: String,
+    pub conn: PacketSink,
+    pub rpc: Rpc,
+    pub compression: Compression,
+    /// the encoding the server accepted in LoginResp
+    pub movement: MoveSettings,
+    pub move_decoder: MoveDeltaDecoder,
+    /// the last sequence sent in MoveDeltaAck
+    pub acked_sequence: u32,
+    pub snapshot_decoder: SnapshotDecoder,
+    /// the own state in the last snapshot
+    pub snapshot_state: Option<QuantizedState>,
+    /// issued in LoginResp if asked for
+    pub datagram_channel: Option<DatagramChannel>,
+    pub datagram: Option<DatagramClient>,
+    pub ticks: u64,
     pub rx: Rx,
     pub pending_states: LinkedList<PlayerState>,
     pub current_state: PlayerState,
 }
 
 impl Client {
-    pub fn new(conn: MessageSink, tx: Tx, rx: Rx) -> Self {
+    pub fn new(conn: PacketSink, rpc: Rpc, compression: Compression, rx: Rx) -> Self {
         Self {
             player_id: 0,
+            resume_token: String::new(),
             conn,
-            tx,
+            rpc,
+            compression,
+            movement: MoveSettings::default(),
+            move_decoder: MoveDeltaDecoder::new(),
+            acked_sequence: 0,
+            snapshot_decoder: SnapshotDecoder::new(),
+            snapshot_state: None,
+            datagram_channel: None,
+            datagram: None,
+            ticks: 0,
             rx,
             pending_states: LinkedList::new(),
             current_state: PlayerState::new(),
@@ -53,16 +76,44 @@ impl Client {
 
     pub fn move_player(&mut self) -> anyhow::Result<()> {
         let delta_mills = TICK_DURATION.as_secs_f32();
-        let move_delta = delta_mills as f32 * self.current_state.speed;
+        let move_delta = delta_mills * self.current_state.speed;
         self.current_state.x += move_delta;
         self.current_state.y += move_delta;
         Ok(())
     }
 
+    /// login and wait for the LoginResp answering it
+    pub async fn login(&mut self, req: LoginReq) -> anyhow::Result<()> {
+        let resp = self.rpc.call(&mut self.conn, Box::new(req), CALL_TIMEOUT).await?;
+        info!("{} {}",resp.descriptor_dyn().name(),resp);
+        match ScMessage::from_dyn(resp)? {
+            ScMessage::LoginResp(resp) => {
+                self.player_id = resp.player_id;
+                self.compression.negotiate(resp.compression);
+                self.movement = MoveSettings::accepted(&resp);
+                self.datagram_channel = resp.datagram.clone().into_option();
+                self.resume_token = resp.resume_token;
+                Ok(())
+            }
+            ScMessage::ErrorResp(resp) => Err(anyhow::anyhow!("login failed {:?} {}", resp.code, resp.reason)),
+            resp => Err(anyhow::anyhow!("unexpected login resp {:?}", resp)),
+        }
+    }
+
     pub async fn notify_server(&mut self, new_state: PlayerState) -> anyhow::Result<()> {
         let mut notify = PlayerMoveNotify::new();
         notify.state = MessageField::some(new_state);
-        self.conn.send(Box::new(notify)).await?;
+        self.send_notify(Box::new(notify)).await
+    }
+
+    /// over the datagram channel if the message may go there, the stream otherwise
+    pub async fn send_notify(&mut self, msg: Box<dyn MessageDyn>) -> anyhow::Result<()> {
+        if let Some(datagram) = &mut self.datagram {
+            if datagram.try_send(msg.as_ref()).await? {
+                return Ok(());
+            }
+        }
+        self.conn.send(Packet::notify(msg)).await?;
         Ok(())
     }
 
@@ -75,12 +126,26 @@ impl Client {
                 Some(message) => {
                     match message {
                         ClientMessage::Proto(resp) => {
-                            let desc = resp.descriptor_dyn();
-                            let msg_name = desc.name();
-                            info!("{} {}",msg_name,resp);
-                            if msg_name == SCPlayerMoveNotify::descriptor().name() {
-                                let notify = cast::<SCPlayerMoveNotify>(resp).unwrap();
-                                self.handle_sc_player_move_notify(notify)
+                            info!("{} {}",resp.descriptor_dyn().name(),resp);
+                            match ScMessage::from_dyn(resp).unwrap() {
+                                ScMessage::SCPlayerMoveNotify(notify) => {
+                                    self.handle_sc_player_move_notify(notify)
+                                }
+                                ScMessage::SCPlayerMoveDelta(delta) => {
+                                    self.handle_sc_player_move_delta(delta)
+                                }
+                                ScMessage::SCWorldSnapshot(snapshot) => {
+                                    self.handle_sc_world_snapshot(snapshot).await
+                                }
+                                ScMessage::SCPing(ping) => {
+                                    self.handle_sc_ping(ping).await
+                                }
+                                ScMessage::TestResp(_)
+                                | ScMessage::LoginResp(_)
+                                | ScMessage::SCPlayerEnterNotify(_)
+                                | ScMessage::SCPlayerLeaveNotify(_)
+                                | ScMessage::SCOtherPlayersStateNotify(_)
+                                | ScMessage::ErrorResp(_) => {}
                             }
                         }
                         ClientMessage::Tick => {
@@ -93,6 +158,21 @@ impl Client {
     }
 
     async fn handle_tick(&mut self) {
+        self.ticks += 1;
+        if let Some(datagram) = &mut self.datagram {
+            if self.ticks.is_multiple_of(DATAGRAM_BIND_TICKS) {
+                if let Err(err) = datagram.bind().await {
+                    warn!("bind datagram channel err {}",err);
+                }
+            }
+        }
+        //一个tick确认一次收到的移动
+        if self.move_decoder.received() > self.acked_sequence {
+            self.acked_sequence = self.move_decoder.received();
+            let mut ack = MoveDeltaAck::new();
+            ack.sequence = self.acked_sequence;
+            self.conn.send(Packet::notify(Box::new(ack))).await.unwrap();
+        }
         //通知服务端进行移动
         self.notify_server(self.current_state.clone()).await.unwrap();
         self.pending_states.push_back(self.current_state.clone());
@@ -110,7 +190,7 @@ impl Client {
         }
     }
 
-    fn handle_sc_player_move_notify(&mut self, notify: Box<SCPlayerMoveNotify>) {
+    fn handle_sc_player_move_notify(&mut self, notify: SCPlayerMoveNotify) {
         if notify.player_id == self.player_id {
             //服务器权威输入
             let authoritative_state = notify.state.unwrap();
@@ -124,12 +204,68 @@ impl Client {
             }
         }
     }
-}
 
-pub fn get_system_time() -> u128 {
-    return SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+    fn handle_sc_player_move_delta(&mut self, delta: SCPlayerMoveDelta) {
+        let authoritative_state = match self.move_decoder.decode(&delta) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("decode move delta err {}",err);
+                return;
+            }
+        };
+        if delta.player_id == self.player_id {
+            //定点数比较, 量化后相同即为相同
+            if let Some(pending_state) = self.pending_states.pop_front() {
+                if authoritative_state != QuantizedState::quantize(&pending_state) {
+                    self.current_state = authoritative_state.to_state();
+                    self.pending_states.clear();
+                    warn!("状态回滚:{}=>{}",pending_state,self.current_state);
+                }
+            }
+        }
+    }
+
+    /// the server measures the round trip of the connection
+    async fn handle_sc_ping(&mut self, ping: SCPing) {
+        let mut ack = PingAck::new();
+        ack.sequence = ping.sequence;
+        if let Err(err) = self.send_notify(Box::new(ack)).await {
+            warn!("client:{} send ping ack err {}", self.player_id, err);
+        }
+    }
+
+    async fn handle_sc_world_snapshot(&mut self, msg: SCWorldSnapshot) {
+        let authoritative_state = match self.snapshot_decoder.decode(&msg) {
+            Ok(snapshot) => snapshot.get(&self.player_id).copied(),
+            Err(err) => {
+                //the server falls back to a full snapshot once the baseline is out of its window
+                warn!("decode snapshot err {}",err);
+                return;
+            }
+        };
+        let mut ack = SnapshotAck::new();
+        ack.sequence = msg.sequence;
+        if let Err(err) = self.send_notify(Box::new(ack)).await {
+            warn!("ack snapshot err {}",err);
+        }
+        let Some(authoritative_state) = authoritative_state else {
+            return;
+        };
+        //快照按世界tick生成, 可能跳过或重复某个输入
+        if self.snapshot_state.replace(authoritative_state) == Some(authoritative_state) {
+            return;
+        }
+        while let Some(pending_state) = self.pending_states.pop_front() {
+            if QuantizedState::quantize(&pending_state) == authoritative_state {
+                return;
+            }
+        }
+        self.current_state = authoritative_state.to_state();
+        warn!("状态回滚:=>{}",self.current_state);
+    }
 }
 
+
 pub fn random_speed() -> f32 {
     let lower: f32 = -10.;
     let higher: f32 = 10.;
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use log::{error, warn};
+use protobuf::MessageDyn;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use protocol::datagram::{BIND_ID, DatagramKey, DatagramSealer, MAX_DATAGRAM_SIZE, Opened};
+use protocol::options::Direction;
+use protocol::registry::BUILTIN_REGISTRY;
+use protocol::test::DatagramChannel;
+
+use crate::client::{ClientMessage, Tx};
+
+/// the client end of the datagram channel issued in LoginResp
+pub struct DatagramClient {
+    socket: Arc<UdpSocket>,
+    sealer: DatagramSealer,
+    buf: BytesMut,
+    receive_handle: JoinHandle<()>,
+}
+
+impl DatagramClient {
+    /// connect to the advertised address and tell the server ours, datagrams from the server go to the client loop
+    pub async fn connect(channel: &DatagramChannel, tx: Tx) -> anyhow::Result<Self> {
+        let key = DatagramKey::from_proto(channel)?;
+        let (sealer, mut opener) = key.split(false);
+        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        socket.connect(&channel.addr).await?;
+        let receive = socket.clone();
+        let receive_handle = tokio::spawn(async move {
+            loop {
+                let mut buf = BytesMut::with_capacity(MAX_DATAGRAM_SIZE);
+                if let Err(err) = receive.recv_buf(&mut buf).await {
+                    warn!("datagram recv err {}",err);
+                    continue;
+                }
+                match opener.open(&BUILTIN_REGISTRY, Direction::SC, buf) {
+                    Ok(Some(Opened::Message(msg))) => {
+                        if tx.send(ClientMessage::Proto(msg)).is_err() {
+                            break;
+                        }
+                    }
+                    //a newer one about the same player arrived first, the server never sends binds
+                    Ok(_) => {}
+                    Err(err) => warn!("datagram dropped {}",err),
+                }
+            }
+        });
+        let mut client = Self { socket, sealer, buf: BytesMut::new(), receive_handle };
+        client.bind().await?;
+        Ok(client)
+    }
+
+    /// the server only sends datagrams to the address it heard from last, also keeps a nat mapping alive
+    pub async fn bind(&mut self) -> anyhow::Result<()> {
+        self.buf.clear();
+        self.sealer.seal(BIND_ID, &[], &mut self.buf)?;
+        self.socket.send(&self.buf).await?;
+        Ok(())
+    }
+
+    /// send the message as a datagram if it declares `unreliable`, false if it must go over the stream
+    pub async fn try_send(&mut self, msg: &dyn MessageDyn) -> anyhow::Result<bool> {
+        let Some((id, body)) = DatagramSealer::unreliable_msg(&BUILTIN_REGISTRY, Direction::CS, msg)? else {
+            return Ok(false);
+        };
+        self.buf.clear();
+        if let Err(err) = self.sealer.seal(id, &body, &mut self.buf) {
+            error!("seal datagram err {}",err);
+            return Ok(false);
+        }
+        self.socket.send(&self.buf).await?;
+        Ok(true)
+    }
+}
+
+impl Drop for DatagramClient {
+    fn drop(&mut self) {
+        self.receive_handle.abort();
+    }
+}
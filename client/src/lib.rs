@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use log::{error, info, warn};
+use rand::{Rng, thread_rng};
+
+use protocol::codec::{CodecConfig, Packet};
+use protocol::kcp::KcpProfile;
+use protocol::movement::MoveSettings;
+use protocol::rpc::Rpc;
+use protocol::test::LoginReq;
+use protocol::transport::{BoxConnection, framed_with_config};
+
+use crate::client::{Client, ClientMessage};
+use crate::datagram::DatagramClient;
+
+pub mod client;
+pub mod datagram;
+
+pub const TICK_DURATION: Duration = Duration::from_millis(100);
+
+/// what a bot asks the server for at login, and how it connects
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientConfig {
+    /// passed to [protocol::transport::Endpoint::connect_with], should match the profile of the server
+    pub kcp: KcpProfile,
+    /// moves of the other players are asked for in this encoding
+    pub movement: MoveSettings,
+    /// ask for a datagram channel, moves skip the stream if the server has one
+    pub datagram: bool,
+}
+
+/// run a bot moving around on the connection until the server goes away
+pub async fn start_client(connection: BoxConnection, config: ClientConfig) {
+    //an outdated client is told to upgrade by the server before anything else
+    let (sink, mut stream, compression) = match framed_with_config::<Packet>(connection, false, CodecConfig::default()).await {
+        Ok(framed) => framed,
+        Err(err) => {
+            error!("{}", err);
+            return;
+        }
+    };
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let rpc = Rpc::new();
+    let mut client = Client::new(sink, rpc.clone(), compression, rx);
+    let tx_clone = tx.clone();
+    //responses go to the call waiting for them, everything else to the client loop
+    tokio::spawn(async move {
+        let tx = tx_clone;
+        loop {
+            match stream.next().await {
+                None => {
+                    break;
+                }
+                Some(Ok(packet)) => {
+                    let Some(packet) = rpc.complete(packet) else {
+                        continue;
+                    };
+                    match tx.send(ClientMessage::Proto(packet.msg)) {
+                        Ok(_) => {}
+                        Err(err) => {
+                            error!("{}",err);
+                            break;
+                        }
+                    };
+                }
+                Some(Err(err)) => {
+                    error!("{}",err);
+                }
+            }
+        }
+    });
+    //the server trusts the claimed id as account id by default, the entity id comes back in LoginResp
+    let account_id = thread_rng().gen_range(0..10000);
+    info!("client:{} started", account_id);
+    let mut login = LoginReq::new();
+    login.player_id = account_id;
+    login.compression = client.compression.is_allowed();
+    config.movement.write_req(&mut login);
+    login.datagram = config.datagram;
+    if let Err(err) = client.login(login).await {
+        error!("client:{} {}", account_id, err);
+        return;
+    }
+    if let Some(channel) = &client.datagram_channel {
+        match DatagramClient::connect(channel, tx.clone()).await {
+            Ok(datagram) => client.datagram = Some(datagram),
+            //everything keeps going over the stream
+            Err(err) => warn!("client:{} connect datagram channel {} err {}", account_id, channel.addr, err),
+        }
+    }
+    tokio::spawn(async move {
+        let tx = tx.clone();
+        loop {
+            match tx.send(ClientMessage::Tick) {
+                Ok(_) => {}
+                Err(error) => {
+                    error!("{}",error);
+                    break;
+                }
+            };
+            tokio::time::sleep(TICK_DURATION).await;
+        }
+    });
+    client.start().await;
+}
@@ -1,32 +1,50 @@
 use std::time::Duration;
 
-use futures::{SinkExt, StreamExt};
-use log::{error, info};
-use protobuf::{Enum, MessageDyn};
-use rand::{Rng, thread_rng};
-use tokio_util::codec::Framed;
+use log::error;
 
-use protocol::codec::ProtoCodec;
-use protocol::mapper::kcp_config;
-use protocol::test::LoginReq;
-
-use crate::client::{Client, ClientMessage};
-
-mod client;
+use client::{ClientConfig, start_client};
+use protocol::kcp::KcpProfile;
+use protocol::movement::MoveSettings;
+use protocol::transport::Endpoint;
 
 const PLAYER_COUNT: usize = 2;
 
-const TICK_DURATION: Duration = Duration::from_millis(100);
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     std::env::set_var("RUST_LOG", "INFO");
     env_logger::init();
-    let addr = "127.0.0.1:4895";
+    //pass `tcp` or `ws` to connect over tcp or websocket instead of kcp
+    let endpoint = match std::env::args().nth(1).as_deref() {
+        Some("tcp") => Endpoint::Tcp("127.0.0.1:4895".to_string()),
+        Some("ws") => Endpoint::WebSocket("127.0.0.1:4896".to_string()),
+        _ => Endpoint::Kcp("127.0.0.1:4895".to_string()),
+    };
+    //then `quantized` or `relative` for delta encoded moves, optionally relative to the player,
+    //or `snapshot` for acked snapshots of the aoi view every world tick
+    let movement = match std::env::args().nth(2).as_deref() {
+        Some("quantized") => MoveSettings::quantized(false),
+        Some("relative") => MoveSettings::quantized(true),
+        Some("snapshot") => MoveSettings::snapshot(),
+        _ => MoveSettings::default(),
+    };
+    //moves go over a datagram channel next to kcp unless `reliable` is passed last
+    let datagram = matches!(endpoint, Endpoint::Kcp(_)) && std::env::args().nth(3).as_deref() != Some("reliable");
+    //KCP_PROFILE picks the tuning of kcp, a profile with optional key=value overrides, the same as the server's
+    let kcp = match std::env::var("KCP_PROFILE") {
+        Ok(profile) => profile.parse()?,
+        Err(_) => KcpProfile::default(),
+    };
+    let config = ClientConfig { kcp, movement, datagram };
     let mut clients = vec![];
     for _ in 0..PLAYER_COUNT {
         tokio::time::sleep(Duration::from_millis(200)).await;
-        let c = tokio::spawn(start_client(addr));
+        let endpoint = endpoint.clone();
+        let c = tokio::spawn(async move {
+            match endpoint.connect_with(config.kcp.settings()).await {
+                Ok(connection) => start_client(connection, config).await,
+                Err(err) => error!("connect {} err {}",endpoint,err),
+            }
+        });
         clients.push(c);
     }
     for c in clients {
@@ -39,56 +57,3 @@ async fn main() -> anyhow::Result<()> {
     }
     Ok(())
 }
-
-async fn start_client(addr: &str) {
-    let cfg = kcp_config();
-    let stream = tokio_kcp::KcpStream::connect(&cfg, addr.parse().unwrap()).await.unwrap();
-
-    let framed = Framed::new(stream, ProtoCodec::new(false));
-    let (sink, mut stream) = framed.split();
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-    let mut client = Client::new(sink, tx.clone(), rx);
-    let player_id = thread_rng().gen_range(0..10000);
-    info!("client:{} started", player_id);
-    client.player_id = player_id;
-    let mut login = LoginReq::new();
-    login.player_id = player_id;
-    client.conn.send(Box::new(login)).await.unwrap();
-    let tx_clone = tx.clone();
-    tokio::spawn(async move {
-        let mut tx = tx_clone;
-        loop {
-            match stream.next().await {
-                None => {
-                    break;
-                }
-                Some(Ok(resp)) => {
-                    match tx.send(ClientMessage::Proto(resp)) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("{}",err);
-                            break;
-                        }
-                    };
-                }
-                Some(Err(err)) => {
-                    error!("{}",err);
-                }
-            }
-        }
-    });
-    tokio::spawn(async move {
-        let mut tx = tx.clone();
-        loop {
-            match tx.send(ClientMessage::Tick) {
-                Ok(_) => {}
-                Err(error) => {
-                    error!("{}",error);
-                    break;
-                }
-            };
-            tokio::time::sleep(TICK_DURATION).await;
-        }
-    });
-    client.start().await;
-}
\ No newline at end of file
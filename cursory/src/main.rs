@@ -1,6 +1,6 @@
 use log::{error, info};
 
-use protocol::mapper::kcp_config;
+use protocol::transport::{accept_all, Endpoint};
 
 use crate::message::WorldMessageWrap;
 use crate::server::new_client;
@@ -17,25 +17,36 @@ mod world_handler;
 async fn main() -> anyhow::Result<()> {
     std::env::set_var("RUST_LOG", "INFO");
     env_logger::init();
-    let addr = "127.0.0.1:4895";
-    let cfg = kcp_config();
-    let mut listener = tokio_kcp::KcpListener::bind(cfg, addr).await?;
-    info!("server listening on: {}", addr);
+    let endpoints = [
+        Endpoint::Kcp("127.0.0.1:4895".to_string()),
+        Endpoint::Tcp("127.0.0.1:4895".to_string()),
+        Endpoint::WebSocket("127.0.0.1:4896".to_string()),
+    ];
+    let mut listeners = vec![];
+    for endpoint in &endpoints {
+        listeners.push(endpoint.bind().await?);
+        info!("server listening on: {}", endpoint);
+    }
+    let mut incoming = accept_all(listeners);
     let (world_sender, world_receiver) = tokio::sync::mpsc::unbounded_channel::<WorldMessageWrap>();
     start_world(world_receiver);
     loop {
         tokio::select! {
-            c = listener.accept() => {
+            Some(c) = incoming.recv() => {
                 match c {
-                    Ok((stream, socket_addr)) => {
-                        match new_client(stream, world_sender.clone()).await {
-                            Ok(_) => {
-                                info!("client:{} connected",socket_addr);
-                            }
-                            Err(err) => {
-                                error!("{} disconnected with err: {}",socket_addr,err);
-                            }
-                        };
+                    Ok((connection, addr)) => {
+                        let world_sender = world_sender.clone();
+                        //don't block the accept loop on the hello exchange
+                        tokio::spawn(async move {
+                            match new_client(connection, world_sender).await {
+                                Ok(_) => {
+                                    info!("client:{} connected",addr);
+                                }
+                                Err(err) => {
+                                    error!("{} disconnected with err: {}",addr,err);
+                                }
+                            };
+                        });
                     }
                     Err(err) => {
                         error!("server accept connection err:{}",err);
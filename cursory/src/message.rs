@@ -1,11 +1,9 @@
-use std::fmt::{Debug, Display};
+use std::fmt::Debug;
 
 use protobuf::MessageDyn;
 
 use crate::player::{PlayerMessageSender, ProtoMessageSender, State};
 
-pub struct WorldProtoMessage(pub i32, pub Box<dyn MessageDyn>);
-
 #[derive(Debug)]
 pub struct WorldMessageWrap {
     pub player_id: i32,
@@ -24,24 +22,7 @@ impl WorldMessageWrap {
 #[derive(Debug)]
 pub enum WorldMessage {
     PlayerLogin(PlayerMessageSender, ProtoMessageSender, State),
-    PlayerLogout,
     PlayerMove(Box<dyn MessageDyn>),
-    Proto(Box<dyn MessageDyn>),
-}
-
-#[derive(Debug)]
-pub struct PlayerMessageWrap {
-    pub world_id: i32,
-    pub message: PlayerMessage,
-}
-
-impl PlayerMessageWrap {
-    pub fn new(world_id: i32, message: PlayerMessage) -> Self {
-        Self {
-            world_id,
-            message,
-        }
-    }
 }
 
 #[derive(Debug, Clone)]
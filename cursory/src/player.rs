@@ -1,13 +1,24 @@
-use protobuf::MessageDyn;
+use futures::FutureExt;
+use lazy_static::lazy_static;
 use rand::{Rng, thread_rng};
 
-use protocol::test::{Color, PlayerState};
+use protocol::codec::Packet;
+use protocol::router::{LogMiddleware, Router};
+use protocol::test::{Color, LoginReq, PlayerMoveNotify, PlayerState};
 
-use crate::message::PlayerMessageWrap;
+use crate::message::PlayerMessage;
+use crate::player_handler::{handle_login_req, handle_move_notify};
 use crate::world::WorldMessageSender;
 
-pub type PlayerMessageSender = tokio::sync::mpsc::UnboundedSender<PlayerMessageWrap>;
-pub type ProtoMessageSender = tokio::sync::mpsc::UnboundedSender<Box<dyn MessageDyn>>;
+lazy_static! {
+    pub static ref PLAYER_ROUTER: Router<Player> = Router::new()
+        .layer(LogMiddleware)
+        .route::<LoginReq, _>(|player, _, req| handle_login_req(player, req).boxed())
+        .route::<PlayerMoveNotify, _>(|player, _, notify| handle_move_notify(player, notify).boxed());
+}
+
+pub type PlayerMessageSender = tokio::sync::mpsc::UnboundedSender<PlayerMessage>;
+pub type ProtoMessageSender = tokio::sync::mpsc::UnboundedSender<Packet>;
 
 pub struct Player {
     pub player_id: i32,
@@ -15,6 +26,8 @@ pub struct Player {
     pub proto_sender: ProtoMessageSender,
     pub state: State,
     pub world_sender: WorldMessageSender,
+    /// sequence of the request being handled, None for notifies
+    pub request_seq: Option<u32>,
 }
 
 impl Player {
@@ -25,6 +38,7 @@ impl Player {
             self_sender,
             world_sender,
             state: State::default(),
+            request_seq: None,
         }
     }
 }
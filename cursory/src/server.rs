@@ -1,21 +1,14 @@
-use std::future::Future;
-
 use futures::{SinkExt, StreamExt};
-use log::{error, info, warn};
-use protobuf::{Message, MessageDyn};
-use tokio_kcp::KcpStream;
-use tokio_util::codec::Framed;
+use log::{error, warn};
 
-use protocol::codec::{ProtoCodec, ProtoCodecError};
-use protocol::test::{LoginReq, PlayerMoveNotify};
+use protocol::codec::{CodecConfig, Packet, ProtoCodecError};
+use protocol::transport::{BoxConnection, framed_with_config};
 
-use crate::message::{PlayerMessageWrap, WorldMessageWrap};
-use crate::player::Player;
-use crate::player_handler::{handle_login_req, handle_move_notify};
+use crate::message::{PlayerMessage, WorldMessageWrap};
+use crate::player::{Player, PLAYER_ROUTER};
 
-pub async fn new_client(stream: KcpStream, world_sender: tokio::sync::mpsc::UnboundedSender<WorldMessageWrap>) -> anyhow::Result<()> {
-    let framed = Framed::new(stream, ProtoCodec::new(true));
-    let (mut sink, mut stream) = framed.split();
+pub async fn new_client(connection: BoxConnection, world_sender: tokio::sync::mpsc::UnboundedSender<WorldMessageWrap>) -> anyhow::Result<()> {
+    let (mut sink, mut stream, _) = framed_with_config::<Packet>(connection, true, CodecConfig::default()).await?;
     let (player_sender, mut player_receiver) = tokio::sync::mpsc::unbounded_channel();
     let (proto_sender, mut proto_receiver) = tokio::sync::mpsc::unbounded_channel();
     tokio::spawn(async move {
@@ -54,27 +47,17 @@ pub async fn new_client(stream: KcpStream, world_sender: tokio::sync::mpsc::Unbo
     Ok(())
 }
 
-async fn handle_client_req(player: &mut Player, req: Result<Box<dyn MessageDyn>, ProtoCodecError>) {
-    async fn handle_inner(player: &mut Player, req_name: &str, msg: Box<dyn MessageDyn>) -> anyhow::Result<()> {
-        match req_name {
-            LoginReq::NAME => handle_login_req(player, msg).await?,
-            PlayerMoveNotify::NAME => handle_move_notify(player, msg).await?,
-            _ => {
-                warn!("unhanded msg:{}",req_name);
-            }
-        }
-        Ok(())
-    }
+async fn handle_client_req(player: &mut Player, req: Result<Packet, ProtoCodecError>) {
     match req {
         Ok(req) => {
-            let desc = req.descriptor_dyn();
-            let req_name = desc.name();
-            match handle_inner(player, req_name, req).await {
+            player.request_seq = req.seq;
+            match PLAYER_ROUTER.dispatch(player, (), req.msg).await {
                 Ok(_) => {}
                 Err(err) => {
                     warn!("player:{} handle msg err:{}",player.player_id,err);
                 }
             }
+            player.request_seq = None;
         }
         Err(err) => {
             error!("player:{} handle client req err:{}",player.player_id,err)
@@ -82,8 +65,8 @@ async fn handle_client_req(player: &mut Player, req: Result<Box<dyn MessageDyn>,
     }
 }
 
-async fn handle_player_msg(player: &mut Player, wrap: Option<PlayerMessageWrap>) {
-    if let Some(wrap) = wrap {
-        todo!()
+async fn handle_player_msg(_player: &mut Player, msg: Option<PlayerMessage>) {
+    if let Some(msg) = msg {
+        match msg {}
     }
 }
\ No newline at end of file
@@ -1,8 +1,14 @@
 use std::collections::HashMap;
 
-use log::{error, info};
+use futures::FutureExt;
+use lazy_static::lazy_static;
+use log::error;
 use protobuf::MessageDyn;
 
+use protocol::codec::Packet;
+use protocol::router::{LogMiddleware, Router};
+use protocol::test::PlayerMoveNotify;
+
 use crate::message::{WorldMessage, WorldMessageWrap};
 use crate::player::{PlayerMessageSender, ProtoMessageSender, State};
 use crate::world_handler::handle_move_notify;
@@ -11,6 +17,12 @@ use crate::world_handler::handle_player_login;
 pub type WorldMessageSender = tokio::sync::mpsc::UnboundedSender<WorldMessageWrap>;
 pub type WorldMessageReceiver = tokio::sync::mpsc::UnboundedReceiver<WorldMessageWrap>;
 
+lazy_static! {
+    static ref WORLD_ROUTER: Router<World, i32> = Router::new()
+        .layer(LogMiddleware)
+        .route::<PlayerMoveNotify, _>(|world, player_id, notify| handle_move_notify(world, player_id, notify).boxed());
+}
+
 pub struct World {
     pub world_id: i32,
     pub sessions: HashMap<i32, (PlayerMessageSender, ProtoMessageSender)>,
@@ -27,14 +39,14 @@ impl World {
     }
     pub fn broad_cast_all(&mut self, msg: Box<dyn MessageDyn>) {
         for x in self.sessions.values() {
-            let _ = x.1.send(msg.clone());
+            let _ = x.1.send(Packet::notify(msg.clone()));
         }
     }
 
     pub fn broad_cast_others(&mut self, player_id: i32, msg: Box<dyn MessageDyn>) {
         for (&id, tx) in &self.sessions {
             if id != player_id {
-                let _ = tx.1.send(msg.clone());
+                let _ = tx.1.send(Packet::notify(msg.clone()));
             }
         }
     }
@@ -65,17 +77,8 @@ async fn inner_handler(world: &mut World, wrap: WorldMessageWrap) -> anyhow::Res
         WorldMessage::PlayerLogin(player_sender, proto_sender, state) => {
             handle_player_login(world, player_id, player_sender, proto_sender, state).await?;
         }
-        WorldMessage::PlayerLogout => {
-            todo!()
-        }
-        WorldMessage::Proto(proto_msg) => {
-            let desc = proto_msg.descriptor_dyn();
-            let msg_name = desc.name();
-            todo!()
-        }
-        WorldMessage::PlayerMove(move_notify) => {
-            info!("{}",move_notify);
-            handle_move_notify(world, player_id, move_notify).await?;
+        WorldMessage::PlayerMove(msg) => {
+            WORLD_ROUTER.dispatch(world, player_id, msg).await?;
         }
     }
     Ok(())
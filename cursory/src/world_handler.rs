@@ -1,8 +1,8 @@
 use anyhow::anyhow;
 use log::info;
-use protobuf::{MessageDyn, MessageField};
+use protobuf::MessageField;
 
-use protocol::mapper::cast;
+use protocol::codec::Packet;
 use protocol::test::{PlayerMoveNotify, SCOtherPlayersStateNotify, SCPlayerEnterNotify};
 use protocol::test::scother_players_state_notify::Bundle;
 use protocol::test::SCPlayerMoveNotify;
@@ -10,8 +10,7 @@ use protocol::test::SCPlayerMoveNotify;
 use crate::player::{PlayerMessageSender, ProtoMessageSender, State};
 use crate::world::World;
 
-pub async fn handle_move_notify(world: &mut World, player_id: i32, msg: Box<dyn MessageDyn>) -> anyhow::Result<()> {
-    let move_notify = cast::<PlayerMoveNotify>(msg)?;
+pub async fn handle_move_notify(world: &mut World, player_id: i32, move_notify: PlayerMoveNotify) -> anyhow::Result<()> {
     let state = world.player_state.get_mut(&player_id).ok_or(anyhow!("player:{} state not found",player_id))?;
     state.player_state = move_notify.state.clone().unwrap();
     let mut notify = SCPlayerMoveNotify::new();
@@ -39,7 +38,7 @@ pub async fn handle_player_login(world: &mut World, player_id: i32, player_sende
         }
     }
     let sender = &world.sessions[&player_id];
-    let _ = sender.1.send(Box::new(others_state_notify));
+    let _ = sender.1.send(Packet::notify(Box::new(others_state_notify)));
     info!("player {} login",player_id);
     Ok(())
 }
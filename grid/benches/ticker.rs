@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+
+use grid::event::ReceiveTimeoutEvent;
+use grid::message::EventMessage;
+use grid::tick::{ScheduleEvent, Ticker};
+
+const TIMERS: usize = 1000;
+
+/// the previous ticker, spawn a tokio task for every scheduled event
+struct SpawnTicker {
+    events: HashMap<String, JoinHandle<()>>,
+    event_sender: tokio::sync::mpsc::UnboundedSender<EventMessage>,
+    event_receiver: tokio::sync::mpsc::UnboundedReceiver<EventMessage>,
+}
+
+impl SpawnTicker {
+    fn new() -> Self {
+        let (event_sender, event_receiver) = tokio::sync::mpsc::unbounded_channel();
+        Self {
+            events: HashMap::new(),
+            event_sender,
+            event_receiver,
+        }
+    }
+
+    fn schedule_once(&mut self, delay: Duration, key: String, event: Box<dyn ScheduleEvent>) {
+        let sender = self.event_sender.clone();
+        let j = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = sender.send(EventMessage(event));
+        });
+        self.events.insert(key, j);
+    }
+
+    fn cancel(&mut self, key: String) -> bool {
+        if let Some(j) = self.events.remove(&key) {
+            j.abort();
+            true
+        } else {
+            false
+        }
+    }
+
+    async fn handle_event(&mut self) -> Option<EventMessage> {
+        self.event_receiver.recv().await
+    }
+}
+
+/// the receive timeout pattern of the player loop: reschedule and cancel on every message
+fn reschedule(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("reschedule");
+    group.bench_function("spawn", |b| b.iter(|| runtime.block_on(async {
+        let mut ticker = SpawnTicker::new();
+        for _ in 0..TIMERS {
+            ticker.schedule_once(Duration::from_secs(10), ReceiveTimeoutEvent.to_string(), Box::new(ReceiveTimeoutEvent));
+            ticker.cancel(ReceiveTimeoutEvent.to_string());
+        }
+    })));
+    group.bench_function("deadline", |b| b.iter(|| runtime.block_on(async {
+        let mut ticker = Ticker::new();
+        for _ in 0..TIMERS {
+            ticker.schedule_once(Duration::from_secs(10), ReceiveTimeoutEvent.to_string(), Box::new(ReceiveTimeoutEvent));
+            ticker.cancel(ReceiveTimeoutEvent.to_string());
+        }
+    })));
+    group.finish();
+}
+
+fn fire(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("fire");
+    group.bench_function("spawn", |b| b.iter(|| runtime.block_on(async {
+        let mut ticker = SpawnTicker::new();
+        for i in 0..TIMERS {
+            ticker.schedule_once(Duration::ZERO, i.to_string(), Box::new(ReceiveTimeoutEvent));
+        }
+        for _ in 0..TIMERS {
+            ticker.handle_event().await.unwrap();
+        }
+    })));
+    group.bench_function("deadline", |b| b.iter(|| runtime.block_on(async {
+        let mut ticker = Ticker::new();
+        for i in 0..TIMERS {
+            ticker.schedule_once(Duration::ZERO, i.to_string(), Box::new(ReceiveTimeoutEvent));
+        }
+        for _ in 0..TIMERS {
+            ticker.handle_event().await.unwrap();
+        }
+    })));
+    group.finish();
+}
+
+criterion_group!(benches, reschedule, fire);
+criterion_main!(benches);
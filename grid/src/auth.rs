@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use rand::{Rng, thread_rng};
+use sha2::Sha256;
+
+use protocol::test::LoginReq;
+
+use crate::config::AuthConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult {
+    /// the authoritative account id of the player
+    Accept(i32),
+    Reject(String),
+}
+
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, req: &LoginReq) -> AuthResult;
+}
+
+pub fn new_authenticator(config: &AuthConfig) -> anyhow::Result<Arc<dyn Authenticator>> {
+    let authenticator: Arc<dyn Authenticator> = match config {
+        AuthConfig::Trust => Arc::new(TrustAuthenticator),
+        AuthConfig::AccountFile(path) => Arc::new(FileAccountStore::load(path)?),
+        AuthConfig::HmacToken(secret) => Arc::new(HmacTokenAuthenticator::new(secret.as_bytes())),
+    };
+    Ok(authenticator)
+}
+
+/// trust the player id claimed by the client, only for local testing
+pub struct TrustAuthenticator;
+
+impl Authenticator for TrustAuthenticator {
+    fn authenticate(&self, req: &LoginReq) -> AuthResult {
+        AuthResult::Accept(req.player_id)
+    }
+}
+
+/// the pbkdf2 cost of new account entries, existing entries keep the cost they were hashed with
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub account_id: i32,
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub password_hash: Vec<u8>,
+}
+
+/// one account per line: `account:account_id:iterations:salt_hex:password_hash_hex`,
+/// the hash is `pbkdf2_hmac_sha256(password, salt, iterations)`, see [account_entry],
+/// empty lines and lines start with `#` are ignored
+pub struct FileAccountStore {
+    accounts: HashMap<String, Account>,
+    /// hashed against for unknown accounts, so the response time doesn't tell which accounts exist
+    dummy: Account,
+}
+
+impl FileAccountStore {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut accounts = HashMap::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split(':').collect();
+            if parts.len() != 5 {
+                return Err(anyhow!("invalid account entry at line {}", n + 1));
+            }
+            let account = Account {
+                account_id: parts[1].parse()?,
+                iterations: parts[2].parse()?,
+                salt: hex::decode(parts[3])?,
+                password_hash: hex::decode(parts[4])?,
+            };
+            if account.iterations == 0 || account.password_hash.is_empty() {
+                return Err(anyhow!("invalid account entry at line {}", n + 1));
+            }
+            if accounts.insert(parts[0].to_string(), account).is_some() {
+                return Err(anyhow!("duplicate account {} at line {}", parts[0], n + 1));
+            }
+        }
+        let iterations = accounts.values().map(|account| account.iterations).max().unwrap_or(PBKDF2_ITERATIONS);
+        let dummy = Account { account_id: 0, iterations, salt: vec![0; 16], password_hash: vec![0; 32] };
+        Ok(Self { accounts, dummy })
+    }
+}
+
+impl Authenticator for FileAccountStore {
+    fn authenticate(&self, req: &LoginReq) -> AuthResult {
+        let (account, known) = match self.accounts.get(&req.account) {
+            Some(account) => (account, true),
+            None => (&self.dummy, false),
+        };
+        let hash = hash_password(&account.salt, &req.password, account.iterations, account.password_hash.len());
+        if constant_time_eq(&hash, &account.password_hash) && known {
+            AuthResult::Accept(account.account_id)
+        } else {
+            AuthResult::Reject("invalid account or password".to_string())
+        }
+    }
+}
+
+/// slow on purpose, run it off the async workers
+pub fn hash_password(salt: &[u8], password: &str, iterations: u32, len: usize) -> Vec<u8> {
+    let mut hash = vec![0u8; len];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut hash);
+    hash
+}
+
+/// build an account entry with a random salt for [FileAccountStore], see [PBKDF2_ITERATIONS]
+pub fn account_entry(account: &str, account_id: i32, password: &str, iterations: u32) -> String {
+    let salt: [u8; 16] = thread_rng().gen();
+    let hash = hash_password(&salt, password, iterations, 32);
+    format!("{}:{}:{}:{}:{}", account, account_id, iterations, hex::encode(salt), hex::encode(hash))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// token format: `account_id.expire_at.signature_hex`, expire_at is unix timestamp in seconds,
+/// the signature is `hmac_sha256(secret, "account_id.expire_at")`
+pub struct HmacTokenAuthenticator {
+    secret: Vec<u8>,
+}
+
+impl HmacTokenAuthenticator {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self { secret: secret.into() }
+    }
+
+    pub fn sign(&self, account_id: i32, expire_at: u64) -> String {
+        let payload = format!("{}.{}", account_id, expire_at);
+        let signature = self.mac(&payload).finalize().into_bytes();
+        format!("{}.{}", payload, hex::encode(signature))
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac can take key of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn verify(&self, token: &str) -> anyhow::Result<i32> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(anyhow!("malformed token"))?;
+        let (account_id, expire_at) = payload.split_once('.').ok_or(anyhow!("malformed token"))?;
+        self.mac(payload)
+            .verify_slice(&hex::decode(signature)?)
+            .map_err(|_| anyhow!("bad token signature"))?;
+        let expire_at: u64 = expire_at.parse()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if now >= expire_at {
+            return Err(anyhow!("token expired"));
+        }
+        Ok(account_id.parse()?)
+    }
+}
+
+impl Authenticator for HmacTokenAuthenticator {
+    fn authenticate(&self, req: &LoginReq) -> AuthResult {
+        match self.verify(&req.auth_token) {
+            Ok(account_id) => AuthResult::Accept(account_id),
+            Err(err) => AuthResult::Reject(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use protocol::test::LoginReq;
+
+    use crate::auth::{account_entry, Authenticator, AuthResult, FileAccountStore, HmacTokenAuthenticator};
+
+    #[test]
+    fn test_file_account_store() {
+        //a low cost keeps the test fast
+        let content = format!("# accounts\n{}\n", account_entry("mikai", 233, "secret", 1000));
+        let store = FileAccountStore::parse(&content).unwrap();
+        let mut req = LoginReq::new();
+        req.account = "mikai".to_string();
+        req.password = "secret".to_string();
+        assert_eq!(store.authenticate(&req), AuthResult::Accept(233));
+        req.password = "wrong".to_string();
+        assert!(matches!(store.authenticate(&req), AuthResult::Reject(_)));
+        req.account = "nobody".to_string();
+        assert!(matches!(store.authenticate(&req), AuthResult::Reject(_)));
+    }
+
+    #[test]
+    fn test_hmac_token() {
+        let authenticator = HmacTokenAuthenticator::new("secret");
+        let mut req = LoginReq::new();
+        req.auth_token = authenticator.sign(233, u64::MAX);
+        assert_eq!(authenticator.authenticate(&req), AuthResult::Accept(233));
+        req.auth_token = HmacTokenAuthenticator::new("other").sign(233, u64::MAX);
+        assert!(matches!(authenticator.authenticate(&req), AuthResult::Reject(_)));
+        req.auth_token = authenticator.sign(233, 1);
+        assert!(matches!(authenticator.authenticate(&req), AuthResult::Reject(_)));
+    }
+}
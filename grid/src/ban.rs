@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use protocol::transport::PeerAddr;
+
+/// peers closed by [protocol::codec::DecodeErrorPolicy::Ban], their connections are dropped at accept until the ban expires
+pub struct BanList {
+    duration: Duration,
+    /// keyed by ip, a banned client can't come back from another port
+    banned: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl BanList {
+    pub fn new(duration: Duration) -> Self {
+        Self { duration, banned: Mutex::new(HashMap::new()) }
+    }
+
+    /// in-process peers have no address to ban, expired bans of peers which never came back are dropped here
+    pub fn ban(&self, addr: PeerAddr) {
+        if let PeerAddr::Socket(addr) = addr {
+            let now = Instant::now();
+            let mut banned = self.banned.lock().unwrap();
+            banned.retain(|_, until| *until > now);
+            banned.insert(addr.ip(), now + self.duration);
+        }
+    }
+
+    pub fn is_banned(&self, addr: PeerAddr) -> bool {
+        let PeerAddr::Socket(addr) = addr else {
+            return false;
+        };
+        let mut banned = self.banned.lock().unwrap();
+        match banned.get(&addr.ip()) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                banned.remove(&addr.ip());
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use protocol::transport::PeerAddr;
+
+    use crate::ban::BanList;
+
+    #[test]
+    fn test_ban_list() {
+        let bans = BanList::new(Duration::from_millis(50));
+        let addr = PeerAddr::Socket("10.0.0.1:4000".parse().unwrap());
+        bans.ban(addr);
+        assert!(bans.is_banned(PeerAddr::Socket("10.0.0.1:4001".parse().unwrap())));
+        assert!(!bans.is_banned(PeerAddr::Socket("10.0.0.2:4000".parse().unwrap())));
+        bans.ban(PeerAddr::Memory(1));
+        assert!(!bans.is_banned(PeerAddr::Memory(1)));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!bans.is_banned(addr));
+    }
+
+    #[test]
+    fn test_ban_list_prune() {
+        let bans = BanList::new(Duration::from_millis(50));
+        bans.ban(PeerAddr::Socket("10.0.0.1:4000".parse().unwrap()));
+        bans.ban(PeerAddr::Socket("10.0.0.2:4000".parse().unwrap()));
+        std::thread::sleep(Duration::from_millis(60));
+        //neither came back, the next ban drops both
+        bans.ban(PeerAddr::Socket("10.0.0.3:4000".parse().unwrap()));
+        assert_eq!(bans.banned.lock().unwrap().len(), 1);
+    }
+}
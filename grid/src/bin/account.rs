@@ -0,0 +1,29 @@
+use std::io::BufRead;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+
+use grid::auth::{account_entry, HmacTokenAuthenticator, PBKDF2_ITERATIONS};
+
+/// credentials for the authenticators of the server, the secret part is read from stdin so it stays out of
+/// the shell history:
+/// `account entry <account> <account_id>` prints a line of the account file for the password,
+/// `account token <account_id> <valid_secs>` prints a login token signed with the hmac secret
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let mut secret = String::new();
+    std::io::stdin().lock().read_line(&mut secret)?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    match args.as_slice() {
+        ["entry", account, account_id] => {
+            println!("{}", account_entry(account, account_id.parse()?, secret, PBKDF2_ITERATIONS));
+        }
+        ["token", account_id, valid_secs] => {
+            let expire_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + valid_secs.parse::<u64>()?;
+            println!("{}", HmacTokenAuthenticator::new(secret).sign(account_id.parse()?, expire_at));
+        }
+        _ => return Err(anyhow!("usage: account entry <account> <account_id> | account token <account_id> <valid_secs>")),
+    }
+    Ok(())
+}
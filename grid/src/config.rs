@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use protocol::codec::{CodecConfig, DecodeErrorPolicy};
+use protocol::kcp::KcpProfile;
+use protocol::transport::Endpoint;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// accept connections from all of them at once
+    pub listen: Vec<ListenerConfig>,
+    /// framing of every connection, clients must be configured the same way,
+    /// optional encryption is raised to required with [AuthConfig::AccountFile]
+    pub codec: CodecConfig,
+    /// tuning of the kcp listeners, clients should pick the same profile or settings
+    pub kcp: KcpProfile,
+    /// how often the kcp stats of every connection are logged, None to never log them and not ping for them
+    pub kcp_diagnostics_interval: Option<Duration>,
+    /// how often a kcp connection is pinged for its round trip
+    pub kcp_ping_interval: Duration,
+    /// how long a disconnected player stays in the world waiting for a resume
+    pub resume_grace_period: Duration,
+    /// how long a released entity id is kept before it can be assigned to another player
+    pub entity_id_reuse_delay: Duration,
+    pub auth: AuthConfig,
+    pub storage: StorageConfig,
+    /// how often the states of all players in the world are saved
+    pub save_interval: Duration,
+    /// how often the players with snapshot moves get a snapshot of their aoi view
+    pub snapshot_interval: Duration,
+    /// how long the address of a peer closed by [DecodeErrorPolicy::Ban] is refused
+    pub ban_duration: Duration,
+    pub write_batch: WriteBatchConfig,
+    /// the udp socket of the unreliable channel next to the stream, None to keep every message on the stream
+    pub datagram: Option<DatagramConfig>,
+}
+
+/// see [crate::datagram::DatagramServer]
+#[derive(Debug, Clone)]
+pub struct DatagramConfig {
+    pub bind: String,
+    /// the address clients send datagrams to if it isn't the bound one, e.g. behind a load balancer
+    pub advertise: Option<String>,
+}
+
+/// how the write task of a connection groups queued messages into one flush
+#[derive(Debug, Clone, Copy)]
+pub struct WriteBatchConfig {
+    /// messages encoded before a flush, 1 flushes every message on its own
+    pub max_messages: usize,
+    /// how long the first message of a batch may wait for more, zero only takes what is queued already
+    pub max_latency: Duration,
+}
+
+impl Default for WriteBatchConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 64,
+            max_latency: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub endpoint: Endpoint,
+    /// overrides [CodecConfig::decode_errors] for the connections of this listener
+    pub decode_errors: Option<DecodeErrorPolicy>,
+}
+
+impl From<Endpoint> for ListenerConfig {
+    fn from(endpoint: Endpoint) -> Self {
+        Self { endpoint, decode_errors: None }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthConfig {
+    /// trust the player id claimed by the client
+    Trust,
+    AccountFile(PathBuf),
+    HmacToken(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    Memory,
+    File(PathBuf),
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![
+                Endpoint::Kcp("127.0.0.1:4895".to_string()).into(),
+                Endpoint::Tcp("127.0.0.1:4895".to_string()).into(),
+                Endpoint::WebSocket("127.0.0.1:4896".to_string()).into(),
+            ],
+            codec: CodecConfig::default(),
+            kcp: KcpProfile::default(),
+            kcp_diagnostics_interval: Some(Duration::from_secs(60)),
+            kcp_ping_interval: Duration::from_secs(1),
+            resume_grace_period: Duration::from_secs(30),
+            entity_id_reuse_delay: Duration::from_secs(60),
+            auth: AuthConfig::Trust,
+            storage: StorageConfig::File(PathBuf::from("data/players")),
+            save_interval: Duration::from_secs(60),
+            snapshot_interval: Duration::from_millis(100),
+            ban_duration: Duration::from_secs(600),
+            write_batch: WriteBatchConfig::default(),
+            datagram: Some(DatagramConfig { bind: "127.0.0.1:4897".to_string(), advertise: None }),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+
+use bytes::BytesMut;
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+
+use protocol::codec::Outbound;
+use protocol::datagram::{DatagramKey, DatagramOpener, DatagramSealer, MAX_DATAGRAM_SIZE, Opened, token};
+use protocol::options::Direction;
+use protocol::registry::MessageRegistry;
+use protocol::test::DatagramChannel;
+
+use crate::config::DatagramConfig;
+use crate::message::{PlayerMessage, PlayerMessageSender, PlayerMessageWrap};
+
+/// the udp socket shared by the datagram channels of all the connections, a datagram finds its
+/// connection by the token issued in LoginResp
+pub struct DatagramServer {
+    socket: UdpSocket,
+    /// where clients send datagrams to, announced in LoginResp
+    advertised: String,
+    registry: Arc<MessageRegistry>,
+    routes: Mutex<HashMap<u64, Route>>,
+}
+
+struct Route {
+    opener: DatagramOpener,
+    link: Weak<DatagramLink>,
+    player: PlayerMessageSender,
+}
+
+impl DatagramServer {
+    pub async fn bind(config: &DatagramConfig, registry: Arc<MessageRegistry>) -> io::Result<Arc<Self>> {
+        let socket = UdpSocket::bind(&config.bind).await?;
+        let advertised = match &config.advertise {
+            Some(advertise) => advertise.clone(),
+            //a port 0 bind is only known once bound
+            None => socket.local_addr()?.to_string(),
+        };
+        info!("datagram channel on udp://{} advertised as {}",socket.local_addr()?,advertised);
+        let server = Arc::new(Self { socket, advertised, registry, routes: Mutex::new(HashMap::new()) });
+        tokio::spawn(Self::receive(server.clone()));
+        Ok(server)
+    }
+
+    /// a link for a new connection, it stays closed until the client asks for a channel at login
+    pub fn link(self: &Arc<Self>) -> Arc<DatagramLink> {
+        Arc::new(DatagramLink { server: self.clone(), state: Mutex::new(None) })
+    }
+
+    async fn receive(server: Arc<Self>) {
+        loop {
+            let mut buf = BytesMut::with_capacity(MAX_DATAGRAM_SIZE);
+            let addr = match server.socket.recv_buf_from(&mut buf).await {
+                Ok((_, addr)) => addr,
+                Err(err) => {
+                    //e.g. icmp port unreachable of a gone client on windows, the socket is still fine
+                    debug!("datagram recv err {}",err);
+                    continue;
+                }
+            };
+            if let Err(err) = server.dispatch(buf, addr) {
+                debug!("{} datagram dropped {}",addr,err);
+            }
+        }
+    }
+
+    fn dispatch(&self, buf: BytesMut, addr: SocketAddr) -> anyhow::Result<()> {
+        let token = token(&buf).ok_or_else(|| anyhow::anyhow!("datagram too short {}", buf.len()))?;
+        //the lock is released before the link is touched, dropping the last link takes it again
+        let (opened, link, player) = {
+            let mut routes = self.routes.lock().unwrap();
+            let route = routes.get_mut(&token).ok_or_else(|| anyhow::anyhow!("unknown datagram token"))?;
+            (route.opener.open(&self.registry, Direction::CS, buf)?, route.link.clone(), route.player.clone())
+        };
+        let Some(opened) = opened else {
+            return Ok(());
+        };
+        if let Some(link) = link.upgrade() {
+            link.bind(addr);
+        }
+        if let Opened::Message(msg) = opened {
+            let _ = player.send(PlayerMessageWrap::new(0, PlayerMessage::Datagram(msg)));
+        }
+        Ok(())
+    }
+}
+
+/// the datagram channel of one connection, shared by the player and its write task
+pub struct DatagramLink {
+    server: Arc<DatagramServer>,
+    state: Mutex<Option<LinkState>>,
+}
+
+struct LinkState {
+    token: u64,
+    sealer: DatagramSealer,
+    /// the address of the last datagram from the client, nothing is sent before it is known
+    peer: Option<SocketAddr>,
+    /// reused for every datagram
+    buf: BytesMut,
+    channel: DatagramChannel,
+}
+
+impl DatagramLink {
+    /// issue the key of the channel, datagrams from the client go to the player, opening twice returns the same channel
+    pub fn open(self: &Arc<Self>, player: PlayerMessageSender) -> DatagramChannel {
+        if let Some(state) = self.state.lock().unwrap().as_ref() {
+            return state.channel.clone();
+        }
+        let key = DatagramKey::generate();
+        let (sealer, opener) = key.split(true);
+        let channel = key.to_proto(self.server.advertised.clone());
+        self.server.routes.lock().unwrap().insert(key.token, Route { opener, link: Arc::downgrade(self), player });
+        *self.state.lock().unwrap() = Some(LinkState { token: key.token, sealer, peer: None, buf: BytesMut::new(), channel: channel.clone() });
+        channel
+    }
+
+    fn bind(&self, addr: SocketAddr) {
+        if let Some(state) = self.state.lock().unwrap().as_mut() {
+            //follows a client behind a nat rebinding its port
+            state.peer = Some(addr);
+        }
+    }
+
+    /// send the message as a datagram if it declares `unreliable` and the client told its address,
+    /// false if it must go over the stream
+    pub fn try_send(&self, outbound: &Outbound) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(LinkState { sealer, peer: Some(peer), buf, .. }) = state.as_mut() else {
+            return false;
+        };
+        let body = match DatagramSealer::unreliable_body(&self.server.registry, Direction::SC, outbound) {
+            Ok(Some(body)) => body,
+            Ok(None) => return false,
+            Err(err) => {
+                warn!("encode datagram err {}",err);
+                return false;
+            }
+        };
+        buf.clear();
+        //too large for one datagram, the stream fragments it
+        if sealer.seal(body.0, &body.1, buf).is_err() {
+            return false;
+        }
+        match self.server.socket.try_send_to(buf, *peer) {
+            Ok(_) => true,
+            Err(err) => {
+                debug!("send datagram to {} err {}, fallback to stream",peer,err);
+                false
+            }
+        }
+    }
+
+    /// forget the token, later datagrams of the client are dropped
+    pub fn close(&self) {
+        let state = self.state.lock().unwrap().take();
+        if let Some(state) = state {
+            if let Some(route) = self.server.routes.lock().unwrap().remove(&state.token) {
+                debug!("datagram channel of {:?} closed, {} stale datagrams dropped",state.peer,route.opener.stale());
+            }
+        }
+    }
+}
+
+impl Drop for DatagramLink {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use protobuf::MessageField;
+    use tokio::net::UdpSocket;
+
+    use protocol::codec::{Outbound, Packet};
+    use protocol::datagram::{BIND_ID, DatagramKey, Opened};
+    use protocol::options::Direction;
+    use protocol::registry::BUILTIN_REGISTRY;
+    use protocol::test::{LoginResp, PlayerMoveNotify, PlayerState, SCPlayerMoveNotify};
+
+    use crate::config::DatagramConfig;
+    use crate::datagram::DatagramServer;
+    use crate::message::PlayerMessage;
+
+    #[tokio::test]
+    async fn test_datagram_link() {
+        let config = DatagramConfig { bind: "127.0.0.1:0".to_string(), advertise: None };
+        let server = DatagramServer::bind(&config, BUILTIN_REGISTRY.clone()).await.unwrap();
+        let (player_tx, mut player_rx) = tokio::sync::mpsc::unbounded_channel();
+        let link = server.link();
+        let channel = link.open(player_tx.clone());
+        assert_eq!(link.open(player_tx).token, channel.token);
+
+        let move_notify: Outbound = Packet::notify(Box::new(SCPlayerMoveNotify::new())).into();
+        //the client address isn't known yet
+        assert!(!link.try_send(&move_notify));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(&channel.addr).await.unwrap();
+        let (mut sealer, mut opener) = DatagramKey::from_proto(&channel).unwrap().split(false);
+        let mut buf = BytesMut::new();
+        sealer.seal(BIND_ID, &[], &mut buf).unwrap();
+        client.send(&buf).await.unwrap();
+        let mut notify = PlayerMoveNotify::new();
+        let mut state = PlayerState::new();
+        state.x = 3.;
+        notify.state = MessageField::some(state);
+        buf.clear();
+        sealer.seal(3, &protobuf::Message::write_to_bytes(&notify).unwrap(), &mut buf).unwrap();
+        client.send(&buf).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(1), player_rx.recv()).await.unwrap().unwrap();
+        let PlayerMessage::Datagram(msg) = received.message else {
+            panic!("expect a datagram message");
+        };
+        assert_eq!(msg.downcast_box::<PlayerMoveNotify>().unwrap().state.x, 3.);
+
+        assert!(link.try_send(&move_notify));
+        assert!(!link.try_send(&Packet::notify(Box::new(LoginResp::new())).into()));
+        let mut datagram = BytesMut::with_capacity(2048);
+        tokio::time::timeout(Duration::from_secs(1), client.recv_buf(&mut datagram)).await.unwrap().unwrap();
+        assert!(matches!(opener.open(&BUILTIN_REGISTRY, Direction::SC, datagram).unwrap(), Some(Opened::Message(_))));
+
+        drop(link);
+        assert!(server.routes.lock().unwrap().is_empty());
+    }
+}
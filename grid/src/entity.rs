@@ -0,0 +1,85 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// allocate runtime entity ids for accounts, a released id can only be reused after `reuse_delay`
+/// so stale messages addressed to the previous owner can't hit a new player
+pub struct EntityIdAllocator {
+    next_id: i32,
+    reuse_delay: Duration,
+    released: VecDeque<(Instant, i32)>,
+    account_entity: HashMap<i32, i32>,
+    entity_account: HashMap<i32, i32>,
+}
+
+impl EntityIdAllocator {
+    pub fn new(reuse_delay: Duration) -> Self {
+        Self {
+            next_id: 1,
+            reuse_delay,
+            released: VecDeque::new(),
+            account_entity: HashMap::new(),
+            entity_account: HashMap::new(),
+        }
+    }
+
+    /// the entity id of the account, allocate a new one if the account has none
+    pub fn allocate(&mut self, account_id: i32) -> i32 {
+        if let Some(&entity_id) = self.account_entity.get(&account_id) {
+            return entity_id;
+        }
+        let entity_id = match self.released.front() {
+            Some((released_at, _)) if released_at.elapsed() >= self.reuse_delay => {
+                self.released.pop_front().unwrap().1
+            }
+            _ => {
+                let entity_id = self.next_id;
+                self.next_id += 1;
+                entity_id
+            }
+        };
+        self.account_entity.insert(account_id, entity_id);
+        self.entity_account.insert(entity_id, account_id);
+        entity_id
+    }
+
+    pub fn release(&mut self, entity_id: i32) {
+        if let Some(account_id) = self.entity_account.remove(&entity_id) {
+            self.account_entity.remove(&account_id);
+            self.released.push_back((Instant::now(), entity_id));
+        }
+    }
+
+    pub fn entity_id(&self, account_id: i32) -> Option<i32> {
+        self.account_entity.get(&account_id).copied()
+    }
+
+    pub fn account_id(&self, entity_id: i32) -> Option<i32> {
+        self.entity_account.get(&entity_id).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::entity::EntityIdAllocator;
+
+    #[test]
+    fn test_allocate_entity_id() {
+        let mut allocator = EntityIdAllocator::new(Duration::from_secs(60));
+        let a = allocator.allocate(100);
+        let b = allocator.allocate(200);
+        assert_ne!(a, b);
+        assert_eq!(allocator.allocate(100), a);
+        allocator.release(a);
+        assert_eq!(allocator.entity_id(100), None);
+        let c = allocator.allocate(100);
+        assert_ne!(c, a);
+
+        let mut allocator = EntityIdAllocator::new(Duration::ZERO);
+        let a = allocator.allocate(100);
+        allocator.release(a);
+        assert_eq!(allocator.allocate(200), a);
+        assert_eq!(allocator.account_id(a), Some(200));
+    }
+}
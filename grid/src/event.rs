@@ -11,4 +11,57 @@ impl Display for ReceiveTimeoutEvent {
     }
 }
 
-impl ScheduleEvent for ReceiveTimeoutEvent {}
\ No newline at end of file
+impl ScheduleEvent for ReceiveTimeoutEvent {}
+
+/// measure the round trip of a kcp connection, see [protocol::kcp::KcpProbe]
+#[derive(Clone, Debug)]
+pub struct PingEvent;
+
+impl Display for PingEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PingEvent")
+    }
+}
+
+impl ScheduleEvent for PingEvent {}
+
+#[derive(Clone, Debug)]
+pub struct SessionExpireEvent {
+    pub player_id: i32,
+}
+
+impl SessionExpireEvent {
+    pub fn key(player_id: i32) -> String {
+        format!("SessionExpireEvent:{}", player_id)
+    }
+}
+
+impl Display for SessionExpireEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SessionExpireEvent({})", self.player_id)
+    }
+}
+
+impl ScheduleEvent for SessionExpireEvent {}
+
+#[derive(Clone, Debug)]
+pub struct SavePlayersEvent;
+
+impl Display for SavePlayersEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SavePlayersEvent")
+    }
+}
+
+impl ScheduleEvent for SavePlayersEvent {}
+
+#[derive(Clone, Debug)]
+pub struct SnapshotEvent;
+
+impl Display for SnapshotEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SnapshotEvent")
+    }
+}
+
+impl ScheduleEvent for SnapshotEvent {}
\ No newline at end of file
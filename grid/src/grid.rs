@@ -11,17 +11,13 @@ pub struct Grid {
 pub fn calculate_grid_id(x: f32, y: f32) -> (i32, i32) {
     let x_n = (x / L as f32) as i32;
     let y_n = (y / L as f32) as i32;
-    return (x_n, y_n);
+    (x_n, y_n)
 }
 
 pub fn is_player_grid_change(pre_location: (f32, f32), curr_location: (f32, f32)) -> bool {
     let (p_n_x, p_n_y) = calculate_grid_id(pre_location.0, pre_location.1);
     let (c_n_x, c_n_y) = calculate_grid_id(curr_location.0, curr_location.1);
-    if p_n_x != c_n_x || p_n_y != c_n_y {
-        true
-    } else {
-        false
-    }
+    p_n_x != c_n_x || p_n_y != c_n_y
 }
 
 #[cfg(test)]
@@ -0,0 +1,15 @@
+pub mod player;
+pub mod message;
+pub mod world;
+pub mod server;
+pub mod world_handler;
+pub mod player_handler;
+pub mod tick;
+pub mod event;
+pub mod grid;
+pub mod config;
+pub mod auth;
+pub mod entity;
+pub mod storage;
+pub mod ban;
+pub mod datagram;
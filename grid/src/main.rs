@@ -1,20 +1,15 @@
-use crate::server::start_server;
-
-mod player;
-mod message;
-mod world;
-mod server;
-mod world_handler;
-mod player_handler;
-mod tick;
-mod event;
-mod grid;
+use grid::config::ServerConfig;
+use grid::server::start_server;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     std::env::set_var("RUST_LOG", "DEBUG");
     env_logger::init();
-    let addr = "127.0.0.1:4895";
-    start_server(addr).await?;
+    let mut config = ServerConfig::default();
+    //e.g. KCP_PROFILE=bandwidth-saver or KCP_PROFILE=balanced,mtu=1200, the clients should be started with the same
+    if let Ok(profile) = std::env::var("KCP_PROFILE") {
+        config.kcp = profile.parse()?;
+    }
+    start_server(config).await?;
     Ok(())
 }
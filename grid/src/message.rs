@@ -2,6 +2,10 @@ use std::fmt::Debug;
 
 use protobuf::MessageDyn;
 
+use protocol::codec::Outbound;
+use protocol::movement::MoveSettings;
+use protocol::test::DatagramChannel;
+
 use crate::player::{PlayerSender, State};
 use crate::tick::ScheduleEvent;
 
@@ -10,18 +14,12 @@ pub type ProtoMessage = Box<dyn MessageDyn>;
 pub type PlayerMessageSender = tokio::sync::mpsc::UnboundedSender<PlayerMessageWrap>;
 pub type PlayerMessageReceiver = tokio::sync::mpsc::UnboundedReceiver<PlayerMessageWrap>;
 
-pub type EventMessageSender = tokio::sync::mpsc::UnboundedSender<EventMessage>;
-pub type EventMessageReceiver = tokio::sync::mpsc::UnboundedReceiver<EventMessage>;
-
 pub type WorldMessageSender = tokio::sync::mpsc::UnboundedSender<WorldMessageWrap>;
-pub type WorldMessageReceiver = tokio::sync::mpsc::UnboundedReceiver<WorldMessageWrap>;
-
-pub type ProtoMessageSender = tokio::sync::mpsc::UnboundedSender<ProtoMessage>;
-pub type ProtoMessageReceiver = tokio::sync::mpsc::UnboundedReceiver<ProtoMessage>;
 
-pub struct WorldProtoMessage(pub i32, pub Box<dyn MessageDyn>);
+pub type ProtoMessageSender = tokio::sync::mpsc::UnboundedSender<Outbound>;
+pub type ProtoMessageReceiver = tokio::sync::mpsc::UnboundedReceiver<Outbound>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct WorldMessageWrap {
     pub player_id: i32,
     pub message: WorldMessage,
@@ -36,12 +34,15 @@ impl WorldMessageWrap {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum WorldMessage {
     PlayerLogin(PlayerLoginData),
-    PlayerLogout,
+    PlayerResume(PlayerResumeData),
+    PlayerDisconnect(PlayerMessageSender),
     PlayerMove(Box<dyn MessageDyn>),
     Proto(Box<dyn MessageDyn>),
+    /// save all the players, reply when done
+    Shutdown(tokio::sync::oneshot::Sender<()>),
 }
 
 #[derive(Debug, Clone)]
@@ -59,21 +60,56 @@ impl PlayerMessageWrap {
     }
 }
 
+/// the stored state of a login, loaded off the world task
+#[derive(Debug)]
+pub struct PlayerLoaded {
+    pub data: PlayerLoginData,
+    pub state: anyhow::Result<Option<State>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct PlayerLoginData {
+    pub account_id: i32,
     pub sender: PlayerSender,
     pub state: State,
+    pub resume_token: String,
+    /// compression negotiated with the client, echoed in LoginResp
+    pub compression: bool,
+    /// sequence of the LoginReq, LoginResp replies to it
+    pub seq: Option<u32>,
+    /// movement encoding negotiated with the client, echoed in LoginResp
+    pub movement: MoveSettings,
+    /// the datagram channel opened for the client, sent in LoginResp
+    pub datagram: Option<DatagramChannel>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerResumeData {
+    pub account_id: i32,
+    pub sender: PlayerSender,
+    pub resume_token: String,
+    pub compression: bool,
+    pub seq: Option<u32>,
+    pub movement: MoveSettings,
+    pub datagram: Option<DatagramChannel>,
 }
 
 #[derive(Debug, Clone)]
 pub enum PlayerMessage {
     KickOut(KickOutReason),
+    LoginAccepted(i32, State),
+    SessionResumed(i32, State),
+    /// the sequence of the LoginReq, the fallback login replies to it
+    ResumeRejected(Option<u32>),
     Event(Box<dyn ScheduleEvent>),
+    /// a message from the datagram channel of the connection
+    Datagram(ProtoMessage),
 }
 
 #[derive(Debug, Clone)]
 pub enum KickOutReason {
-    MultiLogin(String)
+    MultiLogin(String),
+    SessionResumed,
 }
 
 pub struct EventMessage(pub Box<dyn ScheduleEvent>);
\ No newline at end of file
@@ -1,24 +1,60 @@
-use std::net::SocketAddr;
+use std::fmt::Display;
 use std::ops::{Not, Range};
+use std::sync::Arc;
 use std::time::Duration;
 
-use futures::{SinkExt, StreamExt};
-use futures::stream::{SplitSink, SplitStream};
+use anyhow::anyhow;
+
+use futures::{FutureExt, Sink, SinkExt, StreamExt};
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
 use log::{debug, error, info};
-use protobuf::Message;
+use protobuf::{MessageDyn, MessageFull};
 use rand::{Rng, thread_rng};
+use tokio::sync::mpsc::error::TryRecvError;
 use tokio::task::JoinHandle;
-use tokio_kcp::KcpStream;
-use tokio_util::codec::Framed;
+use tokio::time::Instant;
 
-use protocol::codec::ProtoCodec;
-use protocol::test::{Color, LoginReq, PlayerMoveNotify, PlayerState};
+use protocol::codec::{Compression, MessageStream, Outbound, Packet};
+use protocol::router::{Middleware, Next, Router, TimingMiddleware};
+use protocol::movement::MoveSettings;
+use protocol::kcp::KcpProbe;
+use protocol::test::{Color, DatagramChannel, LoginReq, MoveDeltaAck, PingAck, PlayerMoveNotify, PlayerState, SnapshotAck};
+use protocol::transport::PeerAddr;
 
+use crate::auth::Authenticator;
+use crate::ban::BanList;
+use crate::config::WriteBatchConfig;
+use crate::datagram::DatagramLink;
 use crate::event::ReceiveTimeoutEvent;
 use crate::message::{PlayerMessage, PlayerMessageReceiver, PlayerMessageSender, PlayerMessageWrap, ProtoMessage, ProtoMessageReceiver, ProtoMessageSender, WorldMessageSender};
-use crate::player_handler::{handle_event, handle_login_req, handle_move_req, handle_world_kick_out};
+use crate::player_handler::{handle_connection_closed, handle_decode_error, handle_event, handle_login_accepted, handle_login_req, handle_move_ack, handle_move_req, handle_ping_ack, handle_resume_rejected, handle_session_resumed, handle_snapshot_ack, handle_world_kick_out};
 use crate::tick::Ticker;
 
+lazy_static! {
+    pub static ref PLAYER_ROUTER: Router<Player> = Router::new()
+        .layer(TimingMiddleware { threshold: Duration::from_millis(10) })
+        .layer(RequireLogin)
+        .route::<LoginReq, _>(|player, _, req| handle_login_req(player, req).boxed())
+        .route::<PlayerMoveNotify, _>(|player, _, notify| handle_move_req(player, notify).boxed())
+        .route::<MoveDeltaAck, _>(|player, _, ack| handle_move_ack(player, ack).boxed())
+        .route::<SnapshotAck, _>(|player, _, ack| handle_snapshot_ack(player, ack).boxed())
+        .route::<PingAck, _>(|player, _, ack| handle_ping_ack(player, ack).boxed());
+}
+
+/// only LoginReq is accepted before the world assigned the entity id
+pub struct RequireLogin;
+
+impl Middleware<Player, ()> for RequireLogin {
+    fn call<'a>(&'a self, player: &'a mut Player, ctx: (), msg: Box<dyn MessageDyn>, next: Next<'a, Player, ()>) -> BoxFuture<'a, anyhow::Result<()>> {
+        if player.player_id == 0 && msg.descriptor_dyn().full_name() != LoginReq::descriptor().full_name() {
+            let name = msg.descriptor_dyn().name().to_string();
+            return async move { Err(anyhow!("player not login yet, msg:{} dropped", name)) }.boxed();
+        }
+        next.run(player, ctx, msg)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PlayerSender {
     pub player: PlayerMessageSender,
@@ -26,77 +62,128 @@ pub struct PlayerSender {
 }
 
 pub struct Player {
+    pub account_id: i32,
+    /// entity id assigned by the world, 0 before login
     pub player_id: i32,
-    pub addr: SocketAddr,
+    pub addr: PeerAddr,
     pub player_sender: PlayerMessageSender,
     pub proto_sender: ProtoMessageSender,
     pub world_sender: WorldMessageSender,
+    pub authenticator: Arc<dyn Authenticator>,
+    pub compression: Compression,
+    pub movement: MoveSettings,
+    pub bans: Arc<BanList>,
     pub state: State,
+    pub resume_token: String,
+    /// sequence of the request being handled, None for notifies
+    pub request_seq: Option<u32>,
     pub write_handle: Option<JoinHandle<()>>,
+    /// None if the server has no datagram socket
+    pub datagram: Option<Arc<DatagramLink>>,
+    /// opened if the client asked for it at login
+    pub datagram_channel: Option<DatagramChannel>,
     pub stooped: bool,
     pub ticker: Ticker,
+    /// pings a kcp connection for [protocol::kcp::KcpDiagnostics]
+    pub kcp_probe: Option<Arc<KcpProbe>>,
 }
 
 impl Player {
-    pub fn new(addr: SocketAddr, player_sender: PlayerMessageSender, proto_sender: ProtoMessageSender, world_sender: WorldMessageSender) -> Self {
+    pub fn new(addr: PeerAddr, player_sender: PlayerMessageSender, proto_sender: ProtoMessageSender, world_sender: WorldMessageSender, authenticator: Arc<dyn Authenticator>, compression: Compression, bans: Arc<BanList>) -> Self {
         Self {
+            account_id: 0,
             player_id: 0,
             addr,
             player_sender,
             proto_sender,
             world_sender,
+            authenticator,
+            compression,
+            movement: MoveSettings::default(),
+            bans,
             state: State::default(),
+            resume_token: String::new(),
+            request_seq: None,
             write_handle: None,
+            datagram: None,
+            datagram_channel: None,
             stooped: false,
             ticker: Ticker::new(),
+            kcp_probe: None,
         }
     }
 
     pub fn stop(&mut self) {
         info!("player {} stop",self.player_id);
         self.stooped = true;
+        for (msg_name, counter) in self.compression.counters() {
+            debug!("player {} sent {} {} raw bytes:{} wire bytes:{} ratio:{:.2}",self.player_id,counter.messages,msg_name,counter.raw_bytes,counter.wire_bytes,counter.ratio());
+        }
         if let Some(w) = &self.write_handle {
             w.abort();
             info!("abort player {} write handle",self.player_id);
         }
+        if let Some(datagram) = &self.datagram {
+            datagram.close();
+        }
     }
 
-    pub async fn handle_req(&mut self, msg: ProtoMessage) -> anyhow::Result<()> {
-        self.ticker.cancel(ReceiveTimeoutEvent.to_string());
-        let desc = msg.descriptor_dyn();
-        let msg_name = desc.name();
-        if msg_name == LoginReq::NAME {
-            handle_login_req(self, msg).await?;
-        } else if msg_name == PlayerMoveNotify::NAME {
-            handle_move_req(self, msg).await?;
+    pub fn sender(&self) -> PlayerSender {
+        PlayerSender {
+            player: self.player_sender.clone(),
+            proto: self.proto_sender.clone(),
         }
-        Ok(())
+    }
+
+    /// reply to the request being handled
+    pub fn reply(&self, msg: ProtoMessage) {
+        let _ = self.proto_sender.send(Packet::reply(self.request_seq, msg).into());
+    }
+
+    /// the client is gone if nothing comes in for a while, the timers of the player don't count
+    fn keep_alive(&mut self) {
+        self.ticker.schedule_once(Duration::from_secs(10), ReceiveTimeoutEvent.to_string(), Box::new(ReceiveTimeoutEvent));
+    }
+
+    pub async fn handle_req(&mut self, packet: Packet) -> anyhow::Result<()> {
+        self.keep_alive();
+        self.request_seq = packet.seq;
+        let result = PLAYER_ROUTER.dispatch(self, (), packet.msg).await;
+        self.request_seq = None;
+        result
     }
 
     pub async fn handle_player_msg(&mut self, msg: PlayerMessageWrap) -> anyhow::Result<()> {
-        self.ticker.cancel(ReceiveTimeoutEvent.to_string());
+        self.keep_alive();
         let world_id = msg.world_id;
         match msg.message {
             PlayerMessage::KickOut(reason) => { handle_world_kick_out(self, world_id, reason).await?; }
+            PlayerMessage::LoginAccepted(player_id, state) => { handle_login_accepted(self, player_id, state).await?; }
+            PlayerMessage::SessionResumed(player_id, state) => { handle_session_resumed(self, player_id, state).await?; }
+            PlayerMessage::ResumeRejected(seq) => { handle_resume_rejected(self, seq).await?; }
             PlayerMessage::Event(_) => {}
+            PlayerMessage::Datagram(msg) => { self.handle_req(Packet::notify(msg)).await?; }
         }
         Ok(())
     }
 
 
-    pub fn start_receive_msg(player: Player, mut read: SplitStream<Framed<KcpStream, ProtoCodec>>, mut player_receiver: PlayerMessageReceiver) -> JoinHandle<()> {
+    pub fn start_receive_msg(player: Player, mut read: MessageStream, mut player_receiver: PlayerMessageReceiver) -> JoinHandle<()> {
         tokio::spawn(async move {
             let mut player = player;
+            player.keep_alive();
             while player.stooped.not() {
-                player.ticker.schedule_once(Duration::from_secs(10), ReceiveTimeoutEvent.to_string(), Box::new(ReceiveTimeoutEvent));
                 tokio::select! {
-                    Some(Ok(request)) = read.next() => {
-                        match player.handle_req(request).await {
-                            Ok(_) => {}
-                            Err(error) => {
-                                error!("player {} handle msg error {}",player.player_id,error);
-                            }
+                    request = read.next() => {
+                        let result = match request {
+                            Some(Ok(request)) => player.handle_req(request).await,
+                            //the stream ends after an error, frames skipped by the decode error policy never get here
+                            Some(Err(err)) => handle_decode_error(&mut player, err).await,
+                            None => handle_connection_closed(&mut player).await,
                         };
+                        if let Err(error) = result {
+                            error!("player {} handle msg error {}",player.player_id,error);
+                        }
                     }
                     Some(message) = player_receiver.recv() => {
                         match player.handle_player_msg(message).await {
@@ -123,25 +210,40 @@ impl Player {
         })
     }
 
-    pub fn start_write_msg(mut proto_receiver: ProtoMessageReceiver, mut write: SplitSink<Framed<KcpStream, ProtoCodec>, ProtoMessage>) -> JoinHandle<()> {
+    /// encode what is queued back to back and flush it at once, a broadcast storm goes out in a few writes
+    /// instead of one kcp segment per message, unreliable messages skip the stream if the datagram channel is open
+    pub fn start_write_msg<S>(mut proto_receiver: ProtoMessageReceiver, mut write: S, batch: WriteBatchConfig, datagram: Option<Arc<DatagramLink>>) -> JoinHandle<()>
+        where S: Sink<Outbound> + Unpin + Send + 'static, S::Error: Display {
         tokio::spawn(async move {
-            loop {
-                match proto_receiver.recv().await {
-                    None => {
-                        info!("all proto sender dropped, close the receiver");
-                        break;
+            while let Some(msg) = proto_receiver.recv().await {
+                let deadline = Instant::now() + batch.max_latency;
+                let mut next = Some(msg);
+                let mut batched = 0;
+                while let Some(msg) = next.take() {
+                    if datagram.as_ref().is_some_and(|datagram| datagram.try_send(&msg)).not() {
+                        if let Err(err) = write.feed(msg).await {
+                            error!("send proto message err:{}, close the receiver",err);
+                            return;
+                        }
                     }
-                    Some(msg) => {
-                        match write.send(msg).await {
-                            Ok(_) => {}
-                            Err(err) => {
-                                error!("send proto message err:{}, close the receiver",err);
-                                break;
-                            }
-                        };
+                    batched += 1;
+                    if batched >= batch.max_messages {
+                        break;
                     }
+                    next = match proto_receiver.try_recv() {
+                        Ok(msg) => Some(msg),
+                        Err(TryRecvError::Empty) if batch.max_latency.is_zero().not() => {
+                            tokio::time::timeout_at(deadline, proto_receiver.recv()).await.ok().flatten()
+                        }
+                        Err(_) => None,
+                    };
+                }
+                if let Err(err) = write.flush().await {
+                    error!("flush proto message err:{}, close the receiver",err);
+                    return;
                 }
             }
+            info!("all proto sender dropped, close the receiver");
             debug!("write task done")
         })
     }
@@ -162,3 +264,114 @@ pub fn random_color() -> Color {
     color.b = thread_rng.gen_range(range.clone());
     color
 }
+
+pub fn random_resume_token() -> String {
+    let token: u128 = thread_rng().gen();
+    format!("{:032x}", token)
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use futures::{Sink, StreamExt};
+    use tokio_util::codec::Framed;
+
+    use protocol::codec::{Outbound, Packet, ProtoCodec};
+    use protocol::test::PlayerMoveNotify;
+    use protocol::transport::{Listener, memory_transport};
+
+    use crate::auth::TrustAuthenticator;
+    use crate::ban::BanList;
+    use crate::config::WriteBatchConfig;
+    use crate::message::WorldMessage;
+    use crate::player::Player;
+
+    /// the number of messages in every flush
+    #[derive(Clone, Default)]
+    struct FlushRecorder {
+        pending: usize,
+        flushes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Sink<Outbound> for FlushRecorder {
+        type Error = Infallible;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, _: Outbound) -> Result<(), Self::Error> {
+            self.pending += 1;
+            Ok(())
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            let pending = std::mem::take(&mut self.pending);
+            self.flushes.lock().unwrap().push(pending);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    fn notify() -> Outbound {
+        Packet::notify(Box::new(PlayerMoveNotify::new())).into()
+    }
+
+    #[tokio::test]
+    async fn test_write_batch() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        for _ in 0..10 {
+            tx.send(notify()).unwrap();
+        }
+        drop(tx);
+        let sink = FlushRecorder::default();
+        let batch = WriteBatchConfig { max_messages: 4, max_latency: Duration::ZERO };
+        Player::start_write_msg(rx, sink.clone(), batch, None).await.unwrap();
+        assert_eq!(*sink.flushes.lock().unwrap(), vec![4, 4, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_batch_latency() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sink = FlushRecorder::default();
+        let batch = WriteBatchConfig { max_messages: 64, max_latency: Duration::from_millis(10) };
+        let write = Player::start_write_msg(rx, sink.clone(), batch, None);
+        tx.send(notify()).unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        //still inside the latency bound of the first one
+        tx.send(notify()).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        tx.send(notify()).unwrap();
+        drop(tx);
+        write.await.unwrap();
+        assert_eq!(*sink.flushes.lock().unwrap(), vec![2, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed() {
+        let (mut listener, connector) = memory_transport();
+        let client = connector.connect().unwrap();
+        let (connection, addr) = listener.accept().await.unwrap();
+        let codec = ProtoCodec::new(true);
+        let compression = codec.compression();
+        let (_, read) = StreamExt::split::<Outbound>(Framed::new(connection, codec));
+        let (player_tx, player_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (proto_tx, _proto_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (world_tx, mut world_rx) = tokio::sync::mpsc::unbounded_channel();
+        let player = Player::new(addr, player_tx, proto_tx, world_tx, Arc::new(TrustAuthenticator), compression, Arc::new(BanList::new(Duration::from_secs(1))));
+        let receive = Player::start_receive_msg(player, read, player_rx);
+        drop(client);
+        //long before the receive timeout
+        let wrap = tokio::time::timeout(Duration::from_secs(1), world_rx.recv()).await.unwrap().unwrap();
+        assert!(matches!(wrap.message, WorldMessage::PlayerDisconnect(_)));
+        receive.await.unwrap();
+    }
+}
@@ -1,14 +1,21 @@
-use log::info;
-use protobuf::{MessageDyn, MessageField};
+use std::ops::Not;
+use std::time::Duration;
 
-use protocol::mapper::cast;
-use protocol::test::{LoginReq, LoginResp};
+use log::{info, warn};
 
-use crate::event::ReceiveTimeoutEvent;
-use crate::message::{EventMessage, KickOutReason, PlayerLoginData, WorldMessage, WorldMessageWrap};
-use crate::player::{Player, PlayerSender, random_color};
+use protocol::codec::{Packet, ProtoCodecError};
+use protocol::movement::MoveSettings;
+use protocol::test::{ErrorCode, ErrorResp, LoginReq, MoveDeltaAck, PingAck, PlayerMoveNotify, SCPing, SnapshotAck};
 
-pub async fn handle_world_kick_out(player: &mut Player, world_id: i32, reason: KickOutReason) -> anyhow::Result<()> {
+use crate::auth::AuthResult;
+use crate::event::{PingEvent, ReceiveTimeoutEvent};
+use crate::message::{EventMessage, KickOutReason, PlayerLoginData, PlayerResumeData, WorldMessage, WorldMessageWrap};
+use crate::player::{Player, random_color, random_resume_token, State};
+
+/// how long the write task may keep flushing after the read side was closed with an error
+const CLOSE_LINGER: Duration = Duration::from_secs(1);
+
+pub async fn handle_world_kick_out(player: &mut Player, _world_id: i32, _reason: KickOutReason) -> anyhow::Result<()> {
     player.stop();
     Ok(())
 }
@@ -16,33 +23,161 @@ pub async fn handle_world_kick_out(player: &mut Player, world_id: i32, reason: K
 pub async fn handle_event(player: &mut Player, event: EventMessage) -> anyhow::Result<()> {
     let event = event.0;
     if event.to_string() == ReceiveTimeoutEvent.to_string() {
+        //keep the player in the world for a while, the client may come back with the resume token
+        let wrap = WorldMessageWrap::new(player.player_id, WorldMessage::PlayerDisconnect(player.player_sender.clone()));
+        let _ = player.world_sender.send(wrap);
         player.stop();
+    } else if event.to_string() == PingEvent.to_string() {
+        //the client only answers once logged in
+        if let (Some(probe), true) = (&player.kcp_probe, player.player_id != 0) {
+            let mut ping = SCPing::new();
+            ping.sequence = probe.ping();
+            let _ = player.proto_sender.send(Packet::notify(Box::new(ping)).into());
+        }
+    }
+    Ok(())
+}
+
+/// the read stream ended with an error, tell the client why unless the connection is gone and close it,
+/// the player stays in the world like after a lost connection
+pub async fn handle_decode_error(player: &mut Player, err: ProtoCodecError) -> anyhow::Result<()> {
+    if let ProtoCodecError::Io(err) = &err {
+        info!("{} player {} connection lost {}",player.addr,player.player_id,err);
+    } else {
+        warn!("{} player {} sent malformed input, close the connection: {}",player.addr,player.player_id,err);
+        if let ProtoCodecError::TooManyViolations(_) = err {
+            player.bans.ban(player.addr);
+        }
+        let mut rsp = ErrorResp::new();
+        rsp.code = ErrorCode::MALFORMED_MESSAGE.into();
+        rsp.reason = err.to_string();
+        let _ = player.proto_sender.send(Packet::notify(Box::new(rsp)).into());
+        if let Some(write) = player.write_handle.take() {
+            tokio::spawn(async move {
+                tokio::time::sleep(CLOSE_LINGER).await;
+                write.abort();
+            });
+        }
+    }
+    disconnect(player);
+    Ok(())
+}
+
+/// the client closed the connection, the player stays in the world like after a lost connection
+pub async fn handle_connection_closed(player: &mut Player) -> anyhow::Result<()> {
+    info!("{} player {} closed the connection",player.addr,player.player_id);
+    disconnect(player);
+    Ok(())
+}
+
+fn disconnect(player: &mut Player) {
+    let wrap = WorldMessageWrap::new(player.player_id, WorldMessage::PlayerDisconnect(player.player_sender.clone()));
+    let _ = player.world_sender.send(wrap);
+    player.stop();
+}
+
+pub async fn handle_login_req(player: &mut Player, req: LoginReq) -> anyhow::Result<()> {
+    //password hashing is slow on purpose
+    let authenticator = player.authenticator.clone();
+    let auth_req = req.clone();
+    let account_id = match tokio::task::spawn_blocking(move || authenticator.authenticate(&auth_req)).await? {
+        AuthResult::Accept(account_id) => account_id,
+        AuthResult::Reject(reason) => {
+            warn!("{} login rejected: {}",player.addr,reason);
+            let mut rsp = ErrorResp::new();
+            rsp.code = ErrorCode::AUTH_FAILED.into();
+            rsp.reason = reason;
+            player.reply(Box::new(rsp));
+            return Ok(());
+        }
+    };
+    player.account_id = account_id;
+    player.compression.negotiate(req.compression);
+    //every encoding the client may ask for is supported
+    player.movement = MoveSettings::requested(&req);
+    if let (true, Some(datagram)) = (req.datagram, &player.datagram) {
+        player.datagram_channel = Some(datagram.open(player.player_sender.clone()));
+    }
+    if req.resume_token.is_empty().not() {
+        info!("account:{} handle resume req",player.account_id);
+        player.resume_token = req.resume_token.clone();
+        let wrap = WorldMessageWrap::new(player.player_id, WorldMessage::PlayerResume(PlayerResumeData {
+            account_id: player.account_id,
+            sender: player.sender(),
+            resume_token: req.resume_token,
+            compression: player.compression.is_enabled(),
+            seq: player.request_seq,
+            movement: player.movement,
+            datagram: player.datagram_channel.clone(),
+        }));
+        let _ = player.world_sender.send(wrap);
+        return Ok(());
     }
+    info!("account:{} handle login req",player.account_id);
+    let seq = player.request_seq;
+    login(player, seq);
     Ok(())
 }
 
-pub async fn handle_login_req(player: &mut Player, msg: Box<dyn MessageDyn>) -> anyhow::Result<()> {
-    let req = cast::<LoginReq>(msg)?;
-    info!("player:{} handle login req",req.player_id);
-    player.player_id = req.player_id;
+pub async fn handle_login_accepted(player: &mut Player, player_id: i32, state: State) -> anyhow::Result<()> {
+    info!("account:{} login as player:{}",player.account_id,player_id);
+    player.player_id = player_id;
+    player.state = state;
+    Ok(())
+}
+
+pub async fn handle_session_resumed(player: &mut Player, player_id: i32, state: State) -> anyhow::Result<()> {
+    info!("account:{} session resumed as player:{}",player.account_id,player_id);
+    player.player_id = player_id;
+    player.state = state;
+    Ok(())
+}
+
+pub async fn handle_resume_rejected(player: &mut Player, seq: Option<u32>) -> anyhow::Result<()> {
+    info!("account:{} resume rejected, fallback to login",player.account_id);
+    login(player, seq);
+    Ok(())
+}
+
+fn login(player: &mut Player, seq: Option<u32>) {
     player.state.color = random_color();
+    player.resume_token = random_resume_token();
     let wrap = WorldMessageWrap::new(player.player_id, WorldMessage::PlayerLogin(PlayerLoginData {
-        sender: PlayerSender {
-            player: player.player_sender.clone(),
-            proto: player.proto_sender.clone(),
-        },
+        account_id: player.account_id,
+        sender: player.sender(),
         state: player.state.clone(),
+        resume_token: player.resume_token.clone(),
+        compression: player.compression.is_enabled(),
+        seq,
+        movement: player.movement,
+        datagram: player.datagram_channel.clone(),
     }));
     let _ = player.world_sender.send(wrap);
-    let mut rsp = LoginResp::new();
-    rsp.player_id = player.player_id;
-    rsp.color = MessageField::some(player.state.color.clone());
-    let _ = player.proto_sender.send(Box::new(rsp));
+}
+
+pub async fn handle_move_req(player: &mut Player, notify: PlayerMoveNotify) -> anyhow::Result<()> {
+    let wrap=WorldMessageWrap::new(player.player_id,WorldMessage::PlayerMove(Box::new(notify)));
+    let _ = player.world_sender.send(wrap);
     Ok(())
 }
 
-pub async fn handle_move_req(player: &mut Player, msg: Box<dyn MessageDyn>) -> anyhow::Result<()> {
-    let wrap=WorldMessageWrap::new(player.player_id,WorldMessage::PlayerMove(msg));
+/// the move encoders of the observers live in the world
+pub async fn handle_move_ack(player: &mut Player, ack: MoveDeltaAck) -> anyhow::Result<()> {
+    let wrap = WorldMessageWrap::new(player.player_id, WorldMessage::Proto(Box::new(ack)));
     let _ = player.world_sender.send(wrap);
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn handle_ping_ack(player: &mut Player, ack: PingAck) -> anyhow::Result<()> {
+    if let Some(probe) = &player.kcp_probe {
+        probe.ack(ack.sequence);
+    }
+    Ok(())
+}
+
+/// so do the snapshot encoders
+pub async fn handle_snapshot_ack(player: &mut Player, ack: SnapshotAck) -> anyhow::Result<()> {
+    let wrap = WorldMessageWrap::new(player.player_id, WorldMessage::Proto(Box::new(ack)));
+    let _ = player.world_sender.send(wrap);
+    Ok(())
+}
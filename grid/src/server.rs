@@ -1,49 +1,217 @@
-use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures::StreamExt;
-use log::{error, info};
-use tokio_kcp::KcpStream;
-use tokio_util::codec::Framed;
+use log::{error, info, warn};
 
-use protocol::codec::ProtoCodec;
-use protocol::mapper::kcp_config;
+use protocol::codec::{CodecConfig, DecodeErrorPolicy, Outbound};
+use protocol::kcp::KcpDiagnostics;
+use protocol::options::Direction;
+use protocol::secure::EncryptionMode;
+use protocol::transport::{accept_all, BoxConnection, Endpoint, framed_with_config, Listener, PeerAddr};
 
-use crate::message::{PlayerMessageWrap, ProtoMessage, WorldMessageSender};
-use crate::player::Player;
+use crate::auth::{Authenticator, new_authenticator};
+use crate::ban::BanList;
+use crate::datagram::DatagramServer;
+use crate::event::PingEvent;
+use crate::config::{AuthConfig, ServerConfig, WriteBatchConfig};
+use crate::message::{PlayerMessageWrap, WorldMessage, WorldMessageSender, WorldMessageWrap};
+use crate::player::{Player, PLAYER_ROUTER};
+use crate::storage::new_storage;
 use crate::world::start_world;
 
-pub async fn start_server(addr: &str) -> anyhow::Result<()> {
-    let cfg = kcp_config();
-    let world_sender = start_world();
-    let mut listener = tokio_kcp::KcpListener::bind(cfg, addr).await?;
-    info!("server start at {}",addr);
+pub async fn start_server(config: ServerConfig) -> anyhow::Result<()> {
+    let mut listeners = vec![];
+    let mut diagnostics = vec![];
+    for listener in &config.listen {
+        let bound = listener.endpoint.bind_with(config.kcp.settings()).await?;
+        match bound.kcp_diagnostics() {
+            Some(kcp) => {
+                info!("server listen on {} with kcp {:?} {}",listener.endpoint,config.kcp,kcp.settings());
+                diagnostics.push((listener.endpoint.clone(), kcp));
+            }
+            None => info!("server listen on {}",listener.endpoint),
+        }
+        listeners.push((bound, listener.decode_errors));
+    }
+    if let Some(interval) = config.kcp_diagnostics_interval {
+        tokio::spawn(log_kcp_diagnostics(diagnostics, interval));
+    }
+    let datagram = match &config.datagram {
+        Some(datagram) => Some(DatagramServer::bind(datagram, config.codec.registry.clone()).await?),
+        None => None,
+    };
+    let world_sender = serve(&config, listeners, datagram)?;
+    tokio::signal::ctrl_c().await?;
+    info!("signal ctrl c, close server");
+    let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+    let _ = world_sender.send(WorldMessageWrap::new(0, WorldMessage::Shutdown(done_tx)));
+    let _ = done_rx.await;
+    Ok(())
+}
+
+/// log the effective settings and the stats of every kcp connection
+async fn log_kcp_diagnostics(diagnostics: Vec<(Endpoint, KcpDiagnostics)>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    //the first tick completes at once, nothing is connected yet
+    ticker.tick().await;
     loop {
-        tokio::select! {
-            connection = listener.accept() => {
+        ticker.tick().await;
+        for (endpoint, kcp) in &diagnostics {
+            let connections = kcp.connections();
+            info!("{} {} connections, {}",endpoint,connections.len(),kcp.settings());
+            for (addr, stats) in connections {
+                info!("{} rtt {:?}±{:?} rto {:?} pings {} retransmits {}",addr,stats.rtt,stats.rtt_var,stats.rto,stats.pings,stats.retransmits);
+            }
+        }
+    }
+}
+
+/// start the world and accept connections from all the listeners in background,
+/// every listener may override the decode error policy of the codec, clients of all of them may open
+/// a datagram channel on the socket of `datagram`
+pub fn serve(config: &ServerConfig, listeners: Vec<(Box<dyn Listener>, Option<DecodeErrorPolicy>)>, datagram: Option<Arc<DatagramServer>>) -> anyhow::Result<WorldMessageSender> {
+    let storage = new_storage(&config.storage)?;
+    let world_sender = start_world(config, storage);
+    let authenticator = new_authenticator(&config.auth)?;
+    if let AuthConfig::Trust = config.auth {
+        warn!("authentication disabled, client claimed player id will be trusted");
+    }
+    let cs_descriptors = config.codec.registry.descriptors(Direction::CS);
+    for name in PLAYER_ROUTER.unregistered(cs_descriptors.iter().map(|(_, descriptor)| descriptor.full_name())) {
+        warn!("no handler registered for msg:{}, it will be rejected",name);
+    }
+    let bans = Arc::new(BanList::new(config.ban_duration));
+    let mut codec = config.codec.clone();
+    //passwords must not go over a connection an active attacker could downgrade to plaintext
+    if matches!(config.auth, AuthConfig::AccountFile(_)) && codec.encryption == EncryptionMode::Optional {
+        info!("account file authentication, encryption required");
+        codec.encryption = EncryptionMode::Required;
+    }
+    for (listener, decode_errors) in listeners {
+        //kcp connections are pinged for their stats only if someone looks at them
+        let kcp = listener.kcp_diagnostics().filter(|_| config.kcp_diagnostics_interval.is_some());
+        let mut incoming = accept_all(vec![listener]);
+        let mut codec = codec.clone();
+        if let Some(decode_errors) = decode_errors {
+            codec.decode_errors = decode_errors;
+        }
+        let acceptor = Acceptor {
+            codec,
+            write_batch: config.write_batch,
+            world_sender: world_sender.clone(),
+            authenticator: authenticator.clone(),
+            bans: bans.clone(),
+            datagram: datagram.clone(),
+            kcp,
+            kcp_ping_interval: config.kcp_ping_interval,
+        };
+        tokio::spawn(async move {
+            while let Some(connection) = incoming.recv().await {
                 match connection {
-                    Ok((stream,addr)) => accept_connection(stream,addr,world_sender.clone()),
+                    Ok((_, addr)) if acceptor.bans.is_banned(addr) => {
+                        warn!("{} is banned, connection dropped",addr);
+                    }
+                    Ok((connection, addr)) => acceptor.accept(connection, addr),
                     Err(err) => {
                         error!("server accept connection error {}",err);
                     }
                 }
             }
-            _ = tokio::signal::ctrl_c() => {
-                info!("signal ctrl c, close server");
-                break;
+        });
+    }
+    Ok(world_sender)
+}
+
+/// what the connections of a listener share
+struct Acceptor {
+    codec: CodecConfig,
+    write_batch: WriteBatchConfig,
+    world_sender: WorldMessageSender,
+    authenticator: Arc<dyn Authenticator>,
+    bans: Arc<BanList>,
+    datagram: Option<Arc<DatagramServer>>,
+    kcp: Option<KcpDiagnostics>,
+    kcp_ping_interval: Duration,
+}
+
+impl Acceptor {
+    fn accept(&self, connection: BoxConnection, addr: PeerAddr) {
+        let codec = self.codec.clone();
+        let write_batch = self.write_batch;
+        let world_sender = self.world_sender.clone();
+        let authenticator = self.authenticator.clone();
+        let bans = self.bans.clone();
+        let datagram = self.datagram.as_ref().map(|datagram| datagram.link());
+        let kcp_probe = match (&self.kcp, addr) {
+            (Some(kcp), PeerAddr::Socket(addr)) => Some(kcp.probe(addr)),
+            _ => None,
+        };
+        let kcp_ping_interval = self.kcp_ping_interval;
+        //the hello exchange runs in its own task so a slow client can't block the accept loop
+        tokio::spawn(async move {
+            let (write, read, compression) = match framed_with_config(connection, true, codec).await {
+                Ok(framed) => framed,
+                Err(err) => {
+                    warn!("{} hello exchange failed {}",addr,err);
+                    return;
+                }
+            };
+            let (player_tx, player_rx) = tokio::sync::mpsc::unbounded_channel::<PlayerMessageWrap>();
+            let (proto_tx, proto_rx) = tokio::sync::mpsc::unbounded_channel::<Outbound>();
+            let mut player = Player::new(addr, player_tx, proto_tx, world_sender, authenticator, compression, bans);
+            let write_handle = Player::start_write_msg(proto_rx, write, write_batch, datagram.clone());
+            player.write_handle = Some(write_handle);
+            player.datagram = datagram;
+            if let Some(probe) = kcp_probe {
+                player.kcp_probe = Some(probe);
+                player.ticker.schedule_repeat(kcp_ping_interval, PingEvent.to_string(), Box::new(PingEvent));
             }
-        }
+            Player::start_receive_msg(player, read, player_rx);
+            info!("accept new connection {}",addr);
+        });
     }
-    Ok(())
 }
 
-fn accept_connection(stream: KcpStream, addr: SocketAddr, world_sender: WorldMessageSender) {
-    let (player_tx, player_rx) = tokio::sync::mpsc::unbounded_channel::<PlayerMessageWrap>();
-    let (proto_tx, proto_rx) = tokio::sync::mpsc::unbounded_channel::<ProtoMessage>();
-    let mut player = Player::new(addr, player_tx, proto_tx, world_sender);
-    let framed = Framed::new(stream, ProtoCodec::new(true));
-    let (write, read) = framed.split();
-    let write_handle = Player::start_write_msg(proto_rx, write);
-    player.write_handle = Some(write_handle);
-    Player::start_receive_msg(player, read, player_rx);
-    info!("accept new connection {}",addr);
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use protocol::codec::{CodecConfig, Packet, PacketSink};
+    use protocol::mapper::cast;
+    use protocol::rpc::Rpc;
+    use protocol::test::{LoginReq, LoginResp};
+    use protocol::transport::{framed_with_config, memory_transport};
+
+    use crate::config::{DatagramConfig, ServerConfig, StorageConfig};
+    use crate::datagram::DatagramServer;
+    use crate::server::serve;
+
+    #[tokio::test]
+    async fn test_login_over_memory_transport() {
+        let (listener, connector) = memory_transport();
+        let config = ServerConfig { storage: StorageConfig::Memory, ..Default::default() };
+        let datagram = DatagramServer::bind(&DatagramConfig { bind: "127.0.0.1:0".to_string(), advertise: None }, config.codec.registry.clone()).await.unwrap();
+        serve(&config, vec![(Box::new(listener), None)], Some(datagram)).unwrap();
+        let (mut sink, mut stream, _): (PacketSink, _, _) = framed_with_config::<Packet>(connector.connect().unwrap(), false, CodecConfig::default()).await.unwrap();
+        let rpc = Rpc::new();
+        let reader = rpc.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(packet)) = stream.next().await {
+                reader.complete(packet);
+            }
+        });
+        let mut login = LoginReq::new();
+        login.player_id = 233;
+        login.compression = true;
+        login.datagram = true;
+        let resp = rpc.call(&mut sink, Box::new(login), Duration::from_secs(1)).await.unwrap();
+        let resp = cast::<LoginResp>(resp).unwrap();
+        assert_eq!(resp.player_id, 1);
+        assert!(!resp.resume_token.is_empty());
+        assert!(resp.compression);
+        assert!(resp.datagram.addr.starts_with("127.0.0.1:"));
+        assert_eq!(resp.datagram.key.len(), 32);
+    }
+}
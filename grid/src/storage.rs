@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use log::error;
+use protobuf::{Message, MessageField};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use protocol::storage::PlayerData;
+
+use crate::config::StorageConfig;
+use crate::player::State;
+
+pub trait Storage: Send + Sync {
+    fn load(&self, account_id: i32) -> anyhow::Result<Option<State>>;
+
+    fn save(&self, account_id: i32, state: &State) -> anyhow::Result<()>;
+}
+
+pub fn new_storage(config: &StorageConfig) -> anyhow::Result<Box<dyn Storage>> {
+    let storage: Box<dyn Storage> = match config {
+        StorageConfig::Memory => Box::new(MemoryStorage::default()),
+        StorageConfig::File(dir) => Box::new(FileStorage::open(dir.clone())?),
+    };
+    Ok(storage)
+}
+
+enum StorageRequest {
+    Load(i32, oneshot::Sender<anyhow::Result<Option<State>>>),
+    Save(i32, State),
+    Flush(oneshot::Sender<()>),
+}
+
+/// the storage of a world, the blocking reads and writes run one by one on the blocking pool so the world
+/// never waits for the disk, and a load sees every save queued before it
+#[derive(Clone)]
+pub struct StorageHandle {
+    requests: UnboundedSender<StorageRequest>,
+}
+
+impl StorageHandle {
+    pub fn start(storage: Box<dyn Storage>) -> Self {
+        let storage: Arc<dyn Storage> = Arc::from(storage);
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let storage = storage.clone();
+                let handled = tokio::task::spawn_blocking(move || match request {
+                    StorageRequest::Load(account_id, done) => {
+                        let _ = done.send(storage.load(account_id));
+                    }
+                    StorageRequest::Save(account_id, state) => {
+                        if let Err(err) = storage.save(account_id, &state) {
+                            error!("save account {} state err {}",account_id,err);
+                        }
+                    }
+                    StorageRequest::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }).await;
+                if let Err(err) = handled {
+                    error!("storage request panicked {}",err);
+                }
+            }
+        });
+        Self { requests: tx }
+    }
+
+    pub async fn load(&self, account_id: i32) -> anyhow::Result<Option<State>> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.send(StorageRequest::Load(account_id, tx)).map_err(|_| anyhow!("storage stopped"))?;
+        rx.await?
+    }
+
+    /// queue the save, errors are logged
+    pub fn save(&self, account_id: i32, state: State) {
+        let _ = self.requests.send(StorageRequest::Save(account_id, state));
+    }
+
+    /// wait until every save queued before is written
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.requests.send(StorageRequest::Flush(tx)).is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+/// nothing survives a restart, for testing
+#[derive(Default)]
+pub struct MemoryStorage {
+    states: Mutex<HashMap<i32, State>>,
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self, account_id: i32) -> anyhow::Result<Option<State>> {
+        Ok(self.states.lock().unwrap().get(&account_id).cloned())
+    }
+
+    fn save(&self, account_id: i32, state: &State) -> anyhow::Result<()> {
+        self.states.lock().unwrap().insert(account_id, state.clone());
+        Ok(())
+    }
+}
+
+/// one file per account under the directory, the content is an encoded [PlayerData]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn open(dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, account_id: i32) -> PathBuf {
+        self.dir.join(format!("{}.bin", account_id))
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self, account_id: i32) -> anyhow::Result<Option<State>> {
+        let bytes = match fs::read(self.path(account_id)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let data = PlayerData::parse_from_bytes(&bytes)?;
+        Ok(Some(State {
+            player_state: data.state.unwrap_or_default(),
+            color: data.color.unwrap_or_default(),
+        }))
+    }
+
+    fn save(&self, account_id: i32, state: &State) -> anyhow::Result<()> {
+        let mut data = PlayerData::new();
+        data.state = MessageField::some(state.player_state.clone());
+        data.color = MessageField::some(state.color.clone());
+        //write and sync a temp file first so a crash or a power loss never leaves a half written state,
+        //then sync the directory so the rename itself survives
+        let path = self.path(account_id);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data.write_to_bytes()?)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use protocol::test::PlayerState;
+
+    use crate::player::{random_color, State};
+    use crate::storage::{FileStorage, Storage};
+
+    #[test]
+    fn test_file_storage() {
+        let dir = std::env::temp_dir().join(format!("aoi_storage_{}", std::process::id()));
+        let storage = FileStorage::open(dir.clone()).unwrap();
+        assert!(storage.load(1).unwrap().is_none());
+        let mut player_state = PlayerState::new();
+        player_state.x = 12.;
+        player_state.y = 34.;
+        let state = State { player_state, color: random_color() };
+        storage.save(1, &state).unwrap();
+        let loaded = storage.load(1).unwrap().unwrap();
+        assert_eq!(loaded.player_state, state.player_state);
+        assert_eq!(loaded.color, state.color);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,48 +1,131 @@
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
 use std::time::Duration;
 
-use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-use crate::message::{EventMessage, EventMessageReceiver, EventMessageSender};
+use crate::message::EventMessage;
 
+/// the shortest interval of a repeating timer, a zero one would fire on every [Ticker::handle_event] forever
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerHandle(u64);
+
+struct Timer {
+    deadline: Instant,
+    key: Option<String>,
+    interval: Option<Duration>,
+    event: Box<dyn ScheduleEvent>,
+}
+
+/// timers of an actor, all driven by a single sleep until the nearest deadline in [Ticker::handle_event]
 pub struct Ticker {
-    events: HashMap<String, JoinHandle<()>>,
-    event_sender: EventMessageSender,
-    event_receiver: EventMessageReceiver,
+    next_id: u64,
+    timers: HashMap<u64, Timer>,
+    deadlines: BTreeSet<(Instant, u64)>,
+    keys: HashMap<String, u64>,
 }
 
 impl Ticker {
     pub fn new() -> Self {
-        let (event_sender, event_receiver) = tokio::sync::mpsc::unbounded_channel();
         Self {
-            events: HashMap::new(),
-            event_sender,
-            event_receiver,
+            next_id: 0,
+            timers: HashMap::new(),
+            deadlines: BTreeSet::new(),
+            keys: HashMap::new(),
         }
     }
 
-    pub fn schedule_once(&mut self, delay: Duration, key: String, event: Box<dyn ScheduleEvent>) {
-        let sender = self.event_sender.clone();
-        let j = tokio::spawn(async move {
-            tokio::time::sleep(delay).await;
-            let _ = sender.send(EventMessage(event));
-        });
-        self.events.insert(key, j);
+    /// fire the event once after the delay, a timer already scheduled with the same key is replaced
+    pub fn schedule_once(&mut self, delay: Duration, key: String, event: Box<dyn ScheduleEvent>) -> TimerHandle {
+        self.schedule(delay, Some(key), None, event)
+    }
+
+    /// fire the event every interval until cancelled, a timer already scheduled with the same key is replaced,
+    /// the interval is at least [MIN_INTERVAL]
+    pub fn schedule_repeat(&mut self, interval: Duration, key: String, event: Box<dyn ScheduleEvent>) -> TimerHandle {
+        let interval = interval.max(MIN_INTERVAL);
+        self.schedule(interval, Some(key), Some(interval), event)
+    }
+
+    fn schedule(&mut self, delay: Duration, key: Option<String>, interval: Option<Duration>, event: Box<dyn ScheduleEvent>) -> TimerHandle {
+        if let Some(key) = &key {
+            self.cancel(key.clone());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let deadline = Instant::now() + delay;
+        if let Some(key) = &key {
+            self.keys.insert(key.clone(), id);
+        }
+        self.deadlines.insert((deadline, id));
+        self.timers.insert(id, Timer { deadline, key, interval, event });
+        TimerHandle(id)
     }
 
     pub fn cancel(&mut self, key: String) -> bool {
-        if let Some(j) = self.events.remove(&key) {
-            j.abort();
-            true
-        } else {
-            false
+        match self.keys.get(&key) {
+            Some(&id) => self.cancel_handle(TimerHandle(id)),
+            None => false,
+        }
+    }
+
+    pub fn cancel_handle(&mut self, handle: TimerHandle) -> bool {
+        match self.timers.remove(&handle.0) {
+            Some(timer) => {
+                self.deadlines.remove(&(timer.deadline, handle.0));
+                if let Some(key) = timer.key {
+                    self.keys.remove(&key);
+                }
+                true
+            }
+            None => false,
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    /// wait for the next due event, pending forever if there is no timer,
+    /// cancel safe so it can be used in `tokio::select!`
     pub async fn handle_event(&mut self) -> Option<EventMessage> {
-        self.event_receiver.recv().await
+        let &(deadline, id) = match self.deadlines.first() {
+            Some(first) => first,
+            None => std::future::pending().await,
+        };
+        if deadline > Instant::now() {
+            tokio::time::sleep_until(deadline).await;
+        }
+        self.deadlines.remove(&(deadline, id));
+        let mut timer = self.timers.remove(&id)?;
+        match timer.interval {
+            Some(interval) => {
+                let event = timer.event.clone();
+                timer.deadline += interval;
+                self.deadlines.insert((timer.deadline, id));
+                self.timers.insert(id, timer);
+                Some(EventMessage(event))
+            }
+            None => {
+                if let Some(key) = &timer.key {
+                    self.keys.remove(key);
+                }
+                Some(EventMessage(timer.event))
+            }
+        }
+    }
+}
+
+impl Default for Ticker {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -62,4 +145,43 @@ impl<T> EventClone for T where T: 'static + Clone + ScheduleEvent {
     fn clone_box(&self) -> Box<dyn ScheduleEvent> {
         Box::new(self.clone())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use crate::event::{ReceiveTimeoutEvent, SavePlayersEvent};
+    use crate::tick::Ticker;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ticker() {
+        let mut ticker = Ticker::new();
+        ticker.schedule_once(Duration::from_secs(10), ReceiveTimeoutEvent.to_string(), Box::new(ReceiveTimeoutEvent));
+        ticker.schedule_once(Duration::from_secs(1), ReceiveTimeoutEvent.to_string(), Box::new(ReceiveTimeoutEvent));
+        let handle = ticker.schedule_repeat(Duration::from_secs(3), SavePlayersEvent.to_string(), Box::new(SavePlayersEvent));
+        assert_eq!(ticker.len(), 2);
+        let event = ticker.handle_event().await.unwrap();
+        assert_eq!(event.0.to_string(), ReceiveTimeoutEvent.to_string());
+        for _ in 0..3 {
+            let event = ticker.handle_event().await.unwrap();
+            assert_eq!(event.0.to_string(), SavePlayersEvent.to_string());
+        }
+        assert!(ticker.cancel_handle(handle));
+        assert!(ticker.is_empty());
+        assert!(!ticker.cancel(ReceiveTimeoutEvent.to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ticker_zero_interval() {
+        let mut ticker = Ticker::new();
+        ticker.schedule_repeat(Duration::ZERO, SavePlayersEvent.to_string(), Box::new(SavePlayersEvent));
+        let start = Instant::now();
+        for _ in 0..3 {
+            ticker.handle_event().await.unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(3));
+    }
+}
@@ -1,15 +1,41 @@
 use std::collections::{HashMap, HashSet};
 use std::ops::Not;
+use std::sync::Arc;
+use std::time::Duration;
 
+use futures::FutureExt;
+use lazy_static::lazy_static;
 use log::{error, info, warn};
 use protobuf::{MessageDyn, MessageField};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use protocol::test::{PlayerState, SCPlayerEnterNotify, SCPlayerLeaveNotify, SCPlayerMoveNotify};
+use protocol::codec::{EncodedMessage, Outbound, Packet};
+use protocol::options::Direction;
+use protocol::registry::MessageRegistry;
+use protocol::router::{Router, TimingMiddleware};
+use protocol::movement::{MoveDeltaEncoder, MoveSettings, QuantizedState};
+use protocol::snapshot::{Snapshot, SnapshotEncoder};
+use protocol::test::{MoveDeltaAck, PlayerMoveNotify, PlayerState, SCOtherPlayersStateNotify, SCPlayerEnterNotify, SCPlayerLeaveNotify, SCPlayerMoveNotify, SnapshotAck};
+use protocol::test::scother_players_state_notify::Bundle;
 
+use crate::config::ServerConfig;
+use crate::entity::EntityIdAllocator;
+use crate::event::{SavePlayersEvent, SessionExpireEvent, SnapshotEvent};
 use crate::grid::{calculate_grid_id, Grid, is_player_grid_change};
-use crate::message::{KickOutReason, PlayerLoginData, PlayerMessage, PlayerMessageWrap, WorldMessage, WorldMessageSender, WorldMessageWrap};
+use crate::message::{KickOutReason, PlayerLoaded, PlayerLoginData, PlayerMessage, PlayerMessageSender, PlayerMessageWrap, PlayerResumeData, WorldMessage, WorldMessageSender, WorldMessageWrap};
 use crate::player::{PlayerSender, State};
-use crate::world_handler::{handle_player_login, handle_player_move};
+use crate::storage::{Storage, StorageHandle};
+use crate::tick::Ticker;
+use crate::world_handler::{handle_event, handle_move_ack, handle_player_disconnect, handle_player_loaded, handle_player_login, handle_player_move, handle_player_resume, handle_snapshot_ack};
+
+lazy_static! {
+    /// client messages forwarded by the players, the context is the sender player id
+    pub static ref WORLD_ROUTER: Router<World, i32> = Router::new()
+        .layer(TimingMiddleware { threshold: Duration::from_millis(10) })
+        .route::<PlayerMoveNotify, _>(|world, player_id, notify| handle_player_move(world, player_id, notify).boxed())
+        .route::<MoveDeltaAck, _>(|world, player_id, ack| handle_move_ack(world, player_id, ack).boxed())
+        .route::<SnapshotAck, _>(|world, player_id, ack| handle_snapshot_ack(world, player_id, ack).boxed());
+}
 
 pub const H: usize = 200;
 pub const V: usize = 200;
@@ -28,17 +54,46 @@ pub const AOI_V_SIDE: usize = 10;
 pub struct World {
     pub world_id: i32,
     pub sessions: HashMap<i32, PlayerSender>,
+    pub resume_tokens: HashMap<i32, String>,
+    pub resume_grace_period: Duration,
+    pub entity_ids: EntityIdAllocator,
+    pub storage: StorageHandle,
+    /// logins waiting for their stored state, see [handle_player_loaded]
+    pub loaded_sender: UnboundedSender<PlayerLoaded>,
+    pub loaded_receiver: UnboundedReceiver<PlayerLoaded>,
+    pub save_interval: Duration,
     pub player_grid: HashMap<i32, (i32, i32)>,
     pub grids: HashMap<i32, HashMap<i32, Grid>>,
+    pub ticker: Ticker,
+    /// broadcasts are encoded once with the ids of the registry
+    pub registry: Arc<MessageRegistry>,
+    /// the players with quantized moves, the others get the full SCPlayerMoveNotify
+    pub move_encoders: HashMap<i32, MoveDeltaEncoder>,
+    /// the players with snapshot moves, they get no message per move
+    pub snapshot_encoders: HashMap<i32, SnapshotEncoder>,
+    pub snapshot_interval: Duration,
 }
 
 impl World {
-    pub fn new() -> Self {
+    pub fn new(config: &ServerConfig, storage: Box<dyn Storage>) -> Self {
+        let (loaded_sender, loaded_receiver) = tokio::sync::mpsc::unbounded_channel();
         Self {
             world_id: 0,
             sessions: HashMap::new(),
+            resume_tokens: HashMap::new(),
+            resume_grace_period: config.resume_grace_period,
+            entity_ids: EntityIdAllocator::new(config.entity_id_reuse_delay),
+            storage: StorageHandle::start(storage),
+            loaded_sender,
+            loaded_receiver,
+            save_interval: config.save_interval,
             player_grid: HashMap::new(),
             grids: HashMap::new(),
+            ticker: Ticker::new(),
+            registry: config.codec.registry.clone(),
+            move_encoders: HashMap::new(),
+            snapshot_encoders: HashMap::new(),
+            snapshot_interval: config.snapshot_interval,
         }
     }
 
@@ -46,46 +101,127 @@ impl World {
         let player_id = msg.player_id;
         match msg.message {
             WorldMessage::PlayerLogin(data) => {
-                handle_player_login(self, player_id, data).await?;
+                handle_player_login(self, data).await?;
+            }
+            WorldMessage::PlayerResume(data) => {
+                handle_player_resume(self, data).await?;
             }
-            WorldMessage::PlayerLogout => {}
-            WorldMessage::PlayerMove(data) => {
-                handle_player_move(self, player_id, data).await?;
+            WorldMessage::PlayerDisconnect(sender) => {
+                handle_player_disconnect(self, player_id, sender).await?;
+            }
+            WorldMessage::PlayerMove(msg) | WorldMessage::Proto(msg) => {
+                WORLD_ROUTER.dispatch(self, player_id, msg).await?;
+            }
+            WorldMessage::Shutdown(done) => {
+                info!("world {} shutdown, save all players",self.world_id);
+                self.save_all_players();
+                self.storage.flush().await;
+                let _ = done.send(());
             }
-            WorldMessage::Proto(_) => {}
         }
         Ok(())
     }
 
-    /// broadcast msg to current player's aoi view players
-    pub fn broadcast_msg_to_player_aoi(&mut self, current_player: i32, msg: Box<dyn MessageDyn>, include_self: bool) {
+    /// players in current player's aoi view
+    pub fn aoi_players(&mut self, current_player: i32, include_self: bool) -> Vec<i32> {
         let aoi_grids = self.get_player_aoi_view(current_player);
         let mut aoi_players = HashSet::new();
         for (_, grid) in aoi_grids {
-            let players: Vec<i32> = grid.players.keys().map(|&k| { k }).collect();
+            let players: Vec<i32> = grid.players.keys().copied().collect();
             aoi_players.extend(players)
         }
         if include_self.not() {
             aoi_players.remove(&current_player);
         }
-        self.broadcast_msg(Vec::from_iter(aoi_players.into_iter()), msg);
+        Vec::from_iter(aoi_players)
+    }
+
+    /// broadcast msg to current player's aoi view players
+    pub fn broadcast_msg_to_player_aoi(&mut self, current_player: i32, msg: Box<dyn MessageDyn>, include_self: bool) {
+        let aoi_players = self.aoi_players(current_player, include_self);
+        self.broadcast_msg(aoi_players, msg);
+    }
+
+    /// send the move to the aoi players in the encoding each of them negotiated,
+    /// the full notify is encoded once and a delta is encoded per observer
+    pub fn broadcast_move(&mut self, player_id: i32, state: PlayerState) {
+        let (delta_observers, full_observers): (Vec<i32>, Vec<i32>) = self.aoi_players(player_id, true)
+            .into_iter()
+            .filter(|observer| self.snapshot_encoders.contains_key(observer).not())
+            .partition(|observer| self.move_encoders.contains_key(observer));
+        for observer in delta_observers {
+            let observer_state = self.get_player_state(observer).map(|state| state.player_state);
+            let (Some(sender), Some(encoder)) = (self.sessions.get(&observer), self.move_encoders.get_mut(&observer)) else {
+                continue;
+            };
+            let delta = encoder.encode(player_id, &state, observer_state.as_ref());
+            let _ = sender.proto.send(Packet::notify(Box::new(delta)).into());
+        }
+        let mut notify = SCPlayerMoveNotify::new();
+        notify.player_id = player_id;
+        notify.state = MessageField::some(state);
+        self.broadcast_msg(full_observers, Box::new(notify));
+    }
+
+    /// a new session starts the quantized moves and snapshots over from full states
+    fn set_move_encoding(&mut self, player_id: i32, movement: MoveSettings) {
+        self.move_encoders.remove(&player_id);
+        self.snapshot_encoders.remove(&player_id);
+        if movement.is_quantized() {
+            self.move_encoders.insert(player_id, MoveDeltaEncoder::new(movement.observer_relative));
+        } else if movement.is_snapshot() {
+            self.snapshot_encoders.insert(player_id, SnapshotEncoder::new());
+        }
+    }
+
+    /// send every snapshot observer the players in its aoi view, relative to the last snapshot it acked
+    pub fn send_snapshots(&mut self) {
+        let observers: Vec<i32> = self.snapshot_encoders.keys().copied().collect();
+        for observer in observers {
+            let Some(sender) = self.sessions.get(&observer).cloned() else {
+                continue;
+            };
+            let snapshot: Snapshot = self.get_player_aoi_view(observer)
+                .into_values()
+                .flat_map(|grid| grid.players)
+                .map(|(player_id, state)| (player_id, QuantizedState::quantize(&state.player_state)))
+                .collect();
+            let Some(encoder) = self.snapshot_encoders.get_mut(&observer) else {
+                continue;
+            };
+            let msg = encoder.encode(snapshot);
+            let _ = sender.proto.send(Packet::notify(Box::new(msg)).into());
+        }
     }
 
     pub fn broadcast_msg_to_grid(&mut self, grid: &Grid, msg: Box<dyn MessageDyn>) {
-        let players: Vec<i32> = grid.players.keys().map(|&k| { k }).collect();
+        let players: Vec<i32> = grid.players.keys().copied().collect();
         self.broadcast_msg(players, msg);
     }
 
+    /// the message is serialized once and the buffer is shared by the write tasks of the players
     pub fn broadcast_msg(&mut self, players: Vec<i32>, msg: Box<dyn MessageDyn>) {
+        let encoded = match EncodedMessage::new(&self.registry, Direction::SC, msg.as_ref()) {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                error!("encode broadcast msg:{} err {}",msg.descriptor_dyn().name(),err);
+                return;
+            }
+        };
         let mut remove_players = vec![];
         for player_id in players {
-            let sender = &self.sessions[&player_id];
-            if let Some(err) = sender.proto.send(msg.clone()).err() {
+            //detached players are still in the grid but have no session
+            let Some(sender) = self.sessions.get(&player_id) else {
+                continue;
+            };
+            if let Some(err) = sender.proto.send(Outbound::Encoded(encoded.clone())).err() {
                 warn!("broadcast message to player {} err {}, player session will be remove",player_id,err);
                 remove_players.push(player_id);
             }
         }
-        self.remove_players(remove_players);
+        for player_id in remove_players {
+            self.leave_world(player_id);
+        }
     }
 
     pub fn remove_players(&mut self, players: Vec<i32>) {
@@ -97,14 +233,31 @@ impl World {
             let mut notify = SCPlayerLeaveNotify::new();
             notify.player_id = player_id;
             self.broadcast_msg_to_player_aoi(player_id, Box::new(notify), false);
+            self.move_encoders.remove(&player_id);
+            self.snapshot_encoders.remove(&player_id);
+            for encoder in self.move_encoders.values_mut() {
+                encoder.forget(player_id);
+            }
 
             self.remove_player_from_grid(player_id);
         }
     }
 
+    /// save and remove the player from the world and release its entity id
+    pub fn leave_world(&mut self, player_id: i32) {
+        self.save_player(player_id);
+        self.ticker.cancel(SessionExpireEvent::key(player_id));
+        self.resume_tokens.remove(&player_id);
+        self.remove_players(vec![player_id]);
+        self.entity_ids.release(player_id);
+    }
+
     pub fn add_player(&mut self, player_id: i32, player_login_data: PlayerLoginData) {
+        self.ticker.cancel(SessionExpireEvent::key(player_id));
         self.remove_players(vec![player_id]);
         self.sessions.insert(player_id, player_login_data.sender);
+        self.set_move_encoding(player_id, player_login_data.movement);
+        self.resume_tokens.insert(player_id, player_login_data.resume_token);
         let color = player_login_data.state.color.clone();
         self.add_player_to_grid(player_id, player_login_data.state);
         let mut notify = SCPlayerEnterNotify::new();
@@ -113,6 +266,85 @@ impl World {
         self.broadcast_msg_to_player_aoi(player_id, Box::new(notify), true);
     }
 
+    /// keep the player in the grid without a session until it resumes or the grace period expires,
+    /// a disconnect from a session that has already been replaced is ignored
+    pub fn detach_player(&mut self, player_id: i32, sender: &PlayerMessageSender) {
+        let attached = self.sessions.get(&player_id).map(|s| s.player.same_channel(sender)).unwrap_or(false);
+        if attached.not() {
+            return;
+        }
+        self.sessions.remove(&player_id);
+        self.move_encoders.remove(&player_id);
+        self.snapshot_encoders.remove(&player_id);
+        if self.resume_grace_period.is_zero() {
+            self.expire_player(player_id);
+        } else {
+            self.ticker.schedule_once(self.resume_grace_period, SessionExpireEvent::key(player_id), Box::new(SessionExpireEvent { player_id }));
+            info!("player {} detached from world {}",player_id,self.world_id);
+        }
+    }
+
+    /// attach a new session to the account's existing world presence,
+    /// returns the entity id and state of the player, `None` if the token is invalid
+    pub fn resume_player(&mut self, data: PlayerResumeData) -> Option<(i32, State)> {
+        let player_id = self.entity_ids.entity_id(data.account_id)?;
+        if self.resume_tokens.get(&player_id) != Some(&data.resume_token) {
+            return None;
+        }
+        let state = self.get_player_state(player_id)?;
+        self.ticker.cancel(SessionExpireEvent::key(player_id));
+        self.set_move_encoding(player_id, data.movement);
+        if let Some(previous) = self.sessions.insert(player_id, data.sender) {
+            let _ = previous.player.send(PlayerMessageWrap::new(self.world_id, PlayerMessage::KickOut(KickOutReason::SessionResumed)));
+        }
+        info!("player {} session resumed in world {}",player_id,self.world_id);
+        Some((player_id, state))
+    }
+
+    pub fn expire_player(&mut self, player_id: i32) {
+        if self.sessions.contains_key(&player_id) {
+            return;
+        }
+        self.leave_world(player_id);
+        info!("player {} session expired in world {}",player_id,self.world_id);
+    }
+
+    pub fn save_player(&self, player_id: i32) {
+        let (Some(account_id), Some(state)) = (self.entity_ids.account_id(player_id), self.get_player_state(player_id)) else {
+            return;
+        };
+        self.storage.save(account_id, state);
+    }
+
+    pub fn save_all_players(&self) {
+        for &player_id in self.player_grid.keys() {
+            self.save_player(player_id);
+        }
+    }
+
+    pub fn get_player_state(&self, player_id: i32) -> Option<State> {
+        let (n_x, n_y) = self.player_grid.get(&player_id)?;
+        let grid = self.search_grid_by_grid_id(*n_x, *n_y)?;
+        grid.players.get(&player_id).cloned()
+    }
+
+    /// state of all the other players in current player's aoi view
+    pub fn aoi_snapshot(&mut self, player_id: i32) -> SCOtherPlayersStateNotify {
+        let mut notify = SCOtherPlayersStateNotify::new();
+        for (_, grid) in self.get_player_aoi_view(player_id) {
+            for (id, state) in grid.players {
+                if id != player_id {
+                    let mut bundle = Bundle::new();
+                    bundle.player_id = id;
+                    bundle.state = MessageField::some(state.player_state);
+                    bundle.color = MessageField::some(state.color);
+                    notify.players.push(bundle);
+                }
+            }
+        }
+        notify
+    }
+
     pub fn search_grid_by_location(&mut self, x: f32, y: f32) -> Option<&mut Grid> {
         let n_x = (x / L as f32) as i32;
         let n_y = (y / L as f32) as i32;
@@ -139,7 +371,7 @@ impl World {
         }
     }
 
-    pub fn add_player_to_grid(&mut self, player_id: i32, mut state: State) {
+    pub fn add_player_to_grid(&mut self, player_id: i32, state: State) {
         let player_state = &state.player_state;
         let x = player_state.x;
         let y = player_state.y;
@@ -148,8 +380,8 @@ impl World {
         if let Some(grid) = self.search_grid_by_location(x, y) {
             grid.players.insert(player_id, state);
         } else {
-            let column = self.grids.entry(n_x).or_insert(Default::default());
-            let grid = column.entry(n_y).or_insert(Default::default());
+            let column = self.grids.entry(n_x).or_default();
+            let grid = column.entry(n_y).or_default();
             grid.players.insert(player_id, state);
         }
     }
@@ -167,39 +399,39 @@ impl World {
     pub fn get_player_aoi_view(&mut self, player_id: i32) -> HashMap<(i32, i32), Grid> {
         let mut aoi_grids = HashMap::new();
         if let Some((n_x, n_y)) = self.player_grid.get(&player_id) {
-            let n_x = n_x.clone();
-            let n_y = n_y.clone();
+            let n_x = *n_x;
+            let n_y = *n_y;
             let mut aoi_grid_id = vec![];
-            let center = self.search_grid_by_grid_id(n_x, n_y).expect(&*format!("aoi grid:({},{}) not found", n_x, n_y));
+            let center = self.search_grid_by_grid_id(n_x, n_y).unwrap_or_else(|| panic!("aoi grid:({},{}) not found", n_x, n_y));
             aoi_grids.insert((n_x, n_y), center.clone());
             //left
             let mut left_tmp = n_x;
-            for i in 1..=AOI_H_SIDE {
+            for _ in 1..=AOI_H_SIDE {
                 left_tmp -= L as i32;
                 aoi_grid_id.push((left_tmp, n_y));
             }
             //right
             let mut right_tmp = n_x;
-            for i in 1..=AOI_H_SIDE {
+            for _ in 1..=AOI_H_SIDE {
                 right_tmp += L as i32;
                 aoi_grid_id.push((right_tmp, n_y));
             }
             //up
             let mut up_tmp = n_y;
-            for i in 1..=AOI_V_SIDE {
+            for _ in 1..=AOI_V_SIDE {
                 up_tmp -= L as i32;
                 aoi_grid_id.push((n_x, up_tmp));
             }
             //down
             let mut down_tmp = n_y;
-            for i in 1..=AOI_V_SIDE {
+            for _ in 1..=AOI_V_SIDE {
                 down_tmp += L as i32;
                 aoi_grid_id.push((n_x, down_tmp));
             }
             for (n_x, n_y) in aoi_grid_id {
                 if (n_x >= 0 && n_x <= H as i32) && (n_y >= 0 && n_y <= V as i32) {
-                    let column = &mut self.grids.entry(n_x).or_insert(HashMap::new());
-                    let grid = column.entry(n_y).or_insert(Default::default());
+                    let column = &mut self.grids.entry(n_x).or_default();
+                    let grid = column.entry(n_y).or_default();
                     aoi_grids.insert((n_x, n_y), grid.clone());
                 }
             }
@@ -211,18 +443,13 @@ impl World {
         let current_x = new_player_state.x;
         let current_y = new_player_state.y;
         let (n_x, n_y) = self.player_grid[&player_id];
-        let grid = self.search_grid_by_grid_id_mut(n_x, n_y).expect(&*format!("the player:{} player grid:({},{}) not found", player_id, n_x, n_y));
+        let grid = self.search_grid_by_grid_id_mut(n_x, n_y).unwrap_or_else(|| panic!("the player:{} player grid:({},{}) not found", player_id, n_x, n_y));
         let state = &grid.players[&player_id];
         let player_color = state.color.clone();
         let previous_x = state.player_state.x;
         let previous_y = state.player_state.y;
-        let mut player_move_notify = SCPlayerMoveNotify::new();
-        player_move_notify.player_id = player_id;
-        player_move_notify.state = MessageField::some(new_player_state.clone());
         if is_player_grid_change((previous_x, previous_y), (current_x, current_y)) {
             //remove player form old grid and join the new grid
-            let previous_grid = calculate_grid_id(previous_x, previous_y);
-            let current_grid = calculate_grid_id(current_x, current_y);
             let previous_aoi_view = self.get_player_aoi_view(player_id);
 
             let mut state = self.remove_player_from_grid(player_id).unwrap();
@@ -233,13 +460,13 @@ impl World {
             let mut leave_grids = vec![];
             let mut enter_grids = vec![];
             for (grid_id, g) in &previous_aoi_view {
-                if current_aoi_view.contains_key(&grid_id).not() {
+                if current_aoi_view.contains_key(grid_id).not() {
                     leave_grids.push(g);
                 }
             }
 
             for (grid_id, g) in &current_aoi_view {
-                if previous_aoi_view.contains_key(&grid_id).not() {
+                if previous_aoi_view.contains_key(grid_id).not() {
                     enter_grids.push(g);
                 }
             }
@@ -257,23 +484,25 @@ impl World {
                 self.broadcast_msg_to_grid(g, Box::new(notify));
             }
         } else {
-            //player grid not change, just notify all the aoi players
-            self.broadcast_msg_to_player_aoi(player_id, Box::new(player_move_notify), true);
+            //player grid not change, just notify all the aoi players, snapshots read the state from the grid
+            if let Some(state) = self.search_grid_by_grid_id_mut(n_x, n_y).and_then(|grid| grid.players.get_mut(&player_id)) {
+                state.player_state = new_player_state.clone();
+            }
+            self.broadcast_move(player_id, new_player_state);
         }
     }
 }
 
-pub fn start_world() -> WorldMessageSender {
-    let world = World::new();
+pub fn start_world(config: &ServerConfig, storage: Box<dyn Storage>) -> WorldMessageSender {
+    let world = World::new(config, storage);
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<WorldMessageWrap>();
     tokio::spawn(async move {
         let mut world = world;
+        world.ticker.schedule_repeat(world.save_interval, SavePlayersEvent.to_string(), Box::new(SavePlayersEvent));
+        world.ticker.schedule_repeat(world.snapshot_interval, SnapshotEvent.to_string(), Box::new(SnapshotEvent));
         loop {
-            match rx.recv().await {
-                None => {
-                    //world dont stop
-                }
-                Some(message) => {
+            tokio::select! {
+                Some(message) = rx.recv() => {
                     match world.handle_world_msg(message).await {
                         Ok(_) => {}
                         Err(err) => {
@@ -281,8 +510,165 @@ pub fn start_world() -> WorldMessageSender {
                         }
                     }
                 }
-            };
+                Some(loaded) = world.loaded_receiver.recv() => {
+                    if let Err(err) = handle_player_loaded(&mut world, loaded).await {
+                        error!("world {} handle loaded player error {}",world.world_id,err);
+                    }
+                }
+                Some(event) = world.ticker.handle_event() => {
+                    match handle_event(&mut world, event).await {
+                        Ok(_) => {}
+                        Err(err) => {
+                            error!("world {} handle event error {}",world.world_id,err);
+                        }
+                    }
+                }
+                else => {
+                    //world dont stop
+                }
+            }
         }
     });
     tx
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use protocol::codec::Outbound;
+    use protocol::movement::{MoveSettings, QuantizedState};
+    use protocol::test::{PlayerState, SCPlayerMoveDelta, SCWorldSnapshot};
+
+    use crate::config::ServerConfig;
+    use crate::message::{PlayerLoginData, PlayerMessageReceiver, PlayerResumeData, ProtoMessageReceiver};
+    use crate::player::{PlayerSender, State};
+    use crate::storage::MemoryStorage;
+    use crate::world::World;
+
+    fn new_sender() -> (PlayerSender, PlayerMessageReceiver, ProtoMessageReceiver) {
+        let (player, player_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (proto, proto_rx) = tokio::sync::mpsc::unbounded_channel();
+        (PlayerSender { player, proto }, player_rx, proto_rx)
+    }
+
+    #[tokio::test]
+    async fn test_resume_session() {
+        let mut world = World::new(&ServerConfig::default(), Box::new(MemoryStorage::default()));
+        let (sender, _player_rx, _proto_rx) = new_sender();
+        let (other, _other_player_rx, _other_proto_rx) = new_sender();
+        let player_id = world.entity_ids.allocate(100);
+        world.add_player(player_id, PlayerLoginData {
+            account_id: 100,
+            sender: sender.clone(),
+            state: State::default(),
+            resume_token: "token".to_string(),
+            compression: false,
+            seq: None,
+            movement: Default::default(),
+            datagram: None,
+        });
+        world.detach_player(player_id, &other.player);
+        assert!(world.sessions.contains_key(&player_id));
+        world.detach_player(player_id, &sender.player);
+        assert!(!world.sessions.contains_key(&player_id));
+        assert!(world.player_grid.contains_key(&player_id));
+
+        let rejected = world.resume_player(PlayerResumeData { account_id: 100, sender: other.clone(), resume_token: "other".to_string(), compression: false, seq: None, movement: Default::default(), datagram: None });
+        assert!(rejected.is_none());
+        let resumed = world.resume_player(PlayerResumeData { account_id: 100, sender: other.clone(), resume_token: "token".to_string(), compression: false, seq: None, movement: Default::default(), datagram: None });
+        assert_eq!(resumed.map(|(id, _)| id), Some(player_id));
+        world.expire_player(player_id);
+        assert!(world.sessions.contains_key(&player_id));
+
+        world.detach_player(player_id, &other.player);
+        world.expire_player(player_id);
+        assert!(!world.player_grid.contains_key(&player_id));
+        assert!(!world.resume_tokens.contains_key(&player_id));
+        assert_eq!(world.entity_ids.entity_id(100), None);
+        assert!(world.storage.load(100).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_move() {
+        let mut world = World::new(&ServerConfig::default(), Box::new(MemoryStorage::default()));
+        let mut receivers = vec![];
+        for (account_id, movement) in [(100, MoveSettings::default()), (101, MoveSettings::quantized(false))] {
+            let (sender, player_rx, proto_rx) = new_sender();
+            let player_id = world.entity_ids.allocate(account_id);
+            world.add_player(player_id, PlayerLoginData {
+                account_id,
+                sender,
+                state: State::default(),
+                resume_token: "token".to_string(),
+                compression: false,
+                seq: None,
+                movement,
+                datagram: None,
+            });
+            receivers.push((player_id, player_rx, proto_rx));
+        }
+        for (_, _, proto_rx) in receivers.iter_mut() {
+            while proto_rx.try_recv().is_ok() {}
+        }
+        let mover = receivers[0].0;
+        let mut state = PlayerState::new();
+        state.x = 1.5;
+        state.speed = 2.;
+        world.broadcast_move(mover, state.clone());
+
+        let Ok(Outbound::Encoded(full)) = receivers[0].2.try_recv() else {
+            panic!("full observer expects the shared notify");
+        };
+        assert_eq!(full.id(), 3);
+        let Ok(Outbound::Packet(packet)) = receivers[1].2.try_recv() else {
+            panic!("quantized observer expects a delta");
+        };
+        let delta = packet.msg.downcast_box::<SCPlayerMoveDelta>().unwrap();
+        assert_eq!((delta.player_id, delta.baseline, delta.x), (mover, 0, Some(24)));
+        assert_eq!(QuantizedState::quantize(&state).speed, delta.speed.unwrap());
+        assert!(receivers[0].2.try_recv().is_err() && receivers[1].2.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_snapshots() {
+        let mut world = World::new(&ServerConfig::default(), Box::new(MemoryStorage::default()));
+        let mut receivers = vec![];
+        for (account_id, movement) in [(100, MoveSettings::default()), (101, MoveSettings::snapshot())] {
+            let (sender, player_rx, proto_rx) = new_sender();
+            let player_id = world.entity_ids.allocate(account_id);
+            world.add_player(player_id, PlayerLoginData {
+                account_id,
+                sender,
+                state: State::default(),
+                resume_token: "token".to_string(),
+                compression: false,
+                seq: None,
+                movement,
+                datagram: None,
+            });
+            receivers.push((player_id, player_rx, proto_rx));
+        }
+        for (_, _, proto_rx) in receivers.iter_mut() {
+            while proto_rx.try_recv().is_ok() {}
+        }
+        let (mover, observer) = (receivers[0].0, receivers[1].0);
+        let snapshot = |world: &mut World, proto_rx: &mut ProtoMessageReceiver| {
+            world.send_snapshots();
+            let Ok(Outbound::Packet(packet)) = proto_rx.try_recv() else {
+                panic!("snapshot observer expects a snapshot");
+            };
+            packet.msg.downcast_box::<SCWorldSnapshot>().unwrap()
+        };
+        let full = snapshot(&mut world, &mut receivers[1].2);
+        assert_eq!((full.baseline, full.entities.len()), (0, 2));
+        let mut state = PlayerState::new();
+        state.x = 2.;
+        world.move_player(mover, state);
+        //no message per move for the snapshot observer
+        assert!(matches!(receivers[0].2.try_recv(), Ok(Outbound::Encoded(_))));
+        assert!(receivers[1].2.try_recv().is_err());
+        world.snapshot_encoders.get_mut(&observer).unwrap().ack(full.sequence);
+        let delta = snapshot(&mut world, &mut receivers[1].2);
+        assert_eq!(delta.baseline, full.sequence);
+        assert_eq!(delta.entities.len(), 1);
+        assert_eq!((delta.entities[0].player_id, delta.entities[0].x, delta.entities[0].y), (mover, Some(32), None));
+    }
+}
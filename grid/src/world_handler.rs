@@ -1,19 +1,134 @@
-use protobuf::MessageDyn;
+use std::any::Any;
+use std::ops::Not;
 
-use protocol::mapper::cast;
-use protocol::test::PlayerMoveNotify;
+use anyhow::anyhow;
+use protobuf::MessageField;
 
-use crate::message::PlayerLoginData;
+use protocol::codec::Packet;
+use protocol::movement::MoveSettings;
+use protocol::test::{DatagramChannel, ErrorCode, ErrorResp, LoginResp, MoveDeltaAck, PlayerMoveNotify, SnapshotAck};
+
+use crate::event::{SavePlayersEvent, SessionExpireEvent, SnapshotEvent};
+use crate::message::{EventMessage, PlayerLoaded, PlayerLoginData, PlayerMessage, PlayerMessageSender, PlayerMessageWrap, PlayerResumeData};
+use crate::player::State;
 use crate::world::World;
 
-pub async fn handle_player_login(world: &mut World, player_id: i32, player_login_data: PlayerLoginData) -> anyhow::Result<()> {
+pub async fn handle_player_login(world: &mut World, player_login_data: PlayerLoginData) -> anyhow::Result<()> {
+    let account_id = player_login_data.account_id;
+    //a player still in the world, e.g. waiting for a resume, keeps its state
+    if let Some(state) = world.entity_ids.entity_id(account_id).and_then(|player_id| world.get_player_state(player_id)) {
+        return enter_world(world, player_login_data, Ok(Some(state)));
+    }
+    //the world goes on while the storage reads
+    let storage = world.storage.clone();
+    let loaded = world.loaded_sender.clone();
+    tokio::spawn(async move {
+        let state = storage.load(account_id).await;
+        let _ = loaded.send(PlayerLoaded { data: player_login_data, state });
+    });
+    Ok(())
+}
+
+pub async fn handle_player_loaded(world: &mut World, loaded: PlayerLoaded) -> anyhow::Result<()> {
+    //the account may have entered the world while loading, its state there is newer
+    let in_world = world.entity_ids.entity_id(loaded.data.account_id).and_then(|player_id| world.get_player_state(player_id));
+    let state = match in_world {
+        Some(state) => Ok(Some(state)),
+        None => loaded.state,
+    };
+    enter_world(world, loaded.data, state)
+}
+
+fn enter_world(world: &mut World, mut player_login_data: PlayerLoginData, state: anyhow::Result<Option<State>>) -> anyhow::Result<()> {
+    let account_id = player_login_data.account_id;
+    let sender = player_login_data.sender.clone();
+    match state {
+        Ok(Some(state)) => {
+            player_login_data.state = state;
+        }
+        Ok(None) => {}
+        Err(err) => {
+            let mut rsp = ErrorResp::new();
+            rsp.code = ErrorCode::INTERNAL_ERROR.into();
+            rsp.reason = "load player data failed".to_string();
+            let _ = sender.proto.send(Packet::reply(player_login_data.seq, Box::new(rsp)).into());
+            return Err(anyhow!("account {} load player data err {}", account_id, err));
+        }
+    }
+    let player_id = world.entity_ids.allocate(account_id);
+    let _ = sender.player.send(PlayerMessageWrap::new(world.world_id, PlayerMessage::LoginAccepted(player_id, player_login_data.state.clone())));
+    let _ = sender.proto.send(Packet::reply(player_login_data.seq, Box::new(login_resp(player_id, &player_login_data.state, player_login_data.resume_token.clone(), player_login_data.compression, player_login_data.movement, player_login_data.datagram.clone()))).into());
     world.add_player(player_id, player_login_data);
     //todo sync other player's state
     Ok(())
 }
 
-pub async fn handle_player_move(world: &mut World, player_id: i32, msg: Box<dyn MessageDyn>) -> anyhow::Result<()> {
-    let notify = cast::<PlayerMoveNotify>(msg)?;
+pub async fn handle_player_resume(world: &mut World, data: PlayerResumeData) -> anyhow::Result<()> {
+    let sender = data.sender.clone();
+    let resume_token = data.resume_token.clone();
+    let compression = data.compression;
+    let seq = data.seq;
+    let movement = data.movement;
+    let datagram = data.datagram.clone();
+    match world.resume_player(data) {
+        Some((player_id, state)) => {
+            let _ = sender.proto.send(Packet::reply(seq, Box::new(login_resp(player_id, &state, resume_token, compression, movement, datagram))).into());
+            let _ = sender.proto.send(Packet::notify(Box::new(world.aoi_snapshot(player_id))).into());
+            let _ = sender.player.send(PlayerMessageWrap::new(world.world_id, PlayerMessage::SessionResumed(player_id, state)));
+        }
+        None => {
+            let _ = sender.player.send(PlayerMessageWrap::new(world.world_id, PlayerMessage::ResumeRejected(seq)));
+        }
+    }
+    Ok(())
+}
+
+pub async fn handle_player_disconnect(world: &mut World, player_id: i32, sender: PlayerMessageSender) -> anyhow::Result<()> {
+    world.detach_player(player_id, &sender);
+    Ok(())
+}
+
+pub async fn handle_player_move(world: &mut World, player_id: i32, notify: PlayerMoveNotify) -> anyhow::Result<()> {
+    if world.player_grid.contains_key(&player_id).not() {
+        return Err(anyhow!("player {} not in world", player_id));
+    }
     world.move_player(player_id, notify.state.unwrap());
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn handle_move_ack(world: &mut World, player_id: i32, ack: MoveDeltaAck) -> anyhow::Result<()> {
+    if let Some(encoder) = world.move_encoders.get_mut(&player_id) {
+        encoder.ack(ack.sequence);
+    }
+    Ok(())
+}
+
+pub async fn handle_snapshot_ack(world: &mut World, player_id: i32, ack: SnapshotAck) -> anyhow::Result<()> {
+    if let Some(encoder) = world.snapshot_encoders.get_mut(&player_id) {
+        encoder.ack(ack.sequence);
+    }
+    Ok(())
+}
+
+pub async fn handle_event(world: &mut World, event: EventMessage) -> anyhow::Result<()> {
+    let event: Box<dyn Any> = event.0;
+    if let Some(event) = event.downcast_ref::<SessionExpireEvent>() {
+        world.expire_player(event.player_id);
+    } else if event.is::<SavePlayersEvent>() {
+        world.save_all_players();
+    } else if event.is::<SnapshotEvent>() {
+        world.send_snapshots();
+    }
+    Ok(())
+}
+
+fn login_resp(player_id: i32, state: &State, resume_token: String, compression: bool, movement: MoveSettings, datagram: Option<DatagramChannel>) -> LoginResp {
+    let mut rsp = LoginResp::new();
+    rsp.player_id = player_id;
+    rsp.color = MessageField::some(state.color.clone());
+    rsp.resume_token = resume_token;
+    rsp.compression = compression;
+    movement.write_resp(&mut rsp);
+    rsp.datagram = datagram.into();
+    rsp
+}
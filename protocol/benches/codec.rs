@@ -0,0 +1,120 @@
+//! encode and decode throughput of ProtoCodec, the allocations per frame are printed before each group
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::BytesMut;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use protobuf::{MessageDyn, MessageField};
+use tokio_util::codec::{Decoder, Encoder};
+
+use protocol::codec::{Packet, ProtoCodec};
+use protocol::test::{PlayerState, SCOtherPlayersStateNotify, SCPlayerMoveNotify};
+use protocol::test::scother_players_state_notify::Bundle;
+
+/// counts every allocation of the process
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const FRAMES: usize = 1000;
+
+fn move_notify() -> Box<dyn MessageDyn> {
+    let mut notify = SCPlayerMoveNotify::new();
+    notify.player_id = 233;
+    let mut state = PlayerState::new();
+    state.x = 1.;
+    state.y = 2.;
+    state.speed = 3.;
+    notify.state = MessageField::some(state);
+    Box::new(notify)
+}
+
+fn players_notify() -> Box<dyn MessageDyn> {
+    let mut notify = SCOtherPlayersStateNotify::new();
+    for id in 0..500 {
+        let mut bundle = Bundle::new();
+        bundle.player_id = id;
+        bundle.state = MessageField::some(PlayerState::new());
+        notify.players.push(bundle);
+    }
+    Box::new(notify)
+}
+
+fn codecs(compression: bool) -> (ProtoCodec, ProtoCodec) {
+    let server = ProtoCodec::new(true);
+    server.compression().negotiate(compression);
+    (server, ProtoCodec::new(false))
+}
+
+fn allocations(f: impl FnMut()) -> f64 {
+    let mut f = f;
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..FRAMES {
+        f();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / FRAMES as f64
+}
+
+fn bench_codec(c: &mut Criterion) {
+    for (name, msg) in [("move", move_notify()), ("players", players_notify())] {
+        for compression in [false, true] {
+            let id = format!("{}{}", name, if compression { "/lz4" } else { "" });
+            let (mut server, mut client) = codecs(compression);
+            let mut wire = BytesMut::new();
+            server.encode(Packet::notify(msg.clone()), &mut wire).unwrap();
+            let frame = wire.split().freeze();
+
+            let mut dst = BytesMut::with_capacity(frame.len() * 2);
+            let encode = allocations(|| {
+                dst.clear();
+                server.encode(Packet::notify(msg.clone()), &mut dst).unwrap();
+            });
+            //the packet clone is the caller's, not the codec's
+            let clone = allocations(|| drop(Packet::notify(msg.clone())));
+            let decode = allocations(|| {
+                let mut src = BytesMut::from(frame.as_ref());
+                client.decode(&mut src).unwrap().unwrap();
+            });
+            let copy = allocations(|| drop(BytesMut::from(frame.as_ref())));
+            println!("{} frame {} bytes, allocations per frame encode:{:.1} decode:{:.1}", id, frame.len(), encode - clone, decode - copy);
+
+            let mut group = c.benchmark_group("codec");
+            group.throughput(Throughput::Bytes(frame.len() as u64));
+            group.bench_with_input(BenchmarkId::new("encode", &id), &msg, |b, msg| {
+                b.iter(|| {
+                    dst.clear();
+                    server.encode(Packet::notify(msg.clone()), &mut dst).unwrap();
+                })
+            });
+            group.bench_with_input(BenchmarkId::new("decode", &id), &frame, |b, frame| {
+                b.iter(|| {
+                    let mut src = BytesMut::from(frame.as_ref());
+                    client.decode(&mut src).unwrap().unwrap()
+                })
+            });
+            group.finish();
+        }
+    }
+}
+
+criterion_group!(benches, bench_codec);
+criterion_main!(benches);
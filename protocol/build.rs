@@ -1,30 +1,122 @@
+use std::fmt::Write;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use schema::{check_lock, collect_message_ids, descriptor_set, DIRECTIONS, include_all_protos, PROTO_PATH, SCHEMA_LOCK, Variant};
 
 fn main() -> anyhow::Result<()> {
     let proto_bin_path = protoc_bin_vendored::protoc_bin_path()?;
-    let proto_path = "src/proto";
-    println!("cargo:rerun-if-changed={}", proto_path);
-    let all_protos = include_all_protos(proto_path)?;
+    //options.proto extends google/protobuf/descriptor.proto
+    let include_path = protoc_bin_vendored::include_path()?;
+    let proto_path = Path::new(PROTO_PATH);
+    println!("cargo:rerun-if-changed={}", PROTO_PATH);
+    println!("cargo:rerun-if-changed={}", SCHEMA_LOCK);
     protobuf_codegen::Codegen::new()
-        .protoc_path(&*proto_bin_path)
-        .includes(&[proto_path])
-        .inputs(all_protos)
+        .protoc_path(&proto_bin_path)
+        .includes([proto_path, &include_path])
+        .inputs(include_all_protos(proto_path)?)
         .cargo_out_dir("proto")
         .run_from_script();
+    let descriptor_set = descriptor_set(proto_path)?;
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    let directions = collect_message_ids(&descriptor_set)?;
+    fs::write(out_dir.join("message.rs"), generate_message_enums(&directions)?)?;
+    //the lock is only read, the source tree is never written by the build
+    if !check_lock(&PathBuf::from(std::env::var("CARGO_MANIFEST_DIR")?).join(SCHEMA_LOCK), &descriptor_set, &directions)? {
+        println!("cargo:warning={} is behind compatible protocol changes, update it with `cargo run -p schema`", SCHEMA_LOCK);
+    }
     Ok(())
 }
 
-fn include_all_protos(p: &str) -> anyhow::Result<Vec<PathBuf>> {
-    let mut all_protos = vec![];
-    let paths = fs::read_dir(p)?;
-    for p in paths {
-        let p = p?;
-        if let Some(ext) = p.path().extension() {
-            if ext == "proto" {
-                all_protos.push(p.path())
-            }
+/// generate `CsMessage`, `ScMessage` and the id tables from the messages declaring `msg_id`
+fn generate_message_enums(directions: &[Vec<Variant>]) -> anyhow::Result<String> {
+    let mut code = String::new();
+    writeln!(code, "// generated by build.rs from the msg_id and direction options, do not edit")?;
+    for ((_, _, enum_name, table), variants) in DIRECTIONS.iter().zip(directions) {
+        write_message_enum(&mut code, enum_name, variants)?;
+        writeln!(code)?;
+        writeln!(code, "/// id and descriptor of every `{}`", enum_name)?;
+        writeln!(code, "pub fn {}() -> Vec<(i32, protobuf::reflect::MessageDescriptor)> {{", table)?;
+        writeln!(code, "    vec![")?;
+        for v in variants {
+            writeln!(code, "        ({}, <{} as protobuf::MessageFull>::descriptor()),", v.id, v.rust_path)?;
         }
+        writeln!(code, "    ]")?;
+        writeln!(code, "}}")?;
+    }
+    Ok(code)
+}
+
+fn write_message_enum(code: &mut String, enum_name: &str, variants: &[Variant]) -> anyhow::Result<()> {
+    writeln!(code)?;
+    writeln!(code, "#[derive(Debug, Clone, PartialEq)]")?;
+    writeln!(code, "pub enum {} {{", enum_name)?;
+    for v in variants {
+        writeln!(code, "    {}({}),", v.name, v.rust_path)?;
+    }
+    writeln!(code, "}}")?;
+    writeln!(code)?;
+    writeln!(code, "impl crate::message::DirectionMessage for {} {{", enum_name)?;
+    writeln!(code, "    fn id(&self) -> i32 {{")?;
+    writeln!(code, "        match self {{")?;
+    for v in variants {
+        writeln!(code, "            {}::{}(_) => {},", enum_name, v.name, v.id)?;
+    }
+    writeln!(code, "        }}")?;
+    writeln!(code, "    }}")?;
+    writeln!(code)?;
+    writeln!(code, "    fn full_name(&self) -> &'static str {{")?;
+    writeln!(code, "        match self {{")?;
+    for v in variants {
+        writeln!(code, "            {}::{}(_) => \"{}\",", enum_name, v.name, v.full_name.trim_start_matches('.'))?;
     }
-    Ok(all_protos)
-}
\ No newline at end of file
+    writeln!(code, "        }}")?;
+    writeln!(code, "    }}")?;
+    writeln!(code)?;
+    writeln!(code, "    fn write_to_bytes(&self) -> protobuf::Result<Vec<u8>> {{")?;
+    writeln!(code, "        match self {{")?;
+    for v in variants {
+        writeln!(code, "            {}::{}(msg) => protobuf::Message::write_to_bytes(msg),", enum_name, v.name)?;
+    }
+    writeln!(code, "        }}")?;
+    writeln!(code, "    }}")?;
+    writeln!(code)?;
+    writeln!(code, "    fn parse_from_bytes(id: i32, bytes: &[u8]) -> anyhow::Result<Self> {{")?;
+    writeln!(code, "        let msg = match id {{")?;
+    for v in variants {
+        writeln!(code, "            {} => {}::{}(protobuf::Message::parse_from_bytes(bytes)?),", v.id, enum_name, v.name)?;
+    }
+    writeln!(code, "            _ => return Err(anyhow::anyhow!(\"id:{{}} not found in {}\", id)),", enum_name)?;
+    writeln!(code, "        }};")?;
+    writeln!(code, "        Ok(msg)")?;
+    writeln!(code, "    }}")?;
+    writeln!(code)?;
+    writeln!(code, "    fn from_dyn(msg: Box<dyn protobuf::MessageDyn>) -> anyhow::Result<Self> {{")?;
+    writeln!(code, "        let descriptor = msg.descriptor_dyn();")?;
+    writeln!(code, "        let msg = match descriptor.full_name() {{")?;
+    for v in variants {
+        writeln!(code, "            \"{}\" => {}::{}(*crate::mapper::cast(msg)?),", v.full_name.trim_start_matches('.'), enum_name, v.name)?;
+    }
+    writeln!(code, "            name => return Err(anyhow::anyhow!(\"msg:{{}} not found in {}\", name)),", enum_name)?;
+    writeln!(code, "        }};")?;
+    writeln!(code, "        Ok(msg)")?;
+    writeln!(code, "    }}")?;
+    writeln!(code)?;
+    writeln!(code, "    fn into_dyn(self) -> Box<dyn protobuf::MessageDyn> {{")?;
+    writeln!(code, "        match self {{")?;
+    for v in variants {
+        writeln!(code, "            {}::{}(msg) => Box::new(msg),", enum_name, v.name)?;
+    }
+    writeln!(code, "        }}")?;
+    writeln!(code, "    }}")?;
+    writeln!(code, "}}")?;
+    for v in variants {
+        writeln!(code)?;
+        writeln!(code, "impl From<{}> for {} {{", v.rust_path, enum_name)?;
+        writeln!(code, "    fn from(msg: {}) -> Self {{", v.rust_path)?;
+        writeln!(code, "        {}::{}(msg)", enum_name, v.name)?;
+        writeln!(code, "    }}")?;
+        writeln!(code, "}}")?;
+    }
+    Ok(())
+}
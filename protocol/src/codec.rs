@@ -1,62 +1,459 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::io;
+use std::marker::PhantomData;
+use std::ops::Not;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
 use anyhow::anyhow;
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::stream::{SplitSink, SplitStream};
-use protobuf::MessageDyn;
-use tokio_kcp::KcpStream;
+use log::{debug, warn};
+use protobuf::{CodedInputStream, MessageDyn};
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
-use crate::mapper::{CS_ID_DESC_MAP, CS_NAME_ID_MAP, SC_ID_DESC_MAP, SC_NAME_ID_MAP};
+use crate::message::{CsMessage, DirectionMessage, ScMessage};
+use crate::options::Direction;
+use crate::registry::{BUILTIN_REGISTRY, MessageRegistry};
+use crate::secure::{EncryptionMode, SecureSession};
+use crate::transport::BoxConnection;
 
-pub type MessageSink = SplitSink<Framed<KcpStream, ProtoCodec>, Box<dyn MessageDyn>>;
-pub type MessageStream = SplitStream<Framed<KcpStream, ProtoCodec>>;
+pub type MessageSink = SplitSink<Framed<BoxConnection, ProtoCodec>, Box<dyn MessageDyn>>;
+pub type PacketSink = SplitSink<Framed<BoxConnection, ProtoCodec>, Packet>;
+pub type OutboundSink = SplitSink<Framed<BoxConnection, ProtoCodec>, Outbound>;
+pub type MessageStream = SplitStream<Framed<BoxConnection, ProtoCodec>>;
+
+/// encode buffers larger than this are dropped after use instead of being kept per connection
+const ENCODE_BUF_RETAIN: usize = 64 * 1024;
+
+/// length(u32) + id(u16) + flags(u8)
+const V2_HEADER_LEN: usize = 4 + 2 + 1;
+
+/// more fragments of the same message follow this frame
+const FLAG_MORE_FRAGMENTS: u8 = 0b0000_0001;
+
+/// the body is lz4 compressed with its uncompressed size prepended, set on every fragment of the message
+const FLAG_COMPRESSED: u8 = 0b0000_0010;
+
+/// a sequence(u32) follows the flags, the message is a request or the response to the request with the same sequence
+const FLAG_SEQUENCE: u8 = 0b0000_0100;
+
+const SEQUENCE_LEN: usize = 4;
+
+/// a message with the sequence of the request it belongs to, push notifications carry none
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub seq: Option<u32>,
+    pub msg: Box<dyn MessageDyn>,
+}
+
+impl Packet {
+    pub fn notify(msg: Box<dyn MessageDyn>) -> Self {
+        Self { seq: None, msg }
+    }
+
+    /// the response to the request with the sequence, a notify if the request had none
+    pub fn reply(seq: Option<u32>, msg: Box<dyn MessageDyn>) -> Self {
+        Self { seq, msg }
+    }
+}
+
+impl From<Box<dyn MessageDyn>> for Packet {
+    fn from(msg: Box<dyn MessageDyn>) -> Self {
+        Packet::notify(msg)
+    }
+}
+
+/// a message serialized once and shared by every connection it is sent to, clones share the buffers
+#[derive(Debug, Clone)]
+pub struct EncodedMessage {
+    id: i32,
+    full_name: Arc<str>,
+    body: Bytes,
+    /// compressed by the first connection with compression enabled, None if it doesn't get smaller
+    compressed: Arc<OnceLock<Option<Bytes>>>,
+}
+
+impl EncodedMessage {
+    /// serialize with the id of the message in the direction
+    pub fn new(registry: &MessageRegistry, direction: Direction, msg: &dyn MessageDyn) -> anyhow::Result<Self> {
+        let descriptor = msg.descriptor_dyn();
+        let id = registry.id(direction, descriptor.full_name())
+            .ok_or_else(|| anyhow!("msg:{} not found in {:?}", descriptor.full_name(), direction))?;
+        let body = Bytes::from(msg.write_to_bytes_dyn()?);
+        Ok(Self { id, full_name: Arc::from(descriptor.full_name()), body, compressed: Arc::new(OnceLock::new()) })
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+}
+
+/// the item of a write task, a packet of the connection or a message encoded once for many connections
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    Packet(Packet),
+    Encoded(EncodedMessage),
+}
+
+impl From<Packet> for Outbound {
+    fn from(packet: Packet) -> Self {
+        Outbound::Packet(packet)
+    }
+}
+
+impl From<EncodedMessage> for Outbound {
+    fn from(msg: EncodedMessage) -> Self {
+        Outbound::Encoded(msg)
+    }
+}
+
+/// a decoded frame before its body is parsed
+pub struct RawMessage {
+    pub id: i32,
+    pub seq: Option<u32>,
+    pub body: Bytes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingVersion {
+    /// length(u16) + id(u16) + body, a frame can't be larger than 64KiB
+    V1,
+    /// length(u32) + id(u16) + flags(u8) + body
+    V2,
+}
+
+/// both ends of a connection must use the same framing version
+#[derive(Debug, Clone)]
+pub struct CodecConfig {
+    pub version: FramingVersion,
+    /// a larger frame is rejected, or split into fragments if fragmentation is enabled
+    pub max_frame_size: usize,
+    /// split a message larger than [CodecConfig::max_frame_size] into fragments,
+    /// only available in [FramingVersion::V2]
+    pub fragmentation: bool,
+    /// max size of a reassembled message body
+    pub max_message_size: usize,
+    pub compression: CompressionConfig,
+    /// negotiated by the hello exchange at connect time
+    pub encryption: EncryptionMode,
+    /// ids of the messages, both ends must register the same messages
+    pub registry: Arc<MessageRegistry>,
+    pub decode_errors: DecodeErrorPolicy,
+}
+
+/// what the decoder does with a frame it read but can't parse, e.g. an unknown id or a corrupt body,
+/// a broken length or record always ends the stream as the next frame can't be found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// end the stream with [ProtoCodecError::MalformedFrame]
+    #[default]
+    Close,
+    /// drop the frame and decode the next one
+    Skip,
+    /// drop frames until `max_violations` were dropped, then end the stream with [ProtoCodecError::TooManyViolations]
+    Ban { max_violations: u32 },
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        Self {
+            version: FramingVersion::V2,
+            max_frame_size: 64 * 1024,
+            fragmentation: true,
+            max_message_size: 4 * 1024 * 1024,
+            compression: CompressionConfig::default(),
+            encryption: EncryptionMode::Optional,
+            registry: BUILTIN_REGISTRY.clone(),
+            decode_errors: DecodeErrorPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// compress when the peer asks for it at login, only available in [FramingVersion::V2]
+    pub enabled: bool,
+    /// smaller bodies are sent as is
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 256,
+        }
+    }
+}
+
+/// bytes written by the encoder for one message type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ByteCounter {
+    pub messages: u64,
+    /// encoded protobuf bodies
+    pub raw_bytes: u64,
+    /// bodies as they went out, after compression
+    pub wire_bytes: u64,
+}
+
+impl ByteCounter {
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            1.
+        } else {
+            self.wire_bytes as f64 / self.raw_bytes as f64
+        }
+    }
+}
+
+struct CompressionState {
+    allowed: bool,
+    enabled: AtomicBool,
+    counters: Mutex<HashMap<String, ByteCounter>>,
+}
+
+/// compression switch and byte counters of a connection, shared between the codec and the connection owner,
+/// the encoder only compresses after [Compression::negotiate] enabled it, the decoder follows the frame flag
+#[derive(Clone)]
+pub struct Compression(Arc<CompressionState>);
+
+impl Compression {
+    fn new(config: &CodecConfig) -> Self {
+        Compression(Arc::new(CompressionState {
+            allowed: config.compression.enabled && config.version == FramingVersion::V2,
+            enabled: AtomicBool::new(false),
+            counters: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// enable compression if the peer requested it and the config allows it, returns whether it is enabled
+    pub fn negotiate(&self, requested: bool) -> bool {
+        let enabled = requested && self.0.allowed;
+        self.0.enabled.store(enabled, Ordering::Relaxed);
+        enabled
+    }
+
+    pub fn is_allowed(&self) -> bool {
+        self.0.allowed
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.enabled.load(Ordering::Relaxed)
+    }
+
+    /// counters by message name, sorted by the name
+    pub fn counters(&self) -> Vec<(String, ByteCounter)> {
+        let counters = self.0.counters.lock().unwrap();
+        let mut counters: Vec<(String, ByteCounter)> = counters.iter().map(|(name, counter)| (name.clone(), *counter)).collect();
+        counters.sort_by(|a, b| a.0.cmp(&b.0));
+        counters
+    }
+
+    fn record(&self, msg_name: &str, raw_bytes: usize, wire_bytes: usize) {
+        let mut counters = self.0.counters.lock().unwrap();
+        if counters.contains_key(msg_name).not() {
+            counters.insert(msg_name.to_string(), ByteCounter::default());
+        }
+        let counter = counters.get_mut(msg_name).unwrap();
+        counter.messages += 1;
+        counter.raw_bytes += raw_bytes as u64;
+        counter.wire_bytes += wire_bytes as u64;
+    }
+}
 
 pub struct ProtoCodec {
     pub is_server: bool,
+    pub config: CodecConfig,
+    compression: Compression,
+    /// id and body of the message being reassembled from fragments
+    fragments: Option<(i32, BytesMut)>,
+    secure: Option<SecureSession>,
+    /// decrypted bytes not decoded into a message yet
+    plain: BytesMut,
+    /// malformed frames dropped by [DecodeErrorPolicy]
+    violations: u32,
+    /// encode buffers reused across messages, see [ENCODE_BUF_RETAIN]
+    body_buf: Vec<u8>,
+    compress_buf: Vec<u8>,
+    frame_buf: BytesMut,
 }
 
 impl ProtoCodec {
     pub fn new(is_server: bool) -> Self {
-        Self { is_server }
+        Self::with_config(is_server, CodecConfig::default())
     }
 
-    pub fn parse_proto(&self, id: i32, msg_bytes: Vec<u8>) -> anyhow::Result<Box<dyn MessageDyn>> {
-        let descriptor = if self.is_server {
-            CS_ID_DESC_MAP
-                .get(&id)
-                .ok_or(anyhow!("id:{} not found in sc", id))?
-        } else {
-            SC_ID_DESC_MAP
-                .get(&id)
-                .ok_or(anyhow!("id:{} not found in cs", id))?
+    pub fn with_config(is_server: bool, config: CodecConfig) -> Self {
+        let compression = Compression::new(&config);
+        Self {
+            is_server,
+            config,
+            compression,
+            fragments: None,
+            secure: None,
+            plain: BytesMut::new(),
+            violations: 0,
+            body_buf: Vec::new(),
+            compress_buf: Vec::new(),
+            frame_buf: BytesMut::new(),
+        }
+    }
+
+    pub fn config(&self) -> &CodecConfig {
+        &self.config
+    }
+
+    /// frames are sealed in records of the session from now on
+    pub fn set_secure(&mut self, session: SecureSession) {
+        self.secure = Some(session);
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression.clone()
+    }
+
+    pub fn violations(&self) -> u32 {
+        self.violations
+    }
+
+    /// apply [CodecConfig::decode_errors] to a decode error, Ok if the frame is dropped and decoding goes on
+    fn skip_malformed(&mut self, err: ProtoCodecError) -> Result<(), ProtoCodecError> {
+        let ProtoCodecError::MalformedFrame(..) = err else {
+            return Err(err);
         };
-        let msg = descriptor.parse_from_bytes(&*msg_bytes)?;
+        match self.config.decode_errors {
+            DecodeErrorPolicy::Close => Err(err),
+            DecodeErrorPolicy::Skip => {
+                self.violations = self.violations.saturating_add(1);
+                //a peer sending garbage on purpose shouldn't flood the log
+                if self.violations.is_power_of_two() {
+                    warn!("{}, frame dropped, {} dropped so far", err, self.violations);
+                } else {
+                    debug!("{}, frame dropped", err);
+                }
+                Ok(())
+            }
+            DecodeErrorPolicy::Ban { max_violations } => {
+                self.violations = self.violations.saturating_add(1);
+                if self.violations >= max_violations {
+                    return Err(ProtoCodecError::TooManyViolations(self.violations));
+                }
+                warn!("{}, frame dropped, {} of {} violations", err, self.violations, max_violations);
+                Ok(())
+            }
+        }
+    }
+
+    /// the direction of the messages this end receives
+    fn inbound(&self) -> Direction {
+        if self.is_server { Direction::CS } else { Direction::SC }
+    }
+
+    fn outbound(&self) -> Direction {
+        if self.is_server { Direction::SC } else { Direction::CS }
+    }
+
+    /// parse the body of a decoded frame, the body is read in place without copying it
+    pub fn parse_proto(&self, id: i32, msg_bytes: &Bytes) -> anyhow::Result<Box<dyn MessageDyn>> {
+        let direction = self.inbound();
+        let descriptor = self.config.registry
+            .descriptor(direction, id)
+            .ok_or_else(|| anyhow!("id:{} not found in {:?}", id, direction))?;
+        let mut is = CodedInputStream::from_tokio_bytes(msg_bytes);
+        let msg = descriptor.parse_from(&mut is)?;
+        is.check_eof()?;
         Ok(msg)
     }
 
-    pub fn get_proto_id(&self, msg: &Box<dyn MessageDyn>) -> anyhow::Result<i32> {
+    pub fn get_proto_id(&self, msg: &dyn MessageDyn) -> anyhow::Result<i32> {
         let desc = msg.descriptor_dyn();
-        let msg_name = desc.name();
-        let id = if self.is_server {
-            SC_NAME_ID_MAP
-                .get(msg_name)
-                .ok_or(anyhow!("msg:{} not found in sc", msg_name))?
-        } else {
-            CS_NAME_ID_MAP
-                .get(msg_name)
-                .ok_or(anyhow!("msg:{} not found in cs", msg_name))?
-        };
-        Ok(*id)
+        let direction = self.outbound();
+        let id = self.config.registry
+            .id(direction, desc.full_name())
+            .ok_or_else(|| anyhow!("msg:{} not found in {:?}", desc.full_name(), direction))?;
+        Ok(id)
     }
 }
 
-impl Decoder for ProtoCodec {
-    type Item = Box<dyn MessageDyn>;
-    type Error = ProtoCodecError;
+impl ProtoCodec {
+    /// the next message decrypted, reassembled and decompressed
+    pub fn decode_raw(&mut self, src: &mut BytesMut) -> Result<Option<RawMessage>, ProtoCodecError> {
+        if self.secure.is_none() {
+            return self.decode_frame(src);
+        }
+        loop {
+            let mut plain = std::mem::take(&mut self.plain);
+            let msg = self.decode_frame(&mut plain);
+            self.plain = plain;
+            if let Some(msg) = msg? {
+                return Ok(Some(msg));
+            }
+            let max_frame_size = self.config.max_frame_size;
+            match self.secure.as_mut().unwrap().open(src, max_frame_size)? {
+                //the record is split from src in front of what is left of it, joining is free when plain is drained
+                Some(plain) => self.plain.unsplit(plain),
+                None => return Ok(None),
+            }
+        }
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    /// frame an encoded message body, `full_name` is the protobuf name the byte counters are kept by
+    pub fn encode_raw(&mut self, id: i32, seq: Option<u32>, full_name: &str, body: &[u8], dst: &mut BytesMut) -> Result<(), ProtoCodecError> {
+        let mut compress_buf = std::mem::take(&mut self.compress_buf);
+        let compressed = self.should_compress(body.len()) && compress_into(body, &mut compress_buf);
+        let result = self.encode_body(id, seq, full_name, body, compressed.then_some(compress_buf.as_slice()), dst);
+        self.compress_buf = recycle(compress_buf);
+        result
+    }
+
+    fn should_compress(&self, body_len: usize) -> bool {
+        self.compression.is_enabled() && body_len >= self.config.compression.threshold
+    }
+
+    /// frame the body, or its compressed form if there is one
+    fn encode_body(&mut self, id: i32, seq: Option<u32>, full_name: &str, body: &[u8], compressed: Option<&[u8]>, dst: &mut BytesMut) -> Result<(), ProtoCodecError> {
+        let (flags, wire_body) = match compressed {
+            Some(compressed) => (FLAG_COMPRESSED, compressed),
+            None => (0, body),
+        };
+        self.compression.record(full_name, body.len(), wire_body.len());
+        if self.secure.is_none() {
+            return self.encode_frame(id, flags, seq, wire_body, dst);
+        }
+        let mut frames = std::mem::take(&mut self.frame_buf);
+        frames.clear();
+        let result = self.encode_frame(id, flags, seq, wire_body, &mut frames).and_then(|_| {
+            let secure = self.secure.as_mut().unwrap();
+            frames.chunks(self.config.max_frame_size).try_for_each(|chunk| secure.seal(chunk, dst))
+        });
+        if frames.capacity() <= ENCODE_BUF_RETAIN {
+            self.frame_buf = frames;
+        }
+        result
+    }
+
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<RawMessage>, ProtoCodecError> {
+        match self.config.version {
+            FramingVersion::V1 => self.decode_v1(src),
+            FramingVersion::V2 => self.decode_v2(src),
+        }
+    }
+
+    fn encode_frame(&self, id: i32, flags: u8, seq: Option<u32>, body: &[u8], dst: &mut BytesMut) -> Result<(), ProtoCodecError> {
+        match self.config.version {
+            FramingVersion::V1 if seq.is_some() => Err(anyhow!("sequence is only available in framing v2").into()),
+            FramingVersion::V1 => self.encode_v1(id, body, dst),
+            FramingVersion::V2 => self.encode_v2(id, flags, seq, body, dst),
+        }
+    }
+
+    fn decode_v1(&mut self, src: &mut BytesMut) -> Result<Option<RawMessage>, ProtoCodecError> {
         let buf_len = src.len();
         if buf_len < 2 {
             return Ok(None);
@@ -64,19 +461,166 @@ impl Decoder for ProtoCodec {
         let mut package_len_bytes = [0u8; 2];
         package_len_bytes.copy_from_slice(&src[..2]);
         let package_len = u16::from_be_bytes(package_len_bytes) as usize;
-        return if buf_len < package_len {
+        //a length shorter than the header would slice out of the frame
+        if package_len < 4 {
+            return Err(anyhow!("invalid frame length {}", package_len).into());
+        }
+        if package_len > self.config.max_frame_size {
+            return Err(ProtoCodecError::FrameTooLarge(package_len, self.config.max_frame_size));
+        }
+        if buf_len < package_len {
             src.reserve(package_len - buf_len);
             Ok(None)
         } else {
-            let src = src.split_to(package_len);
+            let mut src = src.split_to(package_len);
             let mut id_bytes = [0u8; 2];
             id_bytes.copy_from_slice(&src[2..4]);
             let id = u16::from_be_bytes(id_bytes) as i32;
-            let mut msg_bytes = vec![0u8; package_len - 4];
-            msg_bytes.copy_from_slice(&src[4..package_len]);
-            let msg = self.parse_proto(id, msg_bytes)?;
-            Ok(Some(msg))
+            src.advance(4);
+            Ok(Some(RawMessage { id, seq: None, body: src.freeze() }))
+        }
+    }
+
+    fn decode_v2(&mut self, src: &mut BytesMut) -> Result<Option<RawMessage>, ProtoCodecError> {
+        loop {
+            let buf_len = src.len();
+            if buf_len < 4 {
+                return Ok(None);
+            }
+            let mut package_len_bytes = [0u8; 4];
+            package_len_bytes.copy_from_slice(&src[..4]);
+            let package_len = u32::from_be_bytes(package_len_bytes) as usize;
+            if package_len < V2_HEADER_LEN {
+                return Err(anyhow!("invalid frame length {}", package_len).into());
+            }
+            if package_len > self.config.max_frame_size {
+                return Err(ProtoCodecError::FrameTooLarge(package_len, self.config.max_frame_size));
+            }
+            //a message reassembled from fragments is limited before the frame is buffered as well
+            let buffered = self.fragments.as_ref().map(|(_, body)| body.len()).unwrap_or_default();
+            if buffered + package_len - V2_HEADER_LEN > self.config.max_message_size {
+                return Err(ProtoCodecError::FrameTooLarge(buffered + package_len - V2_HEADER_LEN, self.config.max_message_size));
+            }
+            if buf_len < package_len {
+                src.reserve(package_len - buf_len);
+                return Ok(None);
+            }
+            let mut frame = src.split_to(package_len);
+            frame.advance(4);
+            let id = frame.get_u16() as i32;
+            let flags = frame.get_u8();
+            let seq = if flags & FLAG_SEQUENCE != 0 {
+                if frame.len() < SEQUENCE_LEN {
+                    return Err(ProtoCodecError::malformed(id, "frame too short for the sequence"));
+                }
+                Some(frame.get_u32())
+            } else {
+                None
+            };
+            let body = match self.fragments.take() {
+                Some((fragment_id, mut body)) => {
+                    if fragment_id != id {
+                        return Err(ProtoCodecError::malformed(id, format!("fragment interleaved with id:{}", fragment_id)));
+                    }
+                    body.unsplit(frame);
+                    body
+                }
+                None => frame,
+            };
+            if body.len() > self.config.max_message_size {
+                return Err(ProtoCodecError::FrameTooLarge(body.len(), self.config.max_message_size));
+            }
+            if flags & FLAG_MORE_FRAGMENTS != 0 {
+                self.fragments = Some((id, body));
+                continue;
+            }
+            let body = if flags & FLAG_COMPRESSED != 0 {
+                Bytes::from(self.decompress(&body).map_err(|e| ProtoCodecError::malformed(id, e))?)
+            } else {
+                body.freeze()
+            };
+            return Ok(Some(RawMessage { id, seq, body }));
+        }
+    }
+
+    fn encode_v1(&self, id: i32, body: &[u8], dst: &mut BytesMut) -> Result<(), ProtoCodecError> {
+        let package_len = 2 + 2 + body.len();
+        if package_len > self.config.max_frame_size {
+            return Err(ProtoCodecError::FrameTooLarge(package_len, self.config.max_frame_size));
+        }
+        dst.put_u16(u16::try_from(package_len)?);
+        dst.put_u16(u16::try_from(id)?);
+        dst.put_slice(body);
+        Ok(())
+    }
+
+    fn decompress(&self, body: &[u8]) -> Result<Vec<u8>, ProtoCodecError> {
+        //check the prepended size before lz4 allocates for it
+        if body.len() < 4 {
+            return Err(anyhow!("compressed body too short").into());
+        }
+        let mut size_bytes = [0u8; 4];
+        size_bytes.copy_from_slice(&body[..4]);
+        let size = u32::from_le_bytes(size_bytes) as usize;
+        if size > self.config.max_message_size {
+            return Err(ProtoCodecError::FrameTooLarge(size, self.config.max_message_size));
+        }
+        let body = lz4_flex::decompress_size_prepended(body).map_err(|e| anyhow!("decompress body err {}", e))?;
+        Ok(body)
+    }
+
+    fn encode_v2(&self, id: i32, flags: u8, seq: Option<u32>, body: &[u8], dst: &mut BytesMut) -> Result<(), ProtoCodecError> {
+        let id = u16::try_from(id)?;
+        let (flags, header_len) = match seq {
+            Some(_) => (flags | FLAG_SEQUENCE, V2_HEADER_LEN + SEQUENCE_LEN),
+            None => (flags, V2_HEADER_LEN),
         };
+        let max_body_len = self.config.max_frame_size.saturating_sub(header_len);
+        if body.len() > max_body_len && !self.config.fragmentation {
+            return Err(ProtoCodecError::FrameTooLarge(header_len + body.len(), self.config.max_frame_size));
+        }
+        if body.len() > self.config.max_message_size {
+            return Err(ProtoCodecError::FrameTooLarge(body.len(), self.config.max_message_size));
+        }
+        if max_body_len == 0 {
+            return Err(anyhow!("max frame size {} can't hold a frame header", self.config.max_frame_size).into());
+        }
+        //an empty body still needs one frame
+        let fragments = body.len().div_ceil(max_body_len).max(1);
+        dst.reserve(fragments * header_len + body.len());
+        let mut chunks = body.chunks(max_body_len);
+        for index in 0..fragments {
+            let chunk = chunks.next().unwrap_or_default();
+            let flags = if index + 1 < fragments { flags | FLAG_MORE_FRAGMENTS } else { flags };
+            dst.put_u32(u32::try_from(header_len + chunk.len())?);
+            dst.put_u16(id);
+            dst.put_u8(flags);
+            if let Some(seq) = seq {
+                dst.put_u32(seq);
+            }
+            dst.put_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for ProtoCodec {
+    type Item = Packet;
+    type Error = ProtoCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let packet = self.decode_raw(src).and_then(|raw| match raw {
+                Some(raw) => self.parse_proto(raw.id, &raw.body)
+                    .map(|msg| Some(Packet { seq: raw.seq, msg }))
+                    .map_err(|e| ProtoCodecError::malformed(raw.id, e)),
+                None => Ok(None),
+            });
+            match packet {
+                Ok(packet) => return Ok(packet),
+                Err(err) => self.skip_malformed(err)?,
+            }
+        }
     }
 }
 
@@ -84,13 +628,127 @@ impl Encoder<Box<dyn MessageDyn>> for ProtoCodec {
     type Error = ProtoCodecError;
 
     fn encode(&mut self, msg: Box<dyn MessageDyn>, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let id = self.get_proto_id(&msg)?;
-        let body = msg.write_to_bytes_dyn()?;
-        let package_len = 2 + 2 + body.len();
-        dst.put_u16(u16::try_from(package_len)?);
-        dst.put_u16(u16::try_from(id)?);
-        dst.put_slice(body.as_slice());
-        Ok(())
+        self.encode(Packet::notify(msg), dst)
+    }
+}
+
+impl Encoder<Packet> for ProtoCodec {
+    type Error = ProtoCodecError;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let id = self.get_proto_id(packet.msg.as_ref())?;
+        let mut body = std::mem::take(&mut self.body_buf);
+        body.clear();
+        let result = match packet.msg.write_to_vec_dyn(&mut body) {
+            Ok(()) => self.encode_raw(id, packet.seq, packet.msg.descriptor_dyn().full_name(), &body, dst),
+            Err(e) => Err(e.into()),
+        };
+        self.body_buf = recycle(body);
+        result
+    }
+}
+
+impl Encoder<EncodedMessage> for ProtoCodec {
+    type Error = ProtoCodecError;
+
+    fn encode(&mut self, msg: EncodedMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let compressed = if self.should_compress(msg.body.len()) {
+            msg.compressed.get_or_init(|| compress(&msg.body).map(Bytes::from)).as_deref()
+        } else {
+            None
+        };
+        self.encode_body(msg.id, None, &msg.full_name, &msg.body, compressed, dst)
+    }
+}
+
+impl Encoder<Outbound> for ProtoCodec {
+    type Error = ProtoCodecError;
+
+    fn encode(&mut self, item: Outbound, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            Outbound::Packet(packet) => self.encode(packet, dst),
+            Outbound::Encoded(msg) => self.encode(msg, dst),
+        }
+    }
+}
+
+/// lz4 with the size prepended, None if it doesn't get smaller
+fn compress(body: &[u8]) -> Option<Vec<u8>> {
+    let mut compressed = Vec::new();
+    compress_into(body, &mut compressed).then_some(compressed)
+}
+
+/// [compress] into a reused buffer, false if it doesn't get smaller
+fn compress_into(body: &[u8], dst: &mut Vec<u8>) -> bool {
+    dst.clear();
+    dst.resize(4 + lz4_flex::block::get_maximum_output_size(body.len()), 0);
+    dst[..4].copy_from_slice(&(body.len() as u32).to_le_bytes());
+    match lz4_flex::block::compress_into(body, &mut dst[4..]) {
+        Ok(len) => {
+            dst.truncate(4 + len);
+            dst.len() < body.len()
+        }
+        Err(_) => false,
+    }
+}
+
+/// keep an encode buffer for the next message unless a large message grew it
+fn recycle(buf: Vec<u8>) -> Vec<u8> {
+    if buf.capacity() > ENCODE_BUF_RETAIN { Vec::new() } else { buf }
+}
+
+/// the same framing as [ProtoCodec] with the generated message enums instead of `Box<dyn MessageDyn>`,
+/// the direction is given by the types, `I` is decoded and `O` is encoded, sequences are not exposed
+pub struct TypedCodec<I, O> {
+    inner: ProtoCodec,
+    _marker: PhantomData<fn(O) -> I>,
+}
+
+pub type ServerCodec = TypedCodec<CsMessage, ScMessage>;
+
+pub type ClientCodec = TypedCodec<ScMessage, CsMessage>;
+
+impl<I, O> TypedCodec<I, O> {
+    pub fn new(config: CodecConfig) -> Self {
+        //is_server only picks the direction of the dyn messages which are not used here
+        Self { inner: ProtoCodec::with_config(false, config), _marker: PhantomData }
+    }
+
+    pub fn set_secure(&mut self, session: SecureSession) {
+        self.inner.set_secure(session);
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.inner.compression()
+    }
+}
+
+impl<I: DirectionMessage, O> Decoder for TypedCodec<I, O> {
+    type Item = I;
+    type Error = ProtoCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let msg = self.inner.decode_raw(src).and_then(|raw| match raw {
+                Some(raw) => I::parse_from_bytes(raw.id, &raw.body)
+                    .map(Some)
+                    .map_err(|e| ProtoCodecError::malformed(raw.id, e)),
+                None => Ok(None),
+            });
+            match msg {
+                Ok(msg) => return Ok(msg),
+                Err(err) => self.inner.skip_malformed(err)?,
+            }
+        }
+    }
+}
+
+impl<I, O: DirectionMessage> Encoder<O> for TypedCodec<I, O> {
+    type Error = ProtoCodecError;
+
+    fn encode(&mut self, msg: O, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let body = msg.write_to_bytes()?;
+        self.inner.encode_raw(msg.id(), None, msg.full_name(), &body, dst)
     }
 }
 
@@ -100,6 +758,18 @@ pub enum ProtoCodecError {
     Anyhow(anyhow::Error),
     Io(io::Error),
     TryFromInt(std::num::TryFromIntError),
+    /// size and the limit it exceeds
+    FrameTooLarge(usize, usize),
+    /// a whole frame was read but its body can't be parsed, the stream is still in sync after it
+    MalformedFrame(i32, String),
+    /// malformed frames dropped before [DecodeErrorPolicy::Ban] gave up on the peer
+    TooManyViolations(u32),
+}
+
+impl ProtoCodecError {
+    pub fn malformed(id: i32, reason: impl Display) -> Self {
+        ProtoCodecError::MalformedFrame(id, reason.to_string())
+    }
 }
 
 impl Display for ProtoCodecError {
@@ -117,6 +787,15 @@ impl Display for ProtoCodecError {
             ProtoCodecError::TryFromInt(e) => {
                 write!(f, "{}", e)
             }
+            ProtoCodecError::FrameTooLarge(size, max) => {
+                write!(f, "frame size {} exceeds the limit {}", size, max)
+            }
+            ProtoCodecError::MalformedFrame(id, reason) => {
+                write!(f, "malformed frame of id:{} {}", id, reason)
+            }
+            ProtoCodecError::TooManyViolations(violations) => {
+                write!(f, "{} malformed frames, too many violations", violations)
+            }
         }
     }
 }
@@ -146,3 +825,238 @@ impl From<std::num::TryFromIntError> for ProtoCodecError {
 }
 
 impl std::error::Error for ProtoCodecError {}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use protobuf::{MessageDyn, MessageFull};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::codec::{ClientCodec, CodecConfig, DecodeErrorPolicy, EncodedMessage, FLAG_MORE_FRAGMENTS, FramingVersion, Outbound, ProtoCodec, ProtoCodecError, ServerCodec, V2_HEADER_LEN};
+    use crate::message::{CsMessage, DirectionMessage, ScMessage};
+    use crate::mapper::cast;
+    use crate::options::Direction;
+    use crate::registry::BUILTIN_REGISTRY;
+    use crate::test::{LoginReq, LoginResp, PlayerState, SCOtherPlayersStateNotify};
+    use crate::test::scother_players_state_notify::Bundle;
+
+    fn large_notify() -> Box<dyn MessageDyn> {
+        let mut notify = SCOtherPlayersStateNotify::new();
+        for id in 0..5000 {
+            let mut state = PlayerState::new();
+            state.x = id as f32;
+            state.y = id as f32;
+            let mut bundle = Bundle::new();
+            bundle.player_id = id;
+            bundle.state = Some(state).into();
+            notify.players.push(bundle);
+        }
+        Box::new(notify)
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let config = CodecConfig { max_frame_size: 1024, ..Default::default() };
+        let mut server = ProtoCodec::with_config(true, config.clone());
+        let mut client = ProtoCodec::with_config(false, config);
+        let mut buf = BytesMut::new();
+        server.encode(large_notify(), &mut buf).unwrap();
+        assert!(buf.len() > 64 * 1024);
+        //feed the frames in small pieces like a stream would
+        let mut src = BytesMut::new();
+        let mut decoded = None;
+        while !buf.is_empty() {
+            src.extend_from_slice(&buf.split_to(buf.len().min(333)));
+            if let Some(msg) = client.decode(&mut src).unwrap() {
+                decoded = Some(msg);
+            }
+        }
+        let notify = cast::<SCOtherPlayersStateNotify>(decoded.unwrap().msg).unwrap();
+        assert_eq!(notify.players.len(), 5000);
+        assert_eq!(notify.players[4999].state.x, 4999.);
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut server = ProtoCodec::new(true);
+        let mut client = ProtoCodec::new(false);
+        let compression = server.compression();
+        let mut uncompressed = BytesMut::new();
+        server.encode(large_notify(), &mut uncompressed).unwrap();
+        assert!(compression.negotiate(true));
+        let mut compressed = BytesMut::new();
+        server.encode(large_notify(), &mut compressed).unwrap();
+        assert!(compressed.len() < uncompressed.len());
+
+        let notify = cast::<SCOtherPlayersStateNotify>(client.decode(&mut compressed).unwrap().unwrap().msg).unwrap();
+        assert_eq!(notify.players.len(), 5000);
+        let counters = compression.counters();
+        assert_eq!(counters.len(), 1);
+        let (name, counter) = &counters[0];
+        assert_eq!(name, SCOtherPlayersStateNotify::descriptor().full_name());
+        assert_eq!(counter.messages, 2);
+        assert!(counter.ratio() < 1.);
+
+        let v1 = ProtoCodec::with_config(true, CodecConfig { version: FramingVersion::V1, ..Default::default() });
+        assert!(!v1.compression().negotiate(true));
+    }
+
+    #[test]
+    fn test_encoded_message() {
+        let encoded = EncodedMessage::new(&BUILTIN_REGISTRY, Direction::SC, large_notify().as_ref()).unwrap();
+        for compression in [false, true] {
+            let mut server = ProtoCodec::new(true);
+            server.compression().negotiate(compression);
+            let mut expected = BytesMut::new();
+            server.encode(large_notify(), &mut expected).unwrap();
+            let mut buf = BytesMut::new();
+            server.encode(Outbound::from(encoded.clone()), &mut buf).unwrap();
+            assert_eq!(buf, expected);
+        }
+        //the compressed body is shared by the clones
+        assert!(encoded.compressed.get().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_typed_codec() {
+        let mut server = ServerCodec::new(CodecConfig::default());
+        let mut client = ClientCodec::new(CodecConfig::default());
+        let mut login = LoginReq::new();
+        login.player_id = 233;
+        let mut buf = BytesMut::new();
+        client.encode(CsMessage::from(login.clone()), &mut buf).unwrap();
+        match server.decode(&mut buf).unwrap().unwrap() {
+            CsMessage::LoginReq(req) => assert_eq!(req, login),
+            other => panic!("unexpected {:?}", other),
+        }
+        //the typed codec and the dyn codec put the same bytes on the wire
+        let mut dyn_client = ProtoCodec::new(false);
+        dyn_client.encode(Box::new(login.clone()) as Box<dyn MessageDyn>, &mut buf).unwrap();
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(CsMessage::LoginReq(login)));
+        assert_eq!(ScMessage::from(LoginResp::new()).id(), 2);
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let config = CodecConfig { fragmentation: false, ..Default::default() };
+        let mut server = ProtoCodec::with_config(true, config.clone());
+        let result = server.encode(large_notify(), &mut BytesMut::new());
+        assert!(matches!(result, Err(ProtoCodecError::FrameTooLarge(_, _))));
+
+        let mut v1 = ProtoCodec::with_config(true, CodecConfig { version: FramingVersion::V1, ..config });
+        let result = v1.encode(large_notify(), &mut BytesMut::new());
+        assert!(matches!(result, Err(ProtoCodecError::FrameTooLarge(_, _))));
+
+        let mut client = ProtoCodec::with_config(false, CodecConfig { max_frame_size: 16, ..Default::default() });
+        let mut src = BytesMut::from(&[0u8, 0, 1, 0, 0, 1, 0][..]);
+        assert!(matches!(client.decode(&mut src), Err(ProtoCodecError::FrameTooLarge(256, 16))));
+
+        let mut v1 = ProtoCodec::with_config(false, CodecConfig { version: FramingVersion::V1, ..Default::default() });
+        let mut src = BytesMut::from(&[0u8, 2, 0, 1][..]);
+        assert!(v1.decode(&mut src).is_err());
+
+        //the second fragment is rejected by its header before its body arrives
+        let mut client = ProtoCodec::with_config(false, CodecConfig { max_message_size: 15, ..Default::default() });
+        let mut src = BytesMut::new();
+        src.put_slice(&[0, 0, 0, 17, 0, 1, FLAG_MORE_FRAGMENTS]);
+        src.put_slice(&[0; 10]);
+        src.put_slice(&[0, 0, 0, 17, 0, 1, 0]);
+        assert!(matches!(client.decode(&mut src), Err(ProtoCodecError::FrameTooLarge(20, 15))));
+    }
+
+    fn login_frame(client: &mut ProtoCodec, player_id: i32, dst: &mut BytesMut) {
+        let mut login = LoginReq::new();
+        login.player_id = player_id;
+        client.encode(Box::new(login) as Box<dyn MessageDyn>, dst).unwrap();
+    }
+
+    /// a whole v2 frame of an id no message is registered with
+    fn unknown_frame(body: &[u8], dst: &mut BytesMut) {
+        dst.put_u32((V2_HEADER_LEN + body.len()) as u32);
+        dst.put_u16(u16::MAX);
+        dst.put_u8(0);
+        dst.put_slice(body);
+    }
+
+    fn decode_all(server: &mut ProtoCodec, src: &mut BytesMut) -> (Vec<i32>, Option<ProtoCodecError>) {
+        let mut player_ids = vec![];
+        loop {
+            match server.decode(src) {
+                Ok(Some(packet)) => player_ids.push(cast::<LoginReq>(packet.msg).unwrap().player_id),
+                Ok(None) => return (player_ids, None),
+                Err(err) => return (player_ids, Some(err)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_error_policy() {
+        let mut client = ProtoCodec::new(false);
+        let mut wire = BytesMut::new();
+        login_frame(&mut client, 1, &mut wire);
+        unknown_frame(b"", &mut wire);
+        login_frame(&mut client, 2, &mut wire);
+        unknown_frame(&[0xff; 8], &mut wire);
+        login_frame(&mut client, 3, &mut wire);
+
+        let mut server = ProtoCodec::new(true);
+        let (player_ids, err) = decode_all(&mut server, &mut wire.clone());
+        assert_eq!(player_ids, vec![1]);
+        assert!(matches!(err, Some(ProtoCodecError::MalformedFrame(65535, _))), "{:?}", err);
+
+        let mut server = ProtoCodec::with_config(true, CodecConfig { decode_errors: DecodeErrorPolicy::Skip, ..Default::default() });
+        assert_eq!(decode_all(&mut server, &mut wire.clone()).0, vec![1, 2, 3]);
+        assert_eq!(server.violations(), 2);
+
+        let policy = DecodeErrorPolicy::Ban { max_violations: 2 };
+        let mut server = ProtoCodec::with_config(true, CodecConfig { decode_errors: policy, ..Default::default() });
+        let (player_ids, err) = decode_all(&mut server, &mut wire.clone());
+        assert_eq!(player_ids, vec![1, 2]);
+        assert!(matches!(err, Some(ProtoCodecError::TooManyViolations(2))), "{:?}", err);
+
+        //a broken length can't be skipped
+        let mut server = ProtoCodec::with_config(true, CodecConfig { decode_errors: DecodeErrorPolicy::Skip, ..Default::default() });
+        let mut src = BytesMut::from(&[0u8, 0, 0, 1][..]);
+        assert!(server.decode(&mut src).is_err());
+    }
+
+    proptest! {
+        /// arbitrary input decodes into messages, an error or a wait for more bytes, never a panic,
+        /// the input arrives in two reads split at `split`
+        #[test]
+        fn fuzz_decode(bytes in vec(any::<u8>(), 0..1024), split in any::<usize>(), v1 in any::<bool>(), compression in any::<bool>()) {
+            let version = if v1 { FramingVersion::V1 } else { FramingVersion::V2 };
+            let config = CodecConfig { version, max_message_size: 4096, decode_errors: DecodeErrorPolicy::Skip, ..Default::default() };
+            let mut server = ProtoCodec::with_config(true, config);
+            server.compression().negotiate(compression);
+            let split = split % (bytes.len() + 1);
+            let mut src = BytesMut::new();
+            for chunk in [&bytes[..split], &bytes[split..]] {
+                src.extend_from_slice(chunk);
+                if decode_all(&mut server, &mut src).1.is_some() {
+                    break;
+                }
+            }
+        }
+
+        /// with [DecodeErrorPolicy::Skip] every valid frame is decoded whatever garbage frames are between them
+        #[test]
+        fn fuzz_skip_malformed(frames in vec((any::<Option<i32>>(), vec(any::<u8>(), 0..64)), 0..32)) {
+            let mut client = ProtoCodec::new(false);
+            let mut wire = BytesMut::new();
+            for (player_id, garbage) in &frames {
+                match player_id {
+                    Some(player_id) => login_frame(&mut client, *player_id, &mut wire),
+                    None => unknown_frame(garbage, &mut wire),
+                }
+            }
+            let mut server = ProtoCodec::with_config(true, CodecConfig { decode_errors: DecodeErrorPolicy::Skip, ..Default::default() });
+            let (player_ids, err) = decode_all(&mut server, &mut wire);
+            prop_assert!(err.is_none());
+            prop_assert_eq!(player_ids, frames.iter().filter_map(|(player_id, _)| *player_id).collect::<Vec<_>>());
+            prop_assert_eq!(server.violations() as usize, frames.iter().filter(|(player_id, _)| player_id.is_none()).count());
+        }
+    }
+}
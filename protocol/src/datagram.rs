@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::ops::Not;
+
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use protobuf::MessageDyn;
+use protobuf::reflect::ReflectValueRef;
+
+use crate::codec::Outbound;
+use crate::options::Direction;
+use crate::registry::MessageRegistry;
+use crate::test::DatagramChannel;
+
+/// token(u64) + sequence(u32) + id(u16)
+pub const DATAGRAM_HEADER_LEN: usize = 8 + 4 + 2;
+
+const TAG_LEN: usize = 16;
+
+/// a larger datagram risks ip fragmentation, the message goes over the stream instead
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// sent by the client to tell the server its address, no message has id 0
+pub const BIND_ID: u16 = 0;
+
+/// the session of a datagram channel, issued by the server in LoginResp over the stream
+#[derive(Clone)]
+pub struct DatagramKey {
+    /// finds the session of a datagram on the server, sent in clear
+    pub token: u64,
+    pub key: [u8; 32],
+}
+
+impl DatagramKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self { token: OsRng.next_u64(), key }
+    }
+
+    pub fn from_proto(channel: &DatagramChannel) -> anyhow::Result<Self> {
+        let key = channel.key.as_slice().try_into().map_err(|_| anyhow!("invalid datagram key length {}", channel.key.len()))?;
+        Ok(Self { token: channel.token, key })
+    }
+
+    pub fn to_proto(&self, addr: String) -> DatagramChannel {
+        let mut channel = DatagramChannel::new();
+        channel.addr = addr;
+        channel.token = self.token;
+        channel.key = self.key.to_vec();
+        channel
+    }
+
+    /// both directions share the key, the first byte of the nonce keeps their nonces apart
+    pub fn split(&self, is_server: bool) -> (DatagramSealer, DatagramOpener) {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let (seal_direction, open_direction) = if is_server { (1, 0) } else { (0, 1) };
+        let sealer = DatagramSealer { cipher: cipher.clone(), token: self.token, direction: seal_direction, sequence: 0 };
+        let opener = DatagramOpener { cipher, token: self.token, direction: open_direction, last: HashMap::new(), stale: 0 };
+        (sealer, opener)
+    }
+}
+
+/// the token of a datagram, None if it is too short to be one
+pub fn token(datagram: &[u8]) -> Option<u64> {
+    let token = datagram.get(..8)?;
+    Some(u64::from_be_bytes(token.try_into().ok()?))
+}
+
+fn nonce(direction: u8, sequence: u32) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[0] = direction;
+    nonce[8..].copy_from_slice(&sequence.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+/// the sending half of a datagram channel, every datagram gets the next sequence
+pub struct DatagramSealer {
+    cipher: ChaCha20Poly1305,
+    token: u64,
+    direction: u8,
+    sequence: u32,
+}
+
+impl DatagramSealer {
+    /// seal the message body into one datagram, the header is authenticated but not encrypted
+    pub fn seal(&mut self, id: u16, body: &[u8], dst: &mut BytesMut) -> anyhow::Result<()> {
+        let datagram_len = DATAGRAM_HEADER_LEN + body.len() + TAG_LEN;
+        if datagram_len > MAX_DATAGRAM_SIZE {
+            return Err(anyhow!("datagram of msg {} too large {}", id, datagram_len));
+        }
+        //the sequence is the nonce, the channel ends instead of wrapping
+        self.sequence = self.sequence.checked_add(1).ok_or_else(|| anyhow!("datagram sequence exhausted"))?;
+        dst.reserve(datagram_len);
+        let start = dst.len();
+        dst.put_u64(self.token);
+        dst.put_u32(self.sequence);
+        dst.put_u16(id);
+        let body_start = dst.len();
+        dst.put_slice(body);
+        let (header, body) = dst[start..].split_at_mut(body_start - start);
+        let tag = self.cipher
+            .encrypt_in_place_detached(&nonce(self.direction, self.sequence), header, body)
+            .map_err(|_| anyhow!("seal datagram failed"))?;
+        dst.put_slice(&tag);
+        Ok(())
+    }
+
+    /// the id and body of an outbound message to send as a datagram, None if it must go over the stream,
+    /// replies and messages not declaring `unreliable` always do
+    pub fn unreliable_body(registry: &MessageRegistry, direction: Direction, outbound: &Outbound) -> anyhow::Result<Option<(u16, Bytes)>> {
+        match outbound {
+            Outbound::Encoded(encoded) if registry.is_unreliable(direction, encoded.id()) => {
+                Ok(Some((encoded.id() as u16, encoded.body().clone())))
+            }
+            Outbound::Packet(packet) if packet.seq.is_none() => Self::unreliable_msg(registry, direction, packet.msg.as_ref()),
+            _ => Ok(None),
+        }
+    }
+
+    /// the id and body of a notify to send as a datagram, None if it doesn't declare `unreliable`
+    pub fn unreliable_msg(registry: &MessageRegistry, direction: Direction, msg: &dyn MessageDyn) -> anyhow::Result<Option<(u16, Bytes)>> {
+        match registry.id(direction, msg.descriptor_dyn().full_name()) {
+            Some(id) if registry.is_unreliable(direction, id) => Ok(Some((id as u16, Bytes::from(msg.write_to_bytes_dyn()?)))),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// the receiving half of a datagram channel, only a datagram newer than every one opened before about the same
+/// thing is accepted, messages of different ids or about different players are ordered apart
+pub struct DatagramOpener {
+    cipher: ChaCha20Poly1305,
+    token: u64,
+    direction: u8,
+    /// the last sequence of every id and `player_id` field
+    last: HashMap<(u16, i32), u32>,
+    stale: u64,
+}
+
+/// what an accepted datagram carries
+pub enum Opened {
+    /// the client told its address
+    Bind,
+    Message(Box<dyn MessageDyn>),
+}
+
+impl DatagramOpener {
+    /// authenticate and parse the datagram with the ids of the direction, None if a newer one about the same
+    /// player was opened first, a datagram failing authentication is an error and leaves the sequences alone
+    pub fn open(&mut self, registry: &MessageRegistry, direction: Direction, mut datagram: BytesMut) -> anyhow::Result<Option<Opened>> {
+        if datagram.len() < DATAGRAM_HEADER_LEN + TAG_LEN {
+            return Err(anyhow!("datagram too short {}", datagram.len()));
+        }
+        let mut header = datagram.split_to(DATAGRAM_HEADER_LEN);
+        let token = header.get_u64();
+        let sequence = header.get_u32();
+        let id = header.get_u16();
+        if token != self.token {
+            return Err(anyhow!("datagram token mismatch"));
+        }
+        let body_len = datagram.len() - TAG_LEN;
+        let tag = Tag::clone_from_slice(&datagram[body_len..]);
+        datagram.truncate(body_len);
+        let mut aad = [0u8; DATAGRAM_HEADER_LEN];
+        aad[..8].copy_from_slice(&token.to_be_bytes());
+        aad[8..12].copy_from_slice(&sequence.to_be_bytes());
+        aad[12..].copy_from_slice(&id.to_be_bytes());
+        self.cipher
+            .decrypt_in_place_detached(&nonce(self.direction, sequence), &aad, &mut datagram, &tag)
+            .map_err(|_| anyhow!("datagram {} authentication failed", sequence))?;
+        let opened = match id {
+            BIND_ID => Opened::Bind,
+            id => Opened::Message(Self::parse(registry, direction, id, &datagram)?),
+        };
+        let player_id = match &opened {
+            Opened::Message(msg) => player_id(msg.as_ref()),
+            Opened::Bind => 0,
+        };
+        let last = self.last.entry((id, player_id)).or_default();
+        //sequences start at 1, 0 is never opened
+        if sequence <= *last {
+            self.stale += 1;
+            return Ok(None);
+        }
+        *last = sequence;
+        Ok(Some(opened))
+    }
+
+    /// datagrams dropped because a newer one was opened first
+    pub fn stale(&self) -> u64 {
+        self.stale
+    }
+
+    fn parse(registry: &MessageRegistry, direction: Direction, id: u16, body: &[u8]) -> anyhow::Result<Box<dyn MessageDyn>> {
+        let descriptor = registry.descriptor(direction, id as i32)
+            .ok_or_else(|| anyhow!("datagram msg id {} not found in {:?}", id, direction))?;
+        if registry.is_unreliable(direction, id as i32).not() {
+            return Err(anyhow!("msg:{} can't be sent as a datagram", descriptor.full_name()));
+        }
+        Ok(descriptor.parse_from_bytes(body)?)
+    }
+}
+
+/// the int32 `player_id` field of the message, 0 if it has none
+fn player_id(msg: &dyn MessageDyn) -> i32 {
+    let Some(field) = msg.descriptor_dyn().field_by_name("player_id") else {
+        return 0;
+    };
+    match field.get_singular_field_or_default(msg) {
+        ReflectValueRef::I32(player_id) => player_id,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use protobuf::{Message, MessageField};
+
+    use crate::codec::Packet;
+    use crate::datagram::{DatagramKey, DatagramOpener, DatagramSealer, Opened, token};
+    use crate::options::Direction;
+    use crate::registry::BUILTIN_REGISTRY;
+    use crate::test::{LoginReq, PlayerMoveNotify, PlayerState, SCPlayerMoveNotify};
+
+    #[test]
+    fn test_datagram() {
+        let key = DatagramKey::generate();
+        let key = DatagramKey::from_proto(&key.to_proto("127.0.0.1:4897".to_string())).unwrap();
+        let (mut client_sealer, _) = key.split(false);
+        let (_, mut server_opener) = key.split(true);
+
+        let mut notify = PlayerMoveNotify::new();
+        let mut state = PlayerState::new();
+        state.x = 1.;
+        notify.state = MessageField::some(state);
+        let outbound = Packet::notify(Box::new(notify.clone())).into();
+        let (id, body) = DatagramSealer::unreliable_body(&BUILTIN_REGISTRY, Direction::CS, &outbound).unwrap().unwrap();
+        let login = Packet::notify(Box::new(LoginReq::new())).into();
+        assert!(DatagramSealer::unreliable_body(&BUILTIN_REGISTRY, Direction::CS, &login).unwrap().is_none());
+
+        let mut first = BytesMut::new();
+        client_sealer.seal(id, &body, &mut first).unwrap();
+        let mut second = BytesMut::new();
+        client_sealer.seal(id, &body, &mut second).unwrap();
+        assert_eq!(token(&first), Some(key.token));
+
+        //the newer one arrives first, the older one is stale
+        let Some(Opened::Message(msg)) = server_opener.open(&BUILTIN_REGISTRY, Direction::CS, second.clone()).unwrap() else {
+            panic!("expect a message");
+        };
+        assert_eq!(msg.write_to_bytes_dyn().unwrap(), notify.write_to_bytes().unwrap());
+        assert!(server_opener.open(&BUILTIN_REGISTRY, Direction::CS, first).unwrap().is_none());
+        assert!(server_opener.open(&BUILTIN_REGISTRY, Direction::CS, second.clone()).unwrap().is_none());
+        assert_eq!(server_opener.stale(), 2);
+
+        //a forged header fails authentication, so does a datagram sealed for the other direction
+        let mut forged = BytesMut::new();
+        client_sealer.seal(id, &body, &mut forged).unwrap();
+        forged[13] ^= 1;
+        assert!(server_opener.open(&BUILTIN_REGISTRY, Direction::CS, forged).is_err());
+        let (mut server_sealer, _) = key.split(true);
+        let mut reflected = BytesMut::new();
+        for _ in 0..3 {
+            reflected.clear();
+            server_sealer.seal(id, &body, &mut reflected).unwrap();
+        }
+        assert!(server_opener.open(&BUILTIN_REGISTRY, Direction::CS, reflected).is_err());
+        assert!(DatagramOpener::parse(&BUILTIN_REGISTRY, Direction::CS, 2, &[]).is_err());
+    }
+
+    #[test]
+    fn test_datagram_players_apart() {
+        let key = DatagramKey::generate();
+        let (mut server_sealer, _) = key.split(true);
+        let (_, mut client_opener) = key.split(false);
+        let seal = |sealer: &mut DatagramSealer, player_id: i32| {
+            let mut notify = SCPlayerMoveNotify::new();
+            notify.player_id = player_id;
+            let (id, body) = DatagramSealer::unreliable_msg(&BUILTIN_REGISTRY, Direction::SC, &notify).unwrap().unwrap();
+            let mut datagram = BytesMut::new();
+            sealer.seal(id, &body, &mut datagram).unwrap();
+            datagram
+        };
+        let (a_old, a, b) = (seal(&mut server_sealer, 1), seal(&mut server_sealer, 1), seal(&mut server_sealer, 2));
+        let opened_player = |opened: Option<Opened>| match opened {
+            Some(Opened::Message(msg)) => msg.downcast_box::<SCPlayerMoveNotify>().unwrap().player_id,
+            _ => panic!("expect a message"),
+        };
+        assert_eq!(opened_player(client_opener.open(&BUILTIN_REGISTRY, Direction::SC, b).unwrap()), 2);
+        //still the latest of player 1 after a newer datagram about player 2
+        assert_eq!(opened_player(client_opener.open(&BUILTIN_REGISTRY, Direction::SC, a.clone()).unwrap()), 1);
+        assert!(client_opener.open(&BUILTIN_REGISTRY, Direction::SC, a_old).unwrap().is_none());
+        assert!(client_opener.open(&BUILTIN_REGISTRY, Direction::SC, a).unwrap().is_none());
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::net::ToSocketAddrs;
+use tokio_kcp::{KcpConfig, KcpListener, KcpNoDelayConfig};
+
+use crate::transport::{Accepted, BoxConnection, Listener, PeerAddr};
+
+/// pings waiting for their ack, older ones are forgotten
+const MAX_PENDING_PINGS: usize = 8;
+
+/// named kcp tunings, both ends of a connection should use the same one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KcpProfile {
+    /// resend early and ignore congestion, every lost segment costs bandwidth instead of latency
+    #[default]
+    LowLatency,
+    /// fast resend with congestion control, for links shared with other traffic
+    Balanced,
+    /// the plain kcp defaults with small messages packed into full segments, for mobile data plans
+    BandwidthSaver,
+    Custom(KcpSettings),
+}
+
+impl KcpProfile {
+    pub fn settings(&self) -> KcpSettings {
+        let default = KcpSettings::default();
+        match self {
+            KcpProfile::LowLatency => default,
+            KcpProfile::Balanced => KcpSettings { interval: 20, no_congestion_control: false, ..default },
+            KcpProfile::BandwidthSaver => KcpSettings {
+                send_window: 128,
+                recv_window: 128,
+                nodelay: false,
+                interval: 40,
+                resend: 0,
+                no_congestion_control: false,
+                stream: true,
+                ..default
+            },
+            KcpProfile::Custom(settings) => *settings,
+        }
+    }
+}
+
+impl FromStr for KcpProfile {
+    type Err = anyhow::Error;
+
+    /// a named profile, optionally followed by `key=value` overrides which make it a custom one,
+    /// e.g. `balanced,mtu=1200,send_window=512`, the overrides alone start from the default profile
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',').map(str::trim).peekable();
+        let profile = match parts.peek() {
+            Some(part) if part.contains('=') => KcpProfile::default(),
+            _ => match parts.next().unwrap_or_default() {
+                "low-latency" => KcpProfile::LowLatency,
+                "balanced" => KcpProfile::Balanced,
+                "bandwidth-saver" => KcpProfile::BandwidthSaver,
+                name => return Err(anyhow!("unknown kcp profile {}, expect low-latency, balanced or bandwidth-saver", name)),
+            }
+        };
+        if parts.peek().is_none() {
+            return Ok(profile);
+        }
+        let mut settings = profile.settings();
+        for part in parts {
+            let (key, value) = part.split_once('=').ok_or_else(|| anyhow!("kcp setting {} is not key=value", part))?;
+            settings.set(key, value)?;
+        }
+        Ok(KcpProfile::Custom(settings))
+    }
+}
+
+/// every knob of a kcp session, the default is [KcpProfile::LowLatency]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KcpSettings {
+    /// udp payload size of a segment, keep it below the path mtu
+    pub mtu: usize,
+    /// segments in flight without an ack
+    pub send_window: u16,
+    /// segments buffered for reordering, also announced to the peer as its send limit
+    pub recv_window: u16,
+    /// a shorter minimum rto and a gentler backoff
+    pub nodelay: bool,
+    /// milliseconds between two updates of the session
+    pub interval: i32,
+    /// resend a segment after it was skipped by this many acks, 0 waits for the rto
+    pub resend: i32,
+    pub no_congestion_control: bool,
+    /// merge writes into full segments, messages lose their boundaries which the codec doesn't need
+    pub stream: bool,
+    /// send on every write instead of the next update
+    pub flush_write: bool,
+    /// ack on every input instead of the next update
+    pub flush_acks_input: bool,
+    /// a session without any packet for this long is closed
+    pub session_expire: Duration,
+}
+
+impl Default for KcpSettings {
+    fn default() -> Self {
+        let config = KcpConfig::default();
+        let nodelay = KcpNoDelayConfig::fastest();
+        Self {
+            mtu: config.mtu,
+            send_window: config.wnd_size.0,
+            recv_window: config.wnd_size.1,
+            nodelay: nodelay.nodelay,
+            interval: nodelay.interval,
+            resend: nodelay.resend,
+            no_congestion_control: nodelay.nc,
+            stream: false,
+            flush_write: false,
+            flush_acks_input: false,
+            session_expire: config.session_expire,
+        }
+    }
+}
+
+impl KcpSettings {
+    /// set a field by its name, durations are in milliseconds
+    pub fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        fn parse<T: FromStr>(key: &str, value: &str) -> anyhow::Result<T> where T::Err: Display {
+            value.parse().map_err(|err| anyhow!("invalid kcp setting {}={}: {}", key, value, err))
+        }
+        match key {
+            "mtu" => self.mtu = parse(key, value)?,
+            "send_window" => self.send_window = parse(key, value)?,
+            "recv_window" => self.recv_window = parse(key, value)?,
+            "nodelay" => self.nodelay = parse(key, value)?,
+            "interval" => self.interval = parse(key, value)?,
+            "resend" => self.resend = parse(key, value)?,
+            "no_congestion_control" => self.no_congestion_control = parse(key, value)?,
+            "stream" => self.stream = parse(key, value)?,
+            "flush_write" => self.flush_write = parse(key, value)?,
+            "flush_acks_input" => self.flush_acks_input = parse(key, value)?,
+            "session_expire" => self.session_expire = Duration::from_millis(parse(key, value)?),
+            _ => return Err(anyhow!("unknown kcp setting {}", key)),
+        }
+        Ok(())
+    }
+
+    pub fn to_config(&self) -> KcpConfig {
+        KcpConfig {
+            mtu: self.mtu,
+            nodelay: KcpNoDelayConfig {
+                nodelay: self.nodelay,
+                interval: self.interval,
+                resend: self.resend,
+                nc: self.no_congestion_control,
+            },
+            wnd_size: (self.send_window, self.recv_window),
+            session_expire: self.session_expire,
+            flush_write: self.flush_write,
+            flush_acks_input: self.flush_acks_input,
+            stream: self.stream,
+            ..Default::default()
+        }
+    }
+}
+
+impl Display for KcpSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mtu={} wnd={}/{} nodelay={} interval={}ms resend={} nc={} stream={} flush_write={} flush_acks={} expire={:?}",
+               self.mtu, self.send_window, self.recv_window, self.nodelay, self.interval, self.resend,
+               self.no_congestion_control, self.stream, self.flush_write, self.flush_acks_input, self.session_expire)
+    }
+}
+
+/// the round trip of a kcp connection measured with pings over the stream, tokio_kcp keeps the state
+/// of its sessions private so it is measured above kcp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KcpStats {
+    /// smoothed round trip time
+    pub rtt: Duration,
+    pub rtt_var: Duration,
+    /// `rtt + 4 * rtt_var` like kcp computes its own
+    pub rto: Duration,
+    pub pings: u32,
+    /// pings acked later than the rto, on the reliable stream most likely because a segment was resent
+    pub retransmits: u32,
+}
+
+impl KcpStats {
+    fn sample(last: Option<KcpStats>, rtt: Duration) -> Self {
+        let Some(last) = last else {
+            return Self { rtt, rtt_var: rtt / 2, rto: rtt * 3, pings: 1, retransmits: 0 };
+        };
+        let rtt_var = (last.rtt_var * 3 + last.rtt.abs_diff(rtt)) / 4;
+        let srtt = (last.rtt * 7 + rtt) / 8;
+        Self {
+            rtt: srtt,
+            rtt_var,
+            rto: srtt + rtt_var * 4,
+            pings: last.pings.saturating_add(1),
+            retransmits: last.retransmits.saturating_add((rtt > last.rto) as u32),
+        }
+    }
+}
+
+/// the pings of one connection, the owner of the connection sends them and hands back the acks
+#[derive(Debug, Default)]
+pub struct KcpProbe {
+    state: Mutex<ProbeState>,
+}
+
+#[derive(Debug, Default)]
+struct ProbeState {
+    sequence: u32,
+    pending: VecDeque<(u32, Instant)>,
+    stats: Option<KcpStats>,
+}
+
+impl KcpProbe {
+    /// the sequence of the next ping
+    pub fn ping(&self) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        state.sequence = state.sequence.wrapping_add(1);
+        let sequence = state.sequence;
+        if state.pending.len() >= MAX_PENDING_PINGS {
+            state.pending.pop_front();
+        }
+        state.pending.push_back((sequence, Instant::now()));
+        sequence
+    }
+
+    /// a sequence never sent or already forgotten is ignored
+    pub fn ack(&self, sequence: u32) {
+        let mut state = self.state.lock().unwrap();
+        let Some(index) = state.pending.iter().position(|(pending, _)| *pending == sequence) else {
+            return;
+        };
+        //the stream keeps the order, the pings before it won't be acked any more
+        let (_, sent) = state.pending[index];
+        state.pending.drain(..=index);
+        state.stats = Some(KcpStats::sample(state.stats, sent.elapsed()));
+    }
+
+    /// None before the first ack
+    pub fn stats(&self) -> Option<KcpStats> {
+        self.state.lock().unwrap().stats
+    }
+}
+
+/// the effective settings of a kcp listener and the stats of its open connections
+#[derive(Debug, Clone)]
+pub struct KcpDiagnostics {
+    settings: KcpSettings,
+    connections: Arc<Mutex<HashMap<SocketAddr, Weak<KcpProbe>>>>,
+}
+
+impl KcpDiagnostics {
+    pub fn settings(&self) -> &KcpSettings {
+        &self.settings
+    }
+
+    /// the probe of an accepted connection, it is forgotten once the returned one is dropped
+    pub fn probe(&self, addr: SocketAddr) -> Arc<KcpProbe> {
+        let probe = Arc::new(KcpProbe::default());
+        self.connections.lock().unwrap().insert(addr, Arc::downgrade(&probe));
+        probe
+    }
+
+    /// the connections with at least one acked ping, sorted by address, closed ones are forgotten
+    pub fn connections(&self) -> Vec<(SocketAddr, KcpStats)> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, probe| probe.strong_count() > 0);
+        let mut stats: Vec<_> = connections.iter()
+            .filter_map(|(addr, probe)| Some((*addr, probe.upgrade()?.stats()?)))
+            .collect();
+        stats.sort_by_key(|(addr, _)| *addr);
+        stats
+    }
+}
+
+/// a kcp listener telling its settings and connections in [KcpDiagnostics]
+pub struct KcpServer {
+    listener: KcpListener,
+    diagnostics: KcpDiagnostics,
+}
+
+impl KcpServer {
+    pub async fn bind<A: ToSocketAddrs>(settings: KcpSettings, addr: A) -> io::Result<Self> {
+        let listener = KcpListener::bind(settings.to_config(), addr).await.map_err(io::Error::from)?;
+        let diagnostics = KcpDiagnostics { settings, connections: Arc::new(Mutex::new(HashMap::new())) };
+        Ok(Self { listener, diagnostics })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Listener for KcpServer {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Accepted>> {
+        async move {
+            let (stream, addr) = self.listener.accept().await.map_err(io::Error::from)?;
+            Ok((Box::new(stream) as BoxConnection, PeerAddr::Socket(addr)))
+        }.boxed()
+    }
+
+    fn kcp_diagnostics(&self) -> Option<KcpDiagnostics> {
+        Some(self.diagnostics.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_kcp::KcpStream;
+
+    use crate::kcp::{KcpProfile, KcpServer, KcpSettings, KcpStats};
+    use crate::transport::{Listener, PeerAddr};
+
+    #[test]
+    fn test_kcp_profiles() {
+        let low_latency = KcpProfile::LowLatency.settings().to_config();
+        assert!(low_latency.nodelay.nodelay && low_latency.nodelay.nc);
+        assert_eq!((low_latency.nodelay.interval, low_latency.nodelay.resend), (10, 2));
+        let saver = KcpProfile::BandwidthSaver.settings().to_config();
+        assert!(saver.stream && !saver.nodelay.nc);
+        assert_eq!(saver.wnd_size, (128, 128));
+        assert_eq!("balanced".parse::<KcpProfile>().unwrap(), KcpProfile::Balanced);
+        assert!("fastest".parse::<KcpProfile>().is_err());
+        let custom = KcpSettings { mtu: 1200, send_window: 512, ..Default::default() };
+        let config = KcpProfile::Custom(custom).settings().to_config();
+        assert_eq!((config.mtu, config.wnd_size), (1200, (512, 256)));
+        assert_eq!("mtu=1200, send_window=512".parse::<KcpProfile>().unwrap(), KcpProfile::Custom(custom));
+        let custom = "bandwidth-saver,interval=20,session_expire=5000".parse::<KcpProfile>().unwrap().settings();
+        assert_eq!((custom.interval, custom.session_expire, custom.stream), (20, Duration::from_secs(5), true));
+        assert!("balanced,mtu".parse::<KcpProfile>().is_err());
+        assert!("balanced,window=512".parse::<KcpProfile>().is_err());
+        assert!("balanced,stream=maybe".parse::<KcpProfile>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kcp_diagnostics() {
+        let settings = KcpProfile::Balanced.settings();
+        let mut server = KcpServer::bind(settings, "127.0.0.1:0").await.unwrap();
+        let diagnostics = server.kcp_diagnostics().unwrap();
+        assert_eq!(diagnostics.settings(), &settings);
+        let mut client = KcpStream::connect(&settings.to_config(), server.local_addr().unwrap()).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let (mut connection, addr) = tokio::time::timeout(Duration::from_secs(1), server.accept()).await.unwrap().unwrap();
+        let mut buf = [0u8; 4];
+        connection.read_exact(&mut buf).await.unwrap();
+        connection.write_all(b"pong").await.unwrap();
+        connection.flush().await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+        let PeerAddr::Socket(addr) = addr else {
+            panic!("kcp peer without socket address");
+        };
+
+        let probe = diagnostics.probe(addr);
+        assert!(diagnostics.connections().is_empty());
+        let lost = probe.ping();
+        let sequence = probe.ping();
+        probe.ack(sequence);
+        probe.ack(lost);
+        let connections = diagnostics.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].0, addr);
+        assert_eq!((connections[0].1.pings, connections[0].1.retransmits), (1, 0));
+
+        drop(probe);
+        assert!(diagnostics.connections().is_empty());
+    }
+
+    #[test]
+    fn test_kcp_stats() {
+        let ms = Duration::from_millis;
+        let stats = KcpStats::sample(None, ms(40));
+        assert_eq!((stats.rtt, stats.rtt_var, stats.rto), (ms(40), ms(20), ms(120)));
+        let stats = KcpStats::sample(Some(stats), ms(40));
+        assert_eq!((stats.rtt, stats.rtt_var, stats.rto, stats.retransmits), (ms(40), ms(15), ms(100), 0));
+        //waited for a resend
+        let stats = KcpStats::sample(Some(stats), ms(200));
+        assert_eq!((stats.pings, stats.retransmits), (3, 1));
+        assert!(stats.rto > ms(100));
+    }
+}
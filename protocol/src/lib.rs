@@ -1,4 +1,15 @@
 include!(concat!(env!("OUT_DIR"), "/proto/mod.rs"));
 
 pub mod mapper;
+pub mod message;
 pub mod codec;
+pub mod transport;
+pub mod kcp;
+pub mod websocket;
+pub mod secure;
+pub mod registry;
+pub mod router;
+pub mod rpc;
+pub mod movement;
+pub mod datagram;
+pub mod snapshot;
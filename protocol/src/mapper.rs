@@ -1,52 +1,67 @@
 use std::any::Any;
-use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 
 use anyhow::anyhow;
 use lazy_static::lazy_static;
-use protobuf::{MessageDyn, MessageFull};
-use protobuf::reflect::{FieldDescriptor, MessageDescriptor, RuntimeType};
-use tokio_kcp::KcpConfig;
+use protobuf::MessageDyn;
+use protobuf::reflect::MessageDescriptor;
+use sha2::{Digest, Sha256};
 
-use crate::cs_msg::CS_MSG;
-use crate::sc_msg::SC_MSG;
+use crate::registry::{BUILTIN_REGISTRY, MessageRegistry};
 
 lazy_static! {
-    pub static ref CS_ID_DESC_MAP: HashMap<i32, MessageDescriptor> = id_to_descriptor(CS_MSG::descriptor()).unwrap() ;
-    pub static ref CS_NAME_ID_MAP: HashMap<String, i32> = name_to_id(CS_MSG::descriptor()).unwrap() ;
-    pub static ref SC_ID_DESC_MAP: HashMap<i32, MessageDescriptor> = id_to_descriptor(SC_MSG::descriptor()).unwrap() ;
-    pub static ref SC_NAME_ID_MAP: HashMap<String, i32> = name_to_id(SC_MSG::descriptor()).unwrap() ;
+    pub static ref SCHEMA_FINGERPRINT: [u8; 8] = BUILTIN_REGISTRY.fingerprint();
 }
 
-pub fn name_to_id(descriptor: MessageDescriptor) -> anyhow::Result<HashMap<String, i32>> {
-    let mut m = HashMap::new();
-    for field in descriptor.fields() {
-        let id = field.number();
-        let message_descriptor = get_msg_descriptor_form_msg_field(field)?;
-        m.insert(message_descriptor.name().to_string(), id);
+/// bump when the framing or the meaning of messages changes in a way the fingerprint can't see
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// what both sides must agree on before any message is exchanged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub version: u16,
+    pub fingerprint: [u8; 8],
+}
+
+impl ProtocolVersion {
+    pub fn current() -> Self {
+        Self { version: PROTOCOL_VERSION, fingerprint: *SCHEMA_FINGERPRINT }
+    }
+
+    /// the version of a connection using the messages of the registry
+    pub fn of(registry: &MessageRegistry) -> Self {
+        Self { version: PROTOCOL_VERSION, fingerprint: registry.fingerprint() }
     }
-    Ok(m)
 }
 
-pub fn id_to_descriptor(
-    descriptor: MessageDescriptor,
-) -> anyhow::Result<HashMap<i32, MessageDescriptor>> {
-    let mut m = HashMap::new();
-    for field in descriptor.fields() {
-        let id = field.number();
-        let message_descriptor = get_msg_descriptor_form_msg_field(field)?;
-        m.insert(id, message_descriptor);
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}/", self.version)?;
+        for byte in self.fingerprint {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
     }
-    Ok(m)
 }
 
-pub fn get_msg_descriptor_form_msg_field(
-    field: FieldDescriptor,
-) -> anyhow::Result<MessageDescriptor> {
-    let rt = field.singular_runtime_type();
-    match rt {
-        RuntimeType::Message(message) => Ok(message),
-        _ => Err(anyhow!("{} only support message field", field.name())),
+/// hash of the id to message type mapping of each direction, a build that adds, removes or renumbers
+/// a message gets another fingerprint
+pub fn schema_fingerprint(directions: &[(&str, Vec<(i32, MessageDescriptor)>)]) -> [u8; 8] {
+    let mut hasher = Sha256::new();
+    for (direction, descriptors) in directions {
+        hasher.update(direction.as_bytes());
+        hasher.update([0]);
+        let mut descriptors = descriptors.clone();
+        descriptors.sort_by_key(|(id, _)| *id);
+        for (id, descriptor) in descriptors {
+            hasher.update(id.to_be_bytes());
+            hasher.update(descriptor.full_name().as_bytes());
+            hasher.update([0]);
+        }
     }
+    let mut fingerprint = [0u8; 8];
+    fingerprint.copy_from_slice(&hasher.finalize()[..8]);
+    fingerprint
 }
 
 pub fn cast<T: Any>(msg: Box<dyn MessageDyn>) -> anyhow::Result<Box<T>> {
@@ -55,12 +70,3 @@ pub fn cast<T: Any>(msg: Box<dyn MessageDyn>) -> anyhow::Result<Box<T>> {
         .map_err(|m| anyhow!("cast message:{} failed", m))?;
     Ok(msg)
 }
-
-pub fn kcp_config() -> KcpConfig {
-    let mut cfg = tokio_kcp::KcpConfig::default();
-    // cfg.flush_write = true;
-    // cfg.flush_acks_input = true;
-    // cfg.stream = true;
-    cfg.nodelay = tokio_kcp::KcpNoDelayConfig::fastest();
-    cfg
-}
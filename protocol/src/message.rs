@@ -0,0 +1,19 @@
+use protobuf::MessageDyn;
+
+include!(concat!(env!("OUT_DIR"), "/message.rs"));
+
+/// a message enum generated for a direction, one variant per message declaring it, the id is its `msg_id` option
+pub trait DirectionMessage: Sized + Send + 'static {
+    fn id(&self) -> i32;
+
+    fn full_name(&self) -> &'static str;
+
+    fn write_to_bytes(&self) -> protobuf::Result<Vec<u8>>;
+
+    fn parse_from_bytes(id: i32, bytes: &[u8]) -> anyhow::Result<Self>;
+
+    /// fails if the message doesn't declare the direction of the enum
+    fn from_dyn(msg: Box<dyn MessageDyn>) -> anyhow::Result<Self>;
+
+    fn into_dyn(self) -> Box<dyn MessageDyn>;
+}
@@ -0,0 +1,321 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::anyhow;
+
+use crate::test::{LoginReq, LoginResp, MoveEncoding, PlayerState, SCPlayerMoveDelta};
+
+/// positions and speeds are sent in 1/16 unit
+pub const POSITION_SCALE: f32 = 16.;
+
+/// a turn of 360 degrees is sent in 1024 steps
+pub const ROTATION_STEPS: u32 = 1024;
+
+/// observer relative positions are relative to the corner of the observer's cell of this size in units
+pub const ANCHOR_CELL_SIZE: i32 = 64;
+
+/// sent states kept per player waiting for an ack, and received states kept per player to be a baseline
+const MAX_HISTORY: usize = 32;
+
+/// the movement encoding of a connection, asked in LoginReq and answered in LoginResp
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MoveSettings {
+    pub encoding: MoveEncoding,
+    /// only used by [MoveEncoding::MOVE_ENCODING_QUANTIZED]
+    pub observer_relative: bool,
+}
+
+impl MoveSettings {
+    pub fn quantized(observer_relative: bool) -> Self {
+        Self { encoding: MoveEncoding::MOVE_ENCODING_QUANTIZED, observer_relative }
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        self.encoding == MoveEncoding::MOVE_ENCODING_QUANTIZED
+    }
+
+    pub fn snapshot() -> Self {
+        Self { encoding: MoveEncoding::MOVE_ENCODING_SNAPSHOT, observer_relative: false }
+    }
+
+    /// moves come in [crate::snapshot] instead of a message per move
+    pub fn is_snapshot(&self) -> bool {
+        self.encoding == MoveEncoding::MOVE_ENCODING_SNAPSHOT
+    }
+
+    pub fn requested(req: &LoginReq) -> Self {
+        Self { encoding: req.move_encoding.enum_value_or_default(), observer_relative: req.observer_relative }
+    }
+
+    pub fn accepted(resp: &LoginResp) -> Self {
+        Self { encoding: resp.move_encoding.enum_value_or_default(), observer_relative: resp.observer_relative }
+    }
+
+    pub fn write_req(&self, req: &mut LoginReq) {
+        req.move_encoding = self.encoding.into();
+        req.observer_relative = self.observer_relative;
+    }
+
+    pub fn write_resp(&self, resp: &mut LoginResp) {
+        resp.move_encoding = self.encoding.into();
+        resp.observer_relative = self.observer_relative;
+    }
+}
+
+/// [PlayerState] in fixed point, two states are the same on both ends if they quantize the same
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct QuantizedState {
+    pub x: i32,
+    pub y: i32,
+    pub rotation: u32,
+    pub speed: i32,
+}
+
+impl QuantizedState {
+    pub fn quantize(state: &PlayerState) -> Self {
+        let rotation = (state.rotation.rem_euclid(360.) / 360. * ROTATION_STEPS as f32).round() as u32;
+        Self {
+            x: (state.x * POSITION_SCALE).round() as i32,
+            y: (state.y * POSITION_SCALE).round() as i32,
+            rotation: rotation % ROTATION_STEPS,
+            speed: (state.speed * POSITION_SCALE).round() as i32,
+        }
+    }
+
+    pub fn to_state(self) -> PlayerState {
+        let mut state = PlayerState::new();
+        state.x = self.x as f32 / POSITION_SCALE;
+        state.y = self.y as f32 / POSITION_SCALE;
+        state.rotation = self.rotation as f32 * 360. / ROTATION_STEPS as f32;
+        state.speed = self.speed as f32 / POSITION_SCALE;
+        state
+    }
+
+    /// the cell of the position in [ANCHOR_CELL_SIZE]
+    fn anchor(&self) -> (i32, i32) {
+        let cell = ANCHOR_CELL_SIZE * POSITION_SCALE as i32;
+        (self.x.div_euclid(cell), self.y.div_euclid(cell))
+    }
+}
+
+/// the offset of an anchor cell in quantized units
+fn anchor_offset(anchor: i32) -> i32 {
+    anchor.saturating_mul(ANCHOR_CELL_SIZE * POSITION_SCALE as i32)
+}
+
+/// a state of a player sent to or received by an observer
+#[derive(Debug, Clone, Copy)]
+struct Sent {
+    sequence: u32,
+    state: QuantizedState,
+    /// the cell of the observer the positions were relative to
+    anchor: (i32, i32),
+}
+
+#[derive(Debug, Default)]
+struct Baselines {
+    acked: Option<Sent>,
+    sent: VecDeque<Sent>,
+}
+
+/// the server side of [MoveEncoding::MOVE_ENCODING_QUANTIZED] for one observer, a delta only carries the fields
+/// which differ from the last state of the same player the observer acked
+#[derive(Debug, Default)]
+pub struct MoveDeltaEncoder {
+    observer_relative: bool,
+    sequence: u32,
+    players: HashMap<i32, Baselines>,
+}
+
+impl MoveDeltaEncoder {
+    pub fn new(observer_relative: bool) -> Self {
+        Self { observer_relative, ..Default::default() }
+    }
+
+    /// the move of the player as the observer at `observer` gets it
+    pub fn encode(&mut self, player_id: i32, state: &PlayerState, observer: Option<&PlayerState>) -> SCPlayerMoveDelta {
+        self.sequence += 1;
+        let state = QuantizedState::quantize(state);
+        let anchor = match observer {
+            Some(observer) if self.observer_relative => QuantizedState::quantize(observer).anchor(),
+            _ => (0, 0),
+        };
+        let mut delta = SCPlayerMoveDelta::new();
+        delta.player_id = player_id;
+        delta.sequence = self.sequence;
+        let baselines = self.players.entry(player_id).or_default();
+        //the observer keeps the states after the baseline in a window of MAX_HISTORY, an older one may be gone
+        let baseline = baselines.acked.filter(|_| baselines.sent.len() < MAX_HISTORY - 1);
+        if let Some(baseline) = baseline {
+            delta.baseline = baseline.sequence;
+        }
+        if baseline.map(|baseline| baseline.anchor).unwrap_or_default() != anchor {
+            (delta.anchor_x, delta.anchor_y) = (Some(anchor.0), Some(anchor.1));
+        }
+        let baseline = baseline.map(|baseline| baseline.state);
+        if baseline.is_none_or(|baseline| baseline.x != state.x) {
+            delta.x = Some(state.x.wrapping_sub(anchor_offset(anchor.0)));
+        }
+        if baseline.is_none_or(|baseline| baseline.y != state.y) {
+            delta.y = Some(state.y.wrapping_sub(anchor_offset(anchor.1)));
+        }
+        if baseline.is_none_or(|baseline| baseline.rotation != state.rotation) {
+            delta.rotation = Some(state.rotation);
+        }
+        if baseline.is_none_or(|baseline| baseline.speed != state.speed) {
+            delta.speed = Some(state.speed);
+        }
+        baselines.sent.push_back(Sent { sequence: self.sequence, state, anchor });
+        if baselines.sent.len() > MAX_HISTORY {
+            baselines.sent.pop_front();
+        }
+        delta
+    }
+
+    /// the observer received every delta up to the sequence, the last of them becomes the baseline of each player
+    pub fn ack(&mut self, sequence: u32) {
+        for baselines in self.players.values_mut() {
+            while let Some(&sent) = baselines.sent.front() {
+                if sent.sequence > sequence {
+                    break;
+                }
+                baselines.acked = Some(sent);
+                baselines.sent.pop_front();
+            }
+        }
+    }
+
+    /// the player left the world, its next delta is a full state
+    pub fn forget(&mut self, player_id: i32) {
+        self.players.remove(&player_id);
+    }
+}
+
+/// the client side of [MoveDeltaEncoder], keeps the states received since the last baseline of every player
+#[derive(Debug, Default)]
+pub struct MoveDeltaDecoder {
+    received: u32,
+    players: HashMap<i32, VecDeque<Sent>>,
+}
+
+impl MoveDeltaDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(&mut self, delta: &SCPlayerMoveDelta) -> anyhow::Result<QuantizedState> {
+        let history = self.players.entry(delta.player_id).or_default();
+        let baseline = match delta.baseline {
+            0 => None,
+            sequence => {
+                let baseline = history.iter()
+                    .find(|received| received.sequence == sequence)
+                    .ok_or_else(|| anyhow!("baseline {} of player {} not found", sequence, delta.player_id))?;
+                Some(*baseline)
+            }
+        };
+        let base_anchor = baseline.map(|baseline| baseline.anchor).unwrap_or_default();
+        let anchor = (delta.anchor_x.unwrap_or(base_anchor.0), delta.anchor_y.unwrap_or(base_anchor.1));
+        let baseline = baseline.map(|baseline| baseline.state);
+        let field = |value: Option<i32>, baseline: Option<i32>, name: &str| {
+            value.or(baseline).ok_or_else(|| anyhow!("{} of player {} missing without a baseline", name, delta.player_id))
+        };
+        let state = QuantizedState {
+            x: field(delta.x.map(|x| x.wrapping_add(anchor_offset(anchor.0))), baseline.map(|b| b.x), "x")?,
+            y: field(delta.y.map(|y| y.wrapping_add(anchor_offset(anchor.1))), baseline.map(|b| b.y), "y")?,
+            rotation: field(delta.rotation.map(|r| r as i32), baseline.map(|b| b.rotation as i32), "rotation")? as u32,
+            speed: field(delta.speed, baseline.map(|b| b.speed), "speed")?,
+        };
+        //the server never goes back to an older baseline
+        history.retain(|received| received.sequence >= delta.baseline);
+        history.push_back(Sent { sequence: delta.sequence, state, anchor });
+        if history.len() > MAX_HISTORY {
+            //the baseline stays until an ack moves the server on, the oldest state after it goes instead
+            let pinned = delta.baseline != 0 && history.front().is_some_and(|received| received.sequence == delta.baseline);
+            history.remove(if pinned { 1 } else { 0 });
+        }
+        self.received = self.received.max(delta.sequence);
+        Ok(state)
+    }
+
+    /// the sequence to ack, every delta up to it was received on a reliable connection
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use protobuf::Message;
+
+    use crate::movement::{MoveDeltaDecoder, MoveDeltaEncoder, QuantizedState};
+    use crate::test::PlayerState;
+
+    fn state(x: f32, y: f32, rotation: f32, speed: f32) -> PlayerState {
+        let mut state = PlayerState::new();
+        state.x = x;
+        state.y = y;
+        state.rotation = rotation;
+        state.speed = speed;
+        state
+    }
+
+    #[test]
+    fn test_move_delta() {
+        let quantized = QuantizedState::quantize(&state(1.03, -2.5, 359.9, 7.));
+        assert_eq!(quantized, QuantizedState { x: 16, y: -40, rotation: 0, speed: 112 });
+        assert_eq!(QuantizedState::quantize(&quantized.to_state()), quantized);
+
+        let (mut full_sizes, mut delta_sizes) = (vec![], vec![]);
+        for observer_relative in [false, true] {
+            let mut encoder = MoveDeltaEncoder::new(observer_relative);
+            let mut decoder = MoveDeltaDecoder::new();
+            let observer = state(1000., 1000., 0., 0.);
+            let moves = [state(990., 1010., 90., 5.), state(991., 1010., 90., 5.), state(992., 1011., 90., 5.), state(993., 1011., 180., 5.)];
+            let full = encoder.encode(7, &moves[0], Some(&observer));
+            assert_eq!(decoder.decode(&full).unwrap(), QuantizedState::quantize(&moves[0]));
+            //nothing acked yet, still a full state
+            let unacked = encoder.encode(7, &moves[1], Some(&observer));
+            assert_eq!(unacked.baseline, 0);
+            assert_eq!(decoder.decode(&unacked).unwrap(), QuantizedState::quantize(&moves[1]));
+
+            encoder.ack(decoder.received());
+            let delta = encoder.encode(7, &moves[2], Some(&observer));
+            assert_eq!(delta.baseline, unacked.sequence);
+            assert_eq!((delta.rotation, delta.speed), (None, None));
+            assert!(delta.compute_size() < full.compute_size());
+            assert_eq!(decoder.decode(&delta).unwrap(), QuantizedState::quantize(&moves[2]));
+            let delta = encoder.encode(7, &moves[3], Some(&observer));
+            assert_eq!((delta.x.is_some(), delta.y.is_some(), delta.rotation.is_some()), (true, true, true));
+            assert_eq!(decoder.decode(&delta).unwrap(), QuantizedState::quantize(&moves[3]));
+            full_sizes.push(full.compute_size());
+            delta_sizes.push(delta.compute_size());
+        }
+        //the anchor is sent with the full state, later deltas of the same cell save a byte per position
+        assert_eq!(full_sizes[1], full_sizes[0] + 2);
+        assert_eq!(delta_sizes[1] + 2, delta_sizes[0]);
+
+        let mut decoder = MoveDeltaDecoder::new();
+        let mut encoder = MoveDeltaEncoder::new(false);
+        encoder.encode(7, &state(1., 1., 0., 0.), None);
+        encoder.ack(1);
+        assert!(decoder.decode(&encoder.encode(7, &state(2., 1., 0., 0.), None)).is_err());
+    }
+
+    #[test]
+    fn test_move_delta_lost_acks() {
+        let mut encoder = MoveDeltaEncoder::new(false);
+        let mut decoder = MoveDeltaDecoder::new();
+        decoder.decode(&encoder.encode(7, &state(0., 0., 0., 0.), None)).unwrap();
+        encoder.ack(decoder.received());
+        //33 acks lost, every delta still decodes
+        for x in 1..=33 {
+            let delta = encoder.encode(7, &state(x as f32, 0., 0., 0.), None);
+            assert_eq!(decoder.decode(&delta).unwrap(), QuantizedState::quantize(&state(x as f32, 0., 0., 0.)));
+            assert_eq!(delta.baseline, if x < 32 { 1 } else { 0 });
+        }
+        encoder.ack(decoder.received());
+        let delta = encoder.encode(7, &state(34., 0., 0., 0.), None);
+        assert_eq!(delta.baseline, decoder.received());
+        assert_eq!(decoder.decode(&delta).unwrap(), QuantizedState::quantize(&state(34., 0., 0., 0.)));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use protobuf::descriptor::{FileDescriptorSet, MessageOptions};
+use protobuf::reflect::{FileDescriptor, MessageDescriptor};
+use protobuf::EnumOrUnknown;
+
+use crate::mapper::schema_fingerprint;
+use crate::message::{cs_descriptors, sc_descriptors};
+use crate::options::Direction;
+use crate::options::exts;
+
+lazy_static! {
+    /// the messages compiled into this crate, the default of [crate::codec::CodecConfig::registry]
+    pub static ref BUILTIN_REGISTRY: Arc<MessageRegistry> = Arc::new(MessageRegistry::builtin().expect("build.rs rejects duplicated builtin messages"));
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    /// the id of the direction is taken by another message
+    DuplicateId { direction: Direction, id: i32, registered: String, duplicate: String },
+    /// the message is already registered in the direction, maybe under another id
+    DuplicateMessage { direction: Direction, name: String, registered_id: i32, duplicate_id: i32 },
+    InvalidMessage { name: String, reason: String },
+    Descriptor(protobuf::Error),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::DuplicateId { direction, id, registered, duplicate } => {
+                write!(f, "{:?} id {} of {} already registered by {}", direction, id, duplicate, registered)
+            }
+            RegistryError::DuplicateMessage { direction, name, registered_id, duplicate_id } => {
+                write!(f, "{:?} msg {} registered with id {} and {}", direction, name, registered_id, duplicate_id)
+            }
+            RegistryError::InvalidMessage { name, reason } => write!(f, "invalid msg {}: {}", name, reason),
+            RegistryError::Descriptor(err) => write!(f, "invalid descriptor set: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+#[derive(Debug, Default, Clone)]
+struct Table {
+    descriptors: HashMap<i32, MessageDescriptor>,
+    /// keyed by the full name, short names clash across packages
+    ids: HashMap<String, i32>,
+    /// ids of the messages declaring `unreliable`
+    unreliable: HashSet<i32>,
+}
+
+/// the wire id of every message in both directions
+#[derive(Debug, Default, Clone)]
+pub struct MessageRegistry {
+    cs: Table,
+    sc: Table,
+}
+
+impl MessageRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builtin() -> Result<Self, RegistryError> {
+        let mut registry = Self::new();
+        for (id, descriptor) in cs_descriptors() {
+            registry.register(Direction::CS, id, descriptor)?;
+        }
+        for (id, descriptor) in sc_descriptors() {
+            registry.register(Direction::SC, id, descriptor)?;
+        }
+        Ok(registry)
+    }
+
+    fn table(&self, direction: Direction) -> Option<&Table> {
+        match direction {
+            Direction::CS => Some(&self.cs),
+            Direction::SC => Some(&self.sc),
+            Direction::DIRECTION_UNSPECIFIED => None,
+        }
+    }
+
+    pub fn register(&mut self, direction: Direction, id: i32, descriptor: MessageDescriptor) -> Result<(), RegistryError> {
+        let name = descriptor.full_name().to_string();
+        if id <= 0 || id > u16::MAX as i32 {
+            return Err(RegistryError::InvalidMessage { name, reason: format!("id {} out of range 1..={}", id, u16::MAX) });
+        }
+        let table = match direction {
+            Direction::CS => &mut self.cs,
+            Direction::SC => &mut self.sc,
+            Direction::DIRECTION_UNSPECIFIED => {
+                return Err(RegistryError::InvalidMessage { name, reason: "direction unspecified".to_string() });
+            }
+        };
+        if let Some(registered) = table.descriptors.get(&id) {
+            return Err(RegistryError::DuplicateId { direction, id, registered: registered.full_name().to_string(), duplicate: name });
+        }
+        if let Some(registered_id) = table.ids.get(&name) {
+            return Err(RegistryError::DuplicateMessage { direction, name, registered_id: *registered_id, duplicate_id: id });
+        }
+        if exts::unreliable.get(&descriptor.proto().options).unwrap_or_default() {
+            table.unreliable.insert(id);
+        }
+        table.ids.insert(name, id);
+        table.descriptors.insert(id, descriptor);
+        Ok(())
+    }
+
+    /// register the messages declaring `msg_id` and `direction` in a descriptor set, e.g. the output of
+    /// `protoc --include_imports --descriptor_set_out` for a plugin, files of this crate in the set are skipped,
+    /// all or none of them are registered
+    pub fn register_descriptor_set(&mut self, descriptor_set: FileDescriptorSet) -> Result<(), RegistryError> {
+        let builtin = vec![
+            protobuf::descriptor::file_descriptor().clone(),
+            crate::options::file_descriptor().clone(),
+            crate::test::file_descriptor().clone(),
+            crate::storage::file_descriptor().clone(),
+        ];
+        let files = descriptor_set.file.into_iter()
+            .filter(|file| builtin.iter().all(|b| b.proto().name() != file.name()))
+            .collect();
+        let files = FileDescriptor::new_dynamic_fds(files, &builtin).map_err(RegistryError::Descriptor)?;
+        let mut registry = self.clone();
+        for file in files {
+            for descriptor in file.messages() {
+                if let Some((direction, id)) = read_options(&descriptor)? {
+                    registry.register(direction, id, descriptor)?;
+                }
+            }
+        }
+        *self = registry;
+        Ok(())
+    }
+
+    pub fn descriptor(&self, direction: Direction, id: i32) -> Option<&MessageDescriptor> {
+        self.table(direction)?.descriptors.get(&id)
+    }
+
+    pub fn id(&self, direction: Direction, full_name: &str) -> Option<i32> {
+        self.table(direction)?.ids.get(full_name).copied()
+    }
+
+    /// the message may go over the datagram channel, see [crate::datagram]
+    pub fn is_unreliable(&self, direction: Direction, id: i32) -> bool {
+        self.table(direction).is_some_and(|table| table.unreliable.contains(&id))
+    }
+
+    pub fn descriptors(&self, direction: Direction) -> Vec<(i32, MessageDescriptor)> {
+        self.table(direction)
+            .map(|table| table.descriptors.iter().map(|(id, descriptor)| (*id, descriptor.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    /// both ends must register the same messages, see [crate::mapper::ProtocolVersion]
+    pub fn fingerprint(&self) -> [u8; 8] {
+        schema_fingerprint(&[("CS", self.descriptors(Direction::CS)), ("SC", self.descriptors(Direction::SC))])
+    }
+}
+
+fn read_options(descriptor: &MessageDescriptor) -> Result<Option<(Direction, i32)>, RegistryError> {
+    let options: &MessageOptions = &descriptor.proto().options;
+    match (exts::msg_id.get(options), exts::direction.get(options).map(|d: EnumOrUnknown<Direction>| d.enum_value())) {
+        (None, None) => Ok(None),
+        (Some(id), Some(Ok(direction))) => Ok(Some((direction, id))),
+        (_, _) => Err(RegistryError::InvalidMessage {
+            name: descriptor.full_name().to_string(),
+            reason: "must declare both msg_id and a known direction".to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use protobuf::descriptor::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::codec::{CodecConfig, Packet, ProtoCodec};
+    use crate::options::Direction;
+    use crate::registry::{MessageRegistry, RegistryError};
+
+    fn plugin_message(name: &str, id: u64) -> DescriptorProto {
+        let mut message = DescriptorProto::new();
+        message.set_name(name.to_string());
+        let unknown = message.options.mut_or_insert_default().special_fields.mut_unknown_fields();
+        unknown.add_varint(50001, id);
+        unknown.add_varint(50002, Direction::CS as u64);
+        message
+    }
+
+    fn plugin_set(messages: Vec<DescriptorProto>) -> FileDescriptorSet {
+        let mut file = FileDescriptorProto::new();
+        file.set_name("plugin.proto".to_string());
+        file.set_package("com.example.plugin".to_string());
+        file.set_syntax("proto3".to_string());
+        file.dependency.push("options.proto".to_string());
+        file.message_type = messages;
+        let mut set = FileDescriptorSet::new();
+        set.file.push(file);
+        set
+    }
+
+    #[test]
+    fn test_registry() {
+        let builtin = MessageRegistry::builtin().unwrap();
+        assert_eq!(builtin.id(Direction::CS, "com.mikai233.aoi.LoginReq"), Some(2));
+        assert_eq!(builtin.id(Direction::CS, "LoginReq"), None);
+        assert!(builtin.is_unreliable(Direction::CS, 3));
+        assert!(!builtin.is_unreliable(Direction::CS, 2));
+
+        //the same short name in another package doesn't clash
+        let mut registry = builtin.clone();
+        registry.register_descriptor_set(plugin_set(vec![plugin_message("LoginReq", 100)])).unwrap();
+        assert_eq!(registry.id(Direction::CS, "com.example.plugin.LoginReq"), Some(100));
+        assert_eq!(registry.id(Direction::CS, "com.mikai233.aoi.LoginReq"), Some(2));
+        assert_ne!(registry.fingerprint(), builtin.fingerprint());
+
+        let mut registry = builtin.clone();
+        let err = registry.register_descriptor_set(plugin_set(vec![plugin_message("PluginReq", 100), plugin_message("PluginResp", 2)])).unwrap_err();
+        assert!(matches!(err, RegistryError::DuplicateId { id: 2, .. }), "{}", err);
+        //the messages before the failed one aren't kept
+        assert_eq!(registry.id(Direction::CS, "com.example.plugin.PluginReq"), None);
+        assert_eq!(registry.fingerprint(), builtin.fingerprint());
+        let login = builtin.descriptor(Direction::CS, 2).unwrap().clone();
+        let err = registry.register(Direction::CS, 200, login).unwrap_err();
+        assert!(matches!(err, RegistryError::DuplicateMessage { registered_id: 2, .. }), "{}", err);
+
+        //dynamic messages go through the codec like generated ones
+        let mut registry = builtin.clone();
+        registry.register_descriptor_set(plugin_set(vec![plugin_message("PluginReq", 100)])).unwrap();
+        let config = CodecConfig { registry: Arc::new(registry), ..Default::default() };
+        let mut client = ProtoCodec::with_config(false, config.clone());
+        let mut server = ProtoCodec::with_config(true, config);
+        let descriptor = client.config().registry.descriptor(Direction::CS, 100).unwrap().clone();
+        let mut buf = BytesMut::new();
+        client.encode(Packet::notify(descriptor.new_instance()), &mut buf).unwrap();
+        let packet = server.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(packet.msg.descriptor_dyn().full_name(), "com.example.plugin.PluginReq");
+    }
+}
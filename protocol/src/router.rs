@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{debug, warn};
+use protobuf::{MessageDyn, MessageFull};
+
+use crate::mapper::cast;
+
+type BoxHandler<S, C> = Box<dyn for<'a> Fn(&'a mut S, C, Box<dyn MessageDyn>) -> BoxFuture<'a, anyhow::Result<()>> + Send + Sync>;
+
+/// routes messages of an actor with state `S` to the handler registered for the message type,
+/// `C` is passed along with the message, e.g. the id of the player who sent it
+pub struct Router<S, C = ()> {
+    handlers: HashMap<String, BoxHandler<S, C>>,
+    middlewares: Vec<Box<dyn Middleware<S, C>>>,
+}
+
+/// returned by [Router::dispatch] for a message without handler
+#[derive(Debug)]
+pub struct UnregisteredMessage(pub String);
+
+impl Display for UnregisteredMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no handler registered for msg:{}", self.0)
+    }
+}
+
+impl std::error::Error for UnregisteredMessage {}
+
+impl<S: Send + 'static, C: Send + 'static> Router<S, C> {
+    pub fn new() -> Self {
+        Self { handlers: HashMap::new(), middlewares: vec![] }
+    }
+
+    /// register the handler of message `T`, usually `|state, ctx, msg| handle_xxx(state, ctx, msg).boxed()`
+    pub fn route<T, F>(mut self, handler: F) -> Self
+        where T: MessageFull, F: for<'a> Fn(&'a mut S, C, T) -> BoxFuture<'a, anyhow::Result<()>> + Send + Sync + 'static {
+        let name = T::descriptor().full_name().to_string();
+        let handler: BoxHandler<S, C> = Box::new(move |state, ctx, msg| {
+            match cast::<T>(msg) {
+                Ok(msg) => handler(state, ctx, *msg),
+                Err(err) => async move { Err(err) }.boxed(),
+            }
+        });
+        if self.handlers.insert(name.clone(), handler).is_some() {
+            panic!("msg:{} routed twice", name);
+        }
+        self
+    }
+
+    /// middlewares run in the order they are added, before the handler
+    pub fn layer<M: Middleware<S, C>>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    pub fn is_registered(&self, full_name: &str) -> bool {
+        self.handlers.contains_key(full_name)
+    }
+
+    /// the names without handler, e.g. check all the messages of a direction at startup
+    pub fn unregistered<'n>(&self, full_names: impl IntoIterator<Item=&'n str>) -> Vec<&'n str> {
+        full_names.into_iter().filter(|name| !self.is_registered(name)).collect()
+    }
+
+    /// run the middlewares and the handler of the message, a message without handler
+    /// still passes the middlewares and then fails with [UnregisteredMessage]
+    pub async fn dispatch(&self, state: &mut S, ctx: C, msg: Box<dyn MessageDyn>) -> anyhow::Result<()> {
+        let descriptor = msg.descriptor_dyn();
+        let handler = self.handlers.get(descriptor.full_name());
+        let next = Next { middlewares: &self.middlewares, handler };
+        next.run(state, ctx, msg).await
+    }
+}
+
+impl<S: Send + 'static, C: Send + 'static> Default for Router<S, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the rest of the middleware chain and the handler
+pub struct Next<'r, S, C> {
+    middlewares: &'r [Box<dyn Middleware<S, C>>],
+    handler: Option<&'r BoxHandler<S, C>>,
+}
+
+impl<'r, S: Send + 'static, C: Send + 'static> Next<'r, S, C> {
+    pub fn run<'a>(self, state: &'a mut S, ctx: C, msg: Box<dyn MessageDyn>) -> BoxFuture<'a, anyhow::Result<()>> where 'r: 'a {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => {
+                middleware.call(state, ctx, msg, Next { middlewares, handler: self.handler })
+            }
+            None => match self.handler {
+                Some(handler) => handler(state, ctx, msg),
+                None => {
+                    let name = msg.descriptor_dyn().full_name().to_string();
+                    async move { Err(UnregisteredMessage(name).into()) }.boxed()
+                }
+            },
+        }
+    }
+}
+
+pub trait Middleware<S, C>: Send + Sync + 'static {
+    /// call `next.run(state, ctx, msg)` to continue, or return without calling it to drop the message
+    fn call<'a>(&'a self, state: &'a mut S, ctx: C, msg: Box<dyn MessageDyn>, next: Next<'a, S, C>) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// debug log every message before it is handled
+pub struct LogMiddleware;
+
+impl<S: Send + 'static, C: Send + 'static> Middleware<S, C> for LogMiddleware {
+    fn call<'a>(&'a self, state: &'a mut S, ctx: C, msg: Box<dyn MessageDyn>, next: Next<'a, S, C>) -> BoxFuture<'a, anyhow::Result<()>> {
+        debug!("handle msg:{} {}", msg.descriptor_dyn().name(), msg);
+        next.run(state, ctx, msg)
+    }
+}
+
+/// warn about handlers slower than the threshold, they block the actor
+pub struct TimingMiddleware {
+    pub threshold: Duration,
+}
+
+impl<S: Send + 'static, C: Send + 'static> Middleware<S, C> for TimingMiddleware {
+    fn call<'a>(&'a self, state: &'a mut S, ctx: C, msg: Box<dyn MessageDyn>, next: Next<'a, S, C>) -> BoxFuture<'a, anyhow::Result<()>> {
+        let name = msg.descriptor_dyn().name().to_string();
+        async move {
+            let start = Instant::now();
+            let result = next.run(state, ctx, msg).await;
+            let elapsed = start.elapsed();
+            if elapsed > self.threshold {
+                warn!("handle msg:{} took {:?}", name, elapsed);
+            }
+            result
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+    use protobuf::MessageDyn;
+
+    use crate::router::{Middleware, Next, Router, UnregisteredMessage};
+    use crate::test::{LoginReq, TestReq};
+
+    async fn handle_login_req(state: &mut Vec<i32>, ctx: i32, req: LoginReq) -> anyhow::Result<()> {
+        state.push(ctx);
+        state.push(req.player_id);
+        Ok(())
+    }
+
+    /// drops TestReq before it reaches the router
+    struct DropTestReq;
+
+    impl Middleware<Vec<i32>, i32> for DropTestReq {
+        fn call<'a>(&'a self, state: &'a mut Vec<i32>, ctx: i32, msg: Box<dyn MessageDyn>, next: Next<'a, Vec<i32>, i32>) -> BoxFuture<'a, anyhow::Result<()>> {
+            if msg.descriptor_dyn().full_name() == "com.mikai233.aoi.TestReq" {
+                return async { Ok(()) }.boxed();
+            }
+            next.run(state, ctx, msg)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let router = Router::<Vec<i32>, i32>::new()
+            .route::<LoginReq, _>(|state, ctx, req| handle_login_req(state, ctx, req).boxed());
+        let mut state = vec![];
+        let mut req = LoginReq::new();
+        req.player_id = 233;
+        router.dispatch(&mut state, 1, Box::new(req)).await.unwrap();
+        assert_eq!(state, vec![1, 233]);
+
+        let err = router.dispatch(&mut state, 1, Box::new(TestReq::new())).await.unwrap_err();
+        assert!(err.downcast_ref::<UnregisteredMessage>().is_some());
+        assert_eq!(router.unregistered(["com.mikai233.aoi.LoginReq", "com.mikai233.aoi.TestReq"]), vec!["com.mikai233.aoi.TestReq"]);
+
+        let router = router.layer(DropTestReq);
+        router.dispatch(&mut state, 1, Box::new(TestReq::new())).await.unwrap();
+    }
+}
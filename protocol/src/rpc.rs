@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures::{Sink, SinkExt};
+use protobuf::MessageDyn;
+use tokio::sync::oneshot;
+
+use crate::codec::Packet;
+
+type PendingCalls = Mutex<HashMap<u32, oneshot::Sender<Box<dyn MessageDyn>>>>;
+
+/// correlates responses with the requests sent by [Rpc::call], cloned between the writer and the reader of a connection
+#[derive(Clone, Default)]
+pub struct Rpc {
+    next_seq: Arc<AtomicU32>,
+    pending: Arc<PendingCalls>,
+}
+
+/// removes the pending call when the call returns or is cancelled
+struct PendingGuard<'a> {
+    pending: &'a PendingCalls,
+    seq: u32,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.seq);
+    }
+}
+
+impl Rpc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// send the request with a new sequence and wait for the response with the same sequence,
+    /// the response is whatever the server replied, e.g. `LoginResp` or `ErrorResp`
+    pub async fn call<S>(&self, sink: &mut S, req: Box<dyn MessageDyn>, timeout: Duration) -> anyhow::Result<Box<dyn MessageDyn>>
+        where S: Sink<Packet> + Unpin, S::Error: std::error::Error + Send + Sync + 'static {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let name = req.descriptor_dyn().name().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(seq, tx);
+        let _guard = PendingGuard { pending: &self.pending, seq };
+        sink.send(Packet::reply(Some(seq), req)).await?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(resp)) => Ok(resp),
+            Ok(Err(_)) => Err(anyhow!("call {} seq:{} dropped", name, seq)),
+            Err(_) => Err(anyhow!("call {} seq:{} timeout after {:?}", name, seq, timeout)),
+        }
+    }
+
+    /// hand a received packet to the call waiting for it, the packet is returned if nobody waits for it,
+    /// e.g. a push notify or a response arriving after the timeout
+    pub fn complete(&self, packet: Packet) -> Option<Packet> {
+        let Some(seq) = packet.seq else {
+            return Some(packet);
+        };
+        let waiting = self.pending.lock().unwrap().remove(&seq);
+        match waiting {
+            Some(tx) => tx.send(packet.msg).err().map(|msg| Packet::reply(Some(seq), msg)),
+            None => Some(packet),
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+
+    use crate::codec::{CodecConfig, Packet, PacketSink};
+    use crate::mapper::cast;
+    use crate::rpc::Rpc;
+    use crate::test::{LoginReq, LoginResp, SCPlayerLeaveNotify};
+    use crate::transport::{BoxConnection, framed_with_config};
+
+    #[tokio::test]
+    async fn test_call() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (client, server) = tokio::join!(
+            framed_with_config::<Packet>(Box::new(client) as BoxConnection, false, CodecConfig::default()),
+            framed_with_config::<Packet>(Box::new(server) as BoxConnection, true, CodecConfig::default()),
+        );
+        let (mut client_sink, mut client_stream, _): (PacketSink, _, _) = client.unwrap();
+        let (mut server_sink, mut server_stream, _): (PacketSink, _, _) = server.unwrap();
+        let rpc = Rpc::new();
+        let reader = rpc.clone();
+        let notifies = tokio::spawn(async move {
+            let mut notifies = vec![];
+            while notifies.len() < 2 {
+                let packet = client_stream.next().await.unwrap().unwrap();
+                if let Some(packet) = reader.complete(packet) {
+                    notifies.push(packet);
+                }
+            }
+            notifies
+        });
+        tokio::spawn(async move {
+            while let Some(Ok(packet)) = server_stream.next().await {
+                let req = cast::<LoginReq>(packet.msg).unwrap();
+                //a notify in between doesn't answer the call
+                server_sink.send(Packet::notify(Box::new(SCPlayerLeaveNotify::new()))).await.unwrap();
+                if req.player_id == 0 {
+                    continue;
+                }
+                let mut resp = LoginResp::new();
+                resp.player_id = req.player_id;
+                server_sink.send(Packet::reply(packet.seq, Box::new(resp))).await.unwrap();
+            }
+        });
+
+        let mut req = LoginReq::new();
+        req.player_id = 233;
+        let resp = rpc.call(&mut client_sink, Box::new(req), Duration::from_secs(1)).await.unwrap();
+        assert_eq!(cast::<LoginResp>(resp).unwrap().player_id, 233);
+        let err = rpc.call(&mut client_sink, Box::new(LoginReq::new()), Duration::from_millis(50)).await;
+        assert!(err.is_err());
+        assert_eq!(rpc.pending(), 0);
+        let notifies = notifies.await.unwrap();
+        assert_eq!(notifies.len(), 2);
+        assert!(notifies.iter().all(|packet| packet.seq.is_none()));
+    }
+}
@@ -0,0 +1,390 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::ops::Not;
+
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, BytesMut};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::codec::ProtoCodecError;
+use crate::mapper::ProtocolVersion;
+use crate::transport::BoxConnection;
+
+const HELLO_MAGIC: &[u8; 3] = b"AOI";
+
+const HELLO_VERSION: u8 = 2;
+
+/// magic + hello version
+const HELLO_PREFIX_LEN: usize = 3 + 1;
+
+/// prefix + protocol version + schema fingerprint + status + mode + public key
+const HELLO_LEN: usize = HELLO_PREFIX_LEN + 2 + 8 + 1 + 1 + 32;
+
+const STATUS_ACCEPT: u8 = 0;
+
+const STATUS_UPGRADE_REQUIRED: u8 = 1;
+
+/// length(u32) + sequence(u64)
+const RECORD_HEADER_LEN: usize = 4 + 8;
+
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionMode {
+    Disabled,
+    /// encrypt if the peer supports it, an active attacker can strip the encryption by rewriting the hellos,
+    /// only use it when nothing secret like a password goes over the connection
+    Optional,
+    /// close the connection if the peer doesn't support it
+    Required,
+}
+
+impl EncryptionMode {
+    fn to_byte(self) -> u8 {
+        match self {
+            EncryptionMode::Disabled => 0,
+            EncryptionMode::Optional => 1,
+            EncryptionMode::Required => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(EncryptionMode::Disabled),
+            1 => Ok(EncryptionMode::Optional),
+            2 => Ok(EncryptionMode::Required),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown encryption mode {}", byte))),
+        }
+    }
+}
+
+/// the peer was built from another protocol, returned inside the [io::Error] of [handshake]
+#[derive(Debug, Clone)]
+pub struct UpgradeRequired {
+    pub local: ProtocolVersion,
+    /// None if the peer hello can't be read by this build
+    pub peer: Option<ProtocolVersion>,
+}
+
+impl Display for UpgradeRequired {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.peer {
+            Some(peer) => write!(f, "upgrade required, local protocol {} peer protocol {}", self.local, peer),
+            None => write!(f, "upgrade required, local protocol {} peer hello unknown", self.local),
+        }
+    }
+}
+
+impl std::error::Error for UpgradeRequired {}
+
+impl UpgradeRequired {
+    fn into_io(self) -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, self)
+    }
+}
+
+struct Hello {
+    protocol: ProtocolVersion,
+    status: u8,
+    mode: EncryptionMode,
+    public: [u8; 32],
+}
+
+impl Hello {
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(HELLO_MAGIC);
+        buf.push(HELLO_VERSION);
+        buf.extend_from_slice(&self.protocol.version.to_be_bytes());
+        buf.extend_from_slice(&self.protocol.fingerprint);
+        buf.push(self.status);
+        buf.push(self.mode.to_byte());
+        buf.extend_from_slice(&self.public);
+    }
+
+    /// None if the peer speaks another hello version, the rest of its hello can't be parsed
+    async fn read_from(connection: &mut BoxConnection) -> io::Result<Option<Hello>> {
+        let mut prefix = [0u8; HELLO_PREFIX_LEN];
+        connection.read_exact(&mut prefix).await?;
+        if &prefix[..3] != HELLO_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid hello"));
+        }
+        if prefix[3] != HELLO_VERSION {
+            return Ok(None);
+        }
+        let mut rest = [0u8; HELLO_LEN - HELLO_PREFIX_LEN];
+        connection.read_exact(&mut rest).await?;
+        let mut fingerprint = [0u8; 8];
+        fingerprint.copy_from_slice(&rest[2..10]);
+        let mut public = [0u8; 32];
+        public.copy_from_slice(&rest[12..]);
+        Ok(Some(Hello {
+            protocol: ProtocolVersion { version: u16::from_be_bytes([rest[0], rest[1]]), fingerprint },
+            status: rest[10],
+            mode: EncryptionMode::from_byte(rest[11])?,
+            public,
+        }))
+    }
+}
+
+/// keys of one direction, the sequence is the nonce so it must never repeat under the same key
+struct Cipher {
+    cipher: ChaCha20Poly1305,
+    sequence: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self { cipher: ChaCha20Poly1305::new(Key::from_slice(key)), sequence: 0 }
+    }
+}
+
+fn nonce(sequence: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+/// encrypts the byte stream of the codec in records of length(u32) + sequence(u64) + ciphertext,
+/// a record with another sequence than the one after the last is rejected, a replayed, reordered or
+/// dropped record breaks the stream
+pub struct SecureSession {
+    sealer: Cipher,
+    opener: Cipher,
+}
+
+impl SecureSession {
+    fn new(is_server: bool, client_to_server: [u8; 32], server_to_client: [u8; 32]) -> Self {
+        let (seal_key, open_key) = if is_server {
+            (server_to_client, client_to_server)
+        } else {
+            (client_to_server, server_to_client)
+        };
+        Self { sealer: Cipher::new(&seal_key), opener: Cipher::new(&open_key) }
+    }
+
+    pub fn seal(&mut self, plain: &[u8], dst: &mut BytesMut) -> Result<(), ProtoCodecError> {
+        let sequence = self.sealer.sequence;
+        self.sealer.sequence = sequence.checked_add(1).ok_or_else(|| anyhow!("record sequence exhausted"))?;
+        //encrypt in place in dst, no buffer for the ciphertext
+        let record_len = RECORD_HEADER_LEN + plain.len() + TAG_LEN;
+        dst.reserve(record_len);
+        dst.put_u32(u32::try_from(record_len)?);
+        dst.put_u64(sequence);
+        let start = dst.len();
+        dst.put_slice(plain);
+        let tag = self.sealer.cipher
+            .encrypt_in_place_detached(&nonce(sequence), b"", &mut dst[start..])
+            .map_err(|_| anyhow!("seal record failed"))?;
+        dst.put_slice(&tag);
+        Ok(())
+    }
+
+    /// decrypt the next record in place, the plaintext is split from src without copying,
+    /// `max_plain_len` limits the record size before it is buffered
+    pub fn open(&mut self, src: &mut BytesMut, max_plain_len: usize) -> Result<Option<BytesMut>, ProtoCodecError> {
+        let buf_len = src.len();
+        if buf_len < 4 {
+            return Ok(None);
+        }
+        let mut record_len_bytes = [0u8; 4];
+        record_len_bytes.copy_from_slice(&src[..4]);
+        let record_len = u32::from_be_bytes(record_len_bytes) as usize;
+        if record_len < RECORD_HEADER_LEN + TAG_LEN {
+            return Err(anyhow!("invalid record length {}", record_len).into());
+        }
+        let max_record_len = RECORD_HEADER_LEN + TAG_LEN + max_plain_len;
+        if record_len > max_record_len {
+            return Err(ProtoCodecError::FrameTooLarge(record_len, max_record_len));
+        }
+        if buf_len < record_len {
+            src.reserve(record_len - buf_len);
+            return Ok(None);
+        }
+        let mut record = src.split_to(record_len);
+        record.advance(4);
+        let sequence = record.get_u64();
+        if sequence != self.opener.sequence {
+            return Err(anyhow!("unexpected record sequence {} expect {}", sequence, self.opener.sequence).into());
+        }
+        let plain_len = record.len() - TAG_LEN;
+        let tag = Tag::clone_from_slice(&record[plain_len..]);
+        record.truncate(plain_len);
+        self.opener.cipher
+            .decrypt_in_place_detached(&nonce(sequence), b"", &mut record, &tag)
+            .map_err(|_| anyhow!("record {} authentication failed", sequence))?;
+        self.opener.sequence = sequence + 1;
+        Ok(Some(record))
+    }
+}
+
+/// exchange hellos with the peer right after connect, the client speaks first and the server answers
+/// with its own hello, or rejects a client of another protocol version or schema with [UpgradeRequired]
+/// before any other traffic, then derive the session keys if both sides support encryption.
+/// the key exchange is anonymous, it protects against eavesdropping but doesn't authenticate the server,
+/// both hellos are bound into the keys so a rewritten hello leaves the two sides with different keys
+pub async fn handshake(connection: &mut BoxConnection, is_server: bool, mode: EncryptionMode, protocol: ProtocolVersion) -> io::Result<Option<SecureSession>> {
+    let secret = match mode {
+        EncryptionMode::Disabled => None,
+        _ => Some(EphemeralSecret::random()),
+    };
+    let public = match &secret {
+        Some(secret) => PublicKey::from(secret).to_bytes(),
+        None => [0u8; 32],
+    };
+    let mut hello = Hello { protocol, status: STATUS_ACCEPT, mode, public };
+    let peer_hello = if is_server {
+        let peer_hello = Hello::read_from(connection).await?;
+        let accepted = matches!(&peer_hello, Some(peer_hello) if peer_hello.protocol == protocol);
+        if accepted.not() {
+            hello.status = STATUS_UPGRADE_REQUIRED;
+        }
+        write_hello(connection, &hello).await?;
+        match peer_hello {
+            Some(peer_hello) if accepted => peer_hello,
+            peer_hello => return Err(UpgradeRequired { local: protocol, peer: peer_hello.map(|h| h.protocol) }.into_io()),
+        }
+    } else {
+        write_hello(connection, &hello).await?;
+        match Hello::read_from(connection).await? {
+            Some(peer_hello) if peer_hello.status == STATUS_ACCEPT && peer_hello.protocol == protocol => peer_hello,
+            peer_hello => return Err(UpgradeRequired { local: protocol, peer: peer_hello.map(|h| h.protocol) }.into_io()),
+        }
+    };
+    let peer_mode = peer_hello.mode;
+    let peer_public = peer_hello.public;
+    //the hellos as sent by the client and the server, every field of them is authenticated by the keys
+    let mut transcript = Vec::with_capacity(HELLO_LEN * 2);
+    let (client_hello, server_hello) = if is_server { (&peer_hello, &hello) } else { (&hello, &peer_hello) };
+    client_hello.write_to(&mut transcript);
+    server_hello.write_to(&mut transcript);
+
+    let secret = match (secret, peer_mode) {
+        (Some(secret), EncryptionMode::Optional | EncryptionMode::Required) => secret,
+        (_, _) if mode == EncryptionMode::Required || peer_mode == EncryptionMode::Required => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "encryption required"));
+        }
+        (_, _) => return Ok(None),
+    };
+    let shared = secret.diffie_hellman(&PublicKey::from(peer_public));
+    if shared.was_contributory().not() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid peer public key"));
+    }
+    let hkdf = Hkdf::<Sha256>::new(Some(&transcript), shared.as_bytes());
+    let mut client_to_server = [0u8; 32];
+    let mut server_to_client = [0u8; 32];
+    hkdf.expand(b"aoi client to server", &mut client_to_server).map_err(|e| io::Error::other(e.to_string()))?;
+    hkdf.expand(b"aoi server to client", &mut server_to_client).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(Some(SecureSession::new(is_server, client_to_server, server_to_client)))
+}
+
+async fn write_hello(connection: &mut BoxConnection, hello: &Hello) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(HELLO_LEN);
+    hello.write_to(&mut buf);
+    connection.write_all(&buf).await?;
+    connection.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::mapper::ProtocolVersion;
+    use crate::secure::{EncryptionMode, handshake, HELLO_LEN, UpgradeRequired};
+    use crate::transport::BoxConnection;
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client: BoxConnection = Box::new(client);
+        let mut server: BoxConnection = Box::new(server);
+        let (client, server) = tokio::join!(
+            handshake(&mut client, false, EncryptionMode::Optional, ProtocolVersion::current()),
+            handshake(&mut server, true, EncryptionMode::Required, ProtocolVersion::current()),
+        );
+        let mut client = client.unwrap().unwrap();
+        let mut server = server.unwrap().unwrap();
+
+        let mut wire = BytesMut::new();
+        client.seal(b"hello", &mut wire).unwrap();
+        let replay = wire.clone();
+        assert_eq!(server.open(&mut wire, 1024).unwrap().unwrap(), &b"hello"[..]);
+        let mut replay = replay;
+        assert!(server.open(&mut replay, 1024).is_err());
+
+        //a record deleted on the way
+        let mut wire = BytesMut::new();
+        client.seal(b"dropped", &mut wire).unwrap();
+        wire.clear();
+        client.seal(b"next", &mut wire).unwrap();
+        assert!(server.open(&mut wire, 1024).is_err());
+
+        let mut wire = BytesMut::new();
+        server.seal(b"world", &mut wire).unwrap();
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+        assert!(client.open(&mut wire, 1024).is_err());
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client: BoxConnection = Box::new(client);
+        let mut server: BoxConnection = Box::new(server);
+        let (client, server) = tokio::join!(
+            handshake(&mut client, false, EncryptionMode::Disabled, ProtocolVersion::current()),
+            handshake(&mut server, true, EncryptionMode::Required, ProtocolVersion::current()),
+        );
+        assert_eq!(client.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+        assert!(server.is_err());
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client: BoxConnection = Box::new(client);
+        let mut server: BoxConnection = Box::new(server);
+        let mut old = ProtocolVersion::current();
+        old.fingerprint[0] ^= 1;
+        let (client, server) = tokio::join!(
+            handshake(&mut client, false, EncryptionMode::Optional, old),
+            handshake(&mut server, true, EncryptionMode::Optional, ProtocolVersion::current()),
+        );
+        let client = client.err().unwrap();
+        let upgrade = client.get_ref().and_then(|e| e.downcast_ref::<UpgradeRequired>()).unwrap();
+        assert_eq!(upgrade.peer, Some(ProtocolVersion::current()));
+        assert!(client.to_string().starts_with("upgrade required"));
+        let server = server.err().unwrap();
+        let upgrade = server.get_ref().and_then(|e| e.downcast_ref::<UpgradeRequired>()).unwrap();
+        assert_eq!(upgrade.peer, Some(old));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rewritten_hello() {
+        let (client, client_relay) = tokio::io::duplex(1024);
+        let (server_relay, server) = tokio::io::duplex(1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client_relay);
+        let (mut server_read, mut server_write) = tokio::io::split(server_relay);
+        tokio::spawn(async move { tokio::io::copy(&mut client_read, &mut server_write).await });
+        //a man in the middle tells the client the server only wants optional encryption
+        tokio::spawn(async move {
+            let mut hello = [0u8; HELLO_LEN];
+            server_read.read_exact(&mut hello).await.unwrap();
+            hello[HELLO_LEN - 33] = EncryptionMode::Optional.to_byte();
+            client_write.write_all(&hello).await.unwrap();
+            tokio::io::copy(&mut server_read, &mut client_write).await
+        });
+        let mut client: BoxConnection = Box::new(client);
+        let mut server: BoxConnection = Box::new(server);
+        let (client, server) = tokio::join!(
+            handshake(&mut client, false, EncryptionMode::Optional, ProtocolVersion::current()),
+            handshake(&mut server, true, EncryptionMode::Required, ProtocolVersion::current()),
+        );
+        let mut client = client.unwrap().unwrap();
+        let mut server = server.unwrap().unwrap();
+        let mut wire = BytesMut::new();
+        client.seal(b"password", &mut wire).unwrap();
+        assert!(server.open(&mut wire, 1024).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Not;
+
+use anyhow::anyhow;
+
+use crate::movement::QuantizedState;
+use crate::test::{EntityDelta, SCWorldSnapshot};
+
+/// snapshots kept by the server waiting for an ack, and by the client to be a baseline
+const MAX_HISTORY: usize = 32;
+
+/// the quantized states of the players visible to an observer, keyed by player id
+pub type Snapshot = BTreeMap<i32, QuantizedState>;
+
+/// the server side of [crate::test::MoveEncoding::MOVE_ENCODING_SNAPSHOT] for one observer, every snapshot is
+/// a delta against the last one the observer acked, a lost snapshot is never resent, the next one covers it
+#[derive(Debug, Default)]
+pub struct SnapshotEncoder {
+    sequence: u32,
+    acked: Option<(u32, Snapshot)>,
+    sent: VecDeque<(u32, Snapshot)>,
+}
+
+impl SnapshotEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode(&mut self, snapshot: Snapshot) -> SCWorldSnapshot {
+        self.sequence += 1;
+        let mut msg = SCWorldSnapshot::new();
+        msg.sequence = self.sequence;
+        let empty = Snapshot::new();
+        let baseline = match &self.acked {
+            //the observer keeps the snapshots after its baseline in a window of MAX_HISTORY, an older one may be gone
+            Some((sequence, _)) if msg.sequence - sequence >= MAX_HISTORY as u32 => &empty,
+            Some((sequence, baseline)) => {
+                msg.baseline = *sequence;
+                baseline
+            }
+            None => &empty,
+        };
+        for (&player_id, state) in &snapshot {
+            let base = baseline.get(&player_id);
+            if base == Some(state) {
+                continue;
+            }
+            let mut entity = EntityDelta::new();
+            entity.player_id = player_id;
+            if base.is_none_or(|base| base.x != state.x) {
+                entity.x = Some(state.x);
+            }
+            if base.is_none_or(|base| base.y != state.y) {
+                entity.y = Some(state.y);
+            }
+            if base.is_none_or(|base| base.rotation != state.rotation) {
+                entity.rotation = Some(state.rotation);
+            }
+            if base.is_none_or(|base| base.speed != state.speed) {
+                entity.speed = Some(state.speed);
+            }
+            msg.entities.push(entity);
+        }
+        msg.removed = baseline.keys().filter(|player_id| snapshot.contains_key(player_id).not()).copied().collect();
+        self.sent.push_back((self.sequence, snapshot));
+        if self.sent.len() > MAX_HISTORY {
+            self.sent.pop_front();
+        }
+        msg
+    }
+
+    /// the observer received the snapshot, an ack older than the baseline or of a forgotten snapshot is ignored
+    pub fn ack(&mut self, sequence: u32) {
+        let Some(index) = self.sent.iter().position(|(sent, _)| *sent == sequence) else {
+            return;
+        };
+        //the snapshots before it can't become the baseline any more
+        self.acked = self.sent.drain(..=index).next_back();
+    }
+
+    /// the sequence of the baseline, 0 if nothing was acked
+    pub fn acked(&self) -> u32 {
+        self.acked.as_ref().map(|(sequence, _)| *sequence).unwrap_or_default()
+    }
+}
+
+/// the client side of [SnapshotEncoder], rebuilds every snapshot from the baseline it names
+#[derive(Debug, Default)]
+pub struct SnapshotDecoder {
+    received: VecDeque<(u32, Snapshot)>,
+}
+
+impl SnapshotDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the full snapshot, acking it lets the server send the next ones relative to it
+    pub fn decode(&mut self, msg: &SCWorldSnapshot) -> anyhow::Result<&Snapshot> {
+        let mut snapshot = match msg.baseline {
+            0 => Snapshot::new(),
+            baseline => self.received.iter()
+                .find(|(sequence, _)| *sequence == baseline)
+                .map(|(_, snapshot)| snapshot.clone())
+                .ok_or_else(|| anyhow!("baseline snapshot {} not found", baseline))?,
+        };
+        for player_id in &msg.removed {
+            snapshot.remove(player_id);
+        }
+        for entity in &msg.entities {
+            let base = snapshot.get(&entity.player_id).copied();
+            let field = |value: Option<i32>, base: Option<i32>, name: &str| {
+                value.or(base).ok_or_else(|| anyhow!("{} of player {} missing without a baseline", name, entity.player_id))
+            };
+            let state = QuantizedState {
+                x: field(entity.x, base.map(|b| b.x), "x")?,
+                y: field(entity.y, base.map(|b| b.y), "y")?,
+                rotation: field(entity.rotation.map(|r| r as i32), base.map(|b| b.rotation as i32), "rotation")? as u32,
+                speed: field(entity.speed, base.map(|b| b.speed), "speed")?,
+            };
+            snapshot.insert(entity.player_id, state);
+        }
+        //the server never goes back to an older baseline
+        self.received.retain(|(sequence, _)| *sequence >= msg.baseline);
+        self.received.push_back((msg.sequence, snapshot));
+        if self.received.len() > MAX_HISTORY {
+            //the baseline stays until an ack moves the server on, the oldest snapshot after it goes instead
+            let pinned = msg.baseline != 0 && self.received.front().is_some_and(|(sequence, _)| *sequence == msg.baseline);
+            self.received.remove(if pinned { 1 } else { 0 });
+        }
+        Ok(&self.received.back().expect("just pushed").1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use protobuf::Message;
+
+    use crate::movement::QuantizedState;
+    use crate::snapshot::{Snapshot, SnapshotDecoder, SnapshotEncoder};
+
+    fn snapshot(states: &[(i32, i32)]) -> Snapshot {
+        states.iter().map(|&(player_id, x)| (player_id, QuantizedState { x, y: 10, rotation: 256, speed: 32 })).collect()
+    }
+
+    #[test]
+    fn test_snapshot_delta() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+        let first = snapshot(&[(1, 0), (2, 0), (3, 0)]);
+        let full = encoder.encode(first.clone());
+        assert_eq!((full.baseline, full.entities.len()), (0, 3));
+        assert_eq!(decoder.decode(&full).unwrap(), &first);
+        encoder.ack(full.sequence);
+
+        //lost on the way, the baseline stays
+        let lost = encoder.encode(snapshot(&[(1, 16), (2, 0), (3, 0)]));
+        assert_eq!(lost.baseline, full.sequence);
+
+        let third = snapshot(&[(1, 32), (2, 0), (4, 0)]);
+        let delta = encoder.encode(third.clone());
+        assert_eq!(delta.baseline, full.sequence);
+        assert_eq!(delta.removed, vec![3]);
+        assert_eq!(delta.entities.iter().map(|e| e.player_id).collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!((delta.entities[0].x, delta.entities[0].y), (Some(32), None));
+        assert!(delta.compute_size() < full.compute_size());
+        assert_eq!(decoder.decode(&delta).unwrap(), &third);
+
+        //an ack of the lost snapshot can't happen, a late ack of an older one doesn't move the baseline back
+        encoder.ack(delta.sequence);
+        encoder.ack(lost.sequence);
+        assert_eq!(encoder.acked(), delta.sequence);
+        let same = encoder.encode(third.clone());
+        assert!(same.entities.is_empty() && same.removed.is_empty());
+        assert_eq!(decoder.decode(&same).unwrap(), &third);
+
+        //a client which lost the baseline can't decode
+        let mut decoder = SnapshotDecoder::new();
+        assert!(decoder.decode(&same).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_lost_acks() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+        let first = encoder.encode(snapshot(&[(1, 0)]));
+        decoder.decode(&first).unwrap();
+        encoder.ack(first.sequence);
+        //33 acks lost, every snapshot still decodes
+        for x in 1..=33 {
+            let msg = encoder.encode(snapshot(&[(1, x)]));
+            assert_eq!(decoder.decode(&msg).unwrap(), &snapshot(&[(1, x)]));
+            let baseline = if msg.sequence - first.sequence < 32 { first.sequence } else { 0 };
+            assert_eq!(msg.baseline, baseline);
+        }
+        //the next ack that gets through is a baseline again
+        let msg = encoder.encode(snapshot(&[(1, 34)]));
+        decoder.decode(&msg).unwrap();
+        encoder.ack(msg.sequence);
+        let delta = encoder.encode(snapshot(&[(1, 35)]));
+        assert_eq!(delta.baseline, msg.sequence);
+        assert_eq!(decoder.decode(&delta).unwrap(), &snapshot(&[(1, 35)]));
+    }
+}
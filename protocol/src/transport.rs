@@ -0,0 +1,215 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use futures::stream::SplitSink;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_kcp::KcpStream;
+use tokio_util::codec::{Encoder, Framed};
+
+use crate::codec::{CodecConfig, Compression, MessageSink, MessageStream, ProtoCodec};
+use crate::kcp::{KcpDiagnostics, KcpServer, KcpSettings};
+use crate::mapper::ProtocolVersion;
+use crate::secure;
+use crate::websocket;
+use crate::websocket::WsListener;
+
+const MEMORY_PIPE_BUFFER: usize = 64 * 1024;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// a byte stream the codec can be framed on
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+pub type BoxConnection = Box<dyn Connection>;
+
+pub type Accepted = (BoxConnection, PeerAddr);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Socket(SocketAddr),
+    Memory(u64),
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Socket(addr) => {
+                write!(f, "{}", addr)
+            }
+            PeerAddr::Memory(id) => {
+                write!(f, "memory:{}", id)
+            }
+        }
+    }
+}
+
+pub trait Listener: Send + 'static {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Accepted>>;
+
+    /// only kcp listeners have something to tell
+    fn kcp_diagnostics(&self) -> Option<KcpDiagnostics> {
+        None
+    }
+}
+
+/// where a server listens or a client connects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Kcp(String),
+    Tcp(String),
+    WebSocket(String),
+}
+
+impl Endpoint {
+    /// bind with the default kcp settings
+    pub async fn bind(&self) -> io::Result<Box<dyn Listener>> {
+        self.bind_with(KcpSettings::default()).await
+    }
+
+    /// the kcp settings are ignored by the other transports
+    pub async fn bind_with(&self, kcp: KcpSettings) -> io::Result<Box<dyn Listener>> {
+        let listener: Box<dyn Listener> = match self {
+            Endpoint::Kcp(addr) => Box::new(KcpServer::bind(kcp, addr).await?),
+            Endpoint::Tcp(addr) => Box::new(TcpListener::bind(addr).await?),
+            Endpoint::WebSocket(addr) => Box::new(WsListener::bind(addr).await?),
+        };
+        Ok(listener)
+    }
+
+    /// connect with the default kcp settings
+    pub async fn connect(&self) -> io::Result<BoxConnection> {
+        self.connect_with(KcpSettings::default()).await
+    }
+
+    /// the kcp settings are ignored by the other transports
+    pub async fn connect_with(&self, kcp: KcpSettings) -> io::Result<BoxConnection> {
+        let connection: BoxConnection = match self {
+            Endpoint::Kcp(addr) => {
+                let addr = addr.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Box::new(KcpStream::connect(&kcp.to_config(), addr).await.map_err(io::Error::from)?)
+            }
+            Endpoint::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            Endpoint::WebSocket(addr) => websocket::connect(addr).await?,
+        };
+        Ok(connection)
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Kcp(addr) => {
+                write!(f, "kcp://{}", addr)
+            }
+            Endpoint::Tcp(addr) => {
+                write!(f, "tcp://{}", addr)
+            }
+            Endpoint::WebSocket(addr) => {
+                write!(f, "ws://{}", addr)
+            }
+        }
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Accepted>> {
+        async move {
+            let (stream, addr) = TcpListener::accept(self).await?;
+            stream.set_nodelay(true)?;
+            Ok((Box::new(stream) as BoxConnection, PeerAddr::Socket(addr)))
+        }.boxed()
+    }
+}
+
+/// in-process transport, connections are `tokio::io::duplex` pipes
+pub fn memory_transport() -> (MemoryListener, MemoryConnector) {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let listener = MemoryListener { receiver: rx };
+    let connector = MemoryConnector { sender: tx, next_id: Arc::new(AtomicU64::new(1)) };
+    (listener, connector)
+}
+
+pub struct MemoryListener {
+    receiver: tokio::sync::mpsc::UnboundedReceiver<Accepted>,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Accepted>> {
+        async move {
+            match self.receiver.recv().await {
+                Some(accepted) => Ok(accepted),
+                //all the connectors dropped, nothing will come any more
+                None => std::future::pending().await,
+            }
+        }.boxed()
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryConnector {
+    sender: tokio::sync::mpsc::UnboundedSender<Accepted>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl MemoryConnector {
+    pub fn connect(&self) -> io::Result<BoxConnection> {
+        let (client, server) = tokio::io::duplex(MEMORY_PIPE_BUFFER);
+        let addr = PeerAddr::Memory(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.sender
+            .send((Box::new(server), addr))
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, "memory listener dropped"))?;
+        Ok(Box::new(client))
+    }
+}
+
+/// accept from all the listeners at once, every listener runs in its own task
+pub fn accept_all(listeners: Vec<Box<dyn Listener>>) -> tokio::sync::mpsc::UnboundedReceiver<io::Result<Accepted>> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    for mut listener in listeners {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let accepted = listener.accept().await;
+                if tx.send(accepted).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    rx
+}
+
+/// frame the connection without the hello exchange, the peer must do the same
+pub fn framed(connection: BoxConnection, is_server: bool) -> (MessageSink, MessageStream) {
+    Framed::new(connection, ProtoCodec::new(is_server)).split()
+}
+
+/// exchange hellos with the peer and frame the connection, encrypted if negotiated, the sink takes
+/// `Box<dyn MessageDyn>` or [crate::codec::Packet], also returns the compression switch of the connection,
+/// compression stays off until it is negotiated
+pub async fn framed_with_config<I>(mut connection: BoxConnection, is_server: bool, config: CodecConfig) -> io::Result<(SplitSink<Framed<BoxConnection, ProtoCodec>, I>, MessageStream, Compression)>
+    where ProtoCodec: Encoder<I> {
+    let session = tokio::time::timeout(HANDSHAKE_TIMEOUT, secure::handshake(&mut connection, is_server, config.encryption, ProtocolVersion::of(&config.registry)))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "hello timeout"))??;
+    let mut codec = ProtoCodec::with_config(is_server, config);
+    if let Some(session) = session {
+        codec.set_secure(session);
+    }
+    let compression = codec.compression();
+    let (sink, stream) = Framed::new(connection, codec).split();
+    Ok((sink, stream, compression))
+}
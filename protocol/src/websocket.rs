@@ -0,0 +1,179 @@
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Not;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use futures::future::BoxFuture;
+use futures::{FutureExt, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::transport::{Accepted, BoxConnection, Listener, PeerAddr};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// byte stream over a websocket, every flush is sent as one binary message,
+/// so a browser receives the same frames the codec writes to the other transports
+pub struct WsConnection<S> {
+    ws: WebSocketStream<S>,
+    read_buf: Bytes,
+    write_buf: Vec<u8>,
+}
+
+impl<S> WsConnection<S> {
+    pub fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            read_buf: Bytes::new(),
+            write_buf: Vec::new(),
+        }
+    }
+}
+
+fn ws_error(err: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+impl<S> AsyncRead for WsConnection<S> where S: AsyncRead + AsyncWrite + Unpin {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            match ready!(Pin::new(&mut self.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_buf = Bytes::from(data);
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    return Poll::Ready(Ok(()));
+                }
+                //ping is answered by tungstenite, text is not part of the protocol
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    return Poll::Ready(Err(ws_error(err)));
+                }
+            }
+        }
+        let n = self.read_buf.len().min(buf.remaining());
+        buf.put_slice(&self.read_buf[..n]);
+        self.read_buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsConnection<S> where S: AsyncRead + AsyncWrite + Unpin {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_buf.is_empty().not() {
+            ready!(Pin::new(&mut self.ws).poll_ready(cx)).map_err(ws_error)?;
+            let data = std::mem::take(&mut self.write_buf);
+            Pin::new(&mut self.ws).start_send(Message::Binary(data)).map_err(ws_error)?;
+        }
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.ws).poll_close(cx).map_err(ws_error)
+    }
+}
+
+/// the handshakes run in their own tasks so a slow client can't block the others
+pub struct WsListener {
+    receiver: tokio::sync::mpsc::UnboundedReceiver<io::Result<Accepted>>,
+}
+
+impl WsListener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        if tx.send(Err(err)).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                if tx.is_closed() {
+                    break;
+                }
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let _ = tx.send(handshake(stream, addr).await);
+                });
+            }
+        });
+        Ok(Self { receiver: rx })
+    }
+}
+
+async fn handshake(stream: TcpStream, addr: SocketAddr) -> io::Result<Accepted> {
+    stream.set_nodelay(true)?;
+    let ws = tokio::time::timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::accept_async(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{} websocket handshake timeout", addr)))?
+        .map_err(ws_error)?;
+    Ok((Box::new(WsConnection::new(ws)) as BoxConnection, PeerAddr::Socket(addr)))
+}
+
+impl Listener for WsListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Accepted>> {
+        async move {
+            match self.receiver.recv().await {
+                Some(accepted) => accepted,
+                //the accept task only stops after the listener dropped
+                None => std::future::pending().await,
+            }
+        }.boxed()
+    }
+}
+
+pub async fn connect(addr: &str) -> io::Result<BoxConnection> {
+    let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.map_err(ws_error)?;
+    Ok(Box::new(WsConnection::new(ws)))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{SinkExt, StreamExt};
+
+    use crate::mapper::cast;
+    use crate::test::{LoginReq, LoginResp};
+    use crate::transport::{BoxConnection, framed};
+    use crate::websocket::WsConnection;
+
+    #[tokio::test]
+    async fn test_codec_over_websocket() {
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let (client, server) = tokio::join!(
+            tokio_tungstenite::client_async("ws://localhost/", client_io),
+            tokio_tungstenite::accept_async(server_io),
+        );
+        let client: BoxConnection = Box::new(WsConnection::new(client.unwrap().0));
+        let server: BoxConnection = Box::new(WsConnection::new(server.unwrap()));
+        let (mut client_sink, mut client_stream) = framed(client, false);
+        let (mut server_sink, mut server_stream) = framed(server, true);
+
+        let mut login = LoginReq::new();
+        login.player_id = 233;
+        client_sink.send(Box::new(login)).await.unwrap();
+        let req = cast::<LoginReq>(server_stream.next().await.unwrap().unwrap().msg).unwrap();
+        assert_eq!(req.player_id, 233);
+
+        let mut resp = LoginResp::new();
+        resp.player_id = 1;
+        server_sink.send(Box::new(resp)).await.unwrap();
+        let resp = cast::<LoginResp>(client_stream.next().await.unwrap().unwrap().msg).unwrap();
+        assert_eq!(resp.player_id, 1);
+    }
+}
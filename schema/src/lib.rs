@@ -0,0 +1,357 @@
+//! the wire contract of the protocol crate, read by its build script and rewritten by the `schema` binary
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::ops::Not;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use protobuf::descriptor::field_descriptor_proto::{Label, Type};
+use protobuf::descriptor::{DescriptorProto, FileDescriptorSet};
+
+/// the wire contract with deployed clients, checked in next to the Cargo.toml of protocol
+pub const SCHEMA_LOCK: &str = "schema.lock";
+
+/// where the protos are, relative to the Cargo.toml of protocol
+pub const PROTO_PATH: &str = "src/proto";
+
+/// field numbers of the `msg_id` and `direction` message options in options.proto
+const MSG_ID_OPTION: u32 = 50001;
+const DIRECTION_OPTION: u32 = 50002;
+
+/// (direction value, lock label, enum name, table fn) of the two directions
+pub const DIRECTIONS: [(u64, &str, &str, &str); 2] = [(1, "CS_MSG", "CsMessage", "cs_descriptors"), (2, "SC_MSG", "ScMessage", "sc_descriptors")];
+
+/// parse all the protos of the directory with the vendored protoc
+pub fn descriptor_set(proto_path: &Path) -> anyhow::Result<FileDescriptorSet> {
+    //options.proto extends google/protobuf/descriptor.proto
+    let include_path = protoc_bin_vendored::include_path()?;
+    let descriptor_set = protobuf_parse::Parser::new()
+        .protoc()
+        .protoc_path(&protoc_bin_vendored::protoc_bin_path()?)
+        .includes([proto_path, &include_path])
+        .inputs(include_all_protos(proto_path)?)
+        .file_descriptor_set()?;
+    Ok(descriptor_set)
+}
+
+pub fn include_all_protos(p: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut all_protos = vec![];
+    let paths = fs::read_dir(p)?;
+    for p in paths {
+        let p = p?;
+        if let Some(ext) = p.path().extension() {
+            if ext == "proto" {
+                all_protos.push(p.path())
+            }
+        }
+    }
+    Ok(all_protos)
+}
+
+/// a message declaring `msg_id` and `direction`
+pub struct Variant {
+    pub id: i32,
+    /// message name, also the variant name
+    pub name: String,
+    /// `.package.Name` as protoc writes type names
+    pub full_name: String,
+    pub rust_path: String,
+}
+
+fn read_varint_option(options: &protobuf::descriptor::MessageOptions, number: u32) -> Option<u64> {
+    match options.special_fields.unknown_fields().get(number) {
+        Some(protobuf::UnknownValueRef::Varint(value)) => Some(value),
+        _ => None,
+    }
+}
+
+/// the messages of each direction sorted by id, in the order of [DIRECTIONS]
+pub fn collect_message_ids(descriptor_set: &FileDescriptorSet) -> anyhow::Result<Vec<Vec<Variant>>> {
+    let mut directions: Vec<Vec<Variant>> = DIRECTIONS.iter().map(|_| vec![]).collect();
+    for file in &descriptor_set.file {
+        //protobuf-codegen names the module after the proto file
+        let module = Path::new(file.name())
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or(anyhow!("invalid proto file name {}", file.name()))?
+            .to_string();
+        for message in &file.message_type {
+            let full_name = format!(".{}.{}", file.package(), message.name());
+            for nested in &message.nested_type {
+                if read_varint_option(&nested.options, MSG_ID_OPTION).is_some() {
+                    return Err(anyhow!("{}.{} msg_id only support top level message", full_name, nested.name()));
+                }
+            }
+            let id = read_varint_option(&message.options, MSG_ID_OPTION);
+            let direction = read_varint_option(&message.options, DIRECTION_OPTION);
+            let (id, direction) = match (id, direction) {
+                (None, None) => continue,
+                (Some(id), Some(direction)) => (id as i32, direction),
+                (_, _) => return Err(anyhow!("{} must declare both msg_id and direction", full_name)),
+            };
+            let index = DIRECTIONS.iter().position(|(value, ..)| *value == direction)
+                .ok_or(anyhow!("{} invalid direction {}", full_name, direction))?;
+            if id <= 0 || id > u16::MAX as i32 {
+                return Err(anyhow!("{} msg_id {} out of range 1..={}", full_name, id, u16::MAX));
+            }
+            let variants = &mut directions[index];
+            if let Some(other) = variants.iter().find(|v| v.id == id) {
+                return Err(anyhow!("{} msg_id {} duplicated by {} and {}", DIRECTIONS[index].1, id, other.full_name, full_name));
+            }
+            if let Some(other) = variants.iter().find(|v| v.name == message.name()) {
+                return Err(anyhow!("{} and {} have the same name in {}", other.full_name, full_name, DIRECTIONS[index].1));
+            }
+            variants.push(Variant {
+                id,
+                name: message.name().to_string(),
+                rust_path: format!("crate::{}::{}", module, message.name()),
+                full_name,
+            });
+        }
+    }
+    for variants in &mut directions {
+        variants.sort_by_key(|v| v.id);
+    }
+    Ok(directions)
+}
+
+/// what deployed clients depend on: the message behind every id and the field layout of every message
+#[derive(Default, PartialEq)]
+pub struct Schema {
+    /// (direction, id) -> message full name, the directions keep the names of the former envelopes
+    envelopes: BTreeMap<(String, i32), String>,
+    /// message full name -> field number -> (field name, label, type)
+    messages: BTreeMap<String, BTreeMap<i32, (String, String, String)>>,
+}
+
+impl Schema {
+    pub fn from_descriptor_set(descriptor_set: &FileDescriptorSet, directions: &[Vec<Variant>]) -> Self {
+        let mut schema = Schema::default();
+        for file in &descriptor_set.file {
+            let package = format!(".{}", file.package());
+            for message in &file.message_type {
+                schema.add_message(&package, message);
+            }
+        }
+        for ((_, envelope, ..), variants) in DIRECTIONS.iter().zip(directions) {
+            for v in variants {
+                schema.envelopes.insert((envelope.to_string(), v.id), v.full_name.clone());
+            }
+        }
+        schema
+    }
+
+    fn add_message(&mut self, parent: &str, message: &DescriptorProto) {
+        let full_name = format!("{}.{}", parent, message.name());
+        let fields = message.field.iter()
+            .map(|field| {
+                let label = match field.label() {
+                    Label::LABEL_REPEATED => "repeated",
+                    Label::LABEL_REQUIRED => "required",
+                    Label::LABEL_OPTIONAL => "singular",
+                };
+                let type_ = match field.type_() {
+                    Type::TYPE_MESSAGE | Type::TYPE_ENUM => field.type_name().to_string(),
+                    t => format!("{:?}", t).trim_start_matches("TYPE_").to_lowercase(),
+                };
+                (field.number(), (field.name().to_string(), label.to_string(), type_))
+            })
+            .collect();
+        self.messages.insert(full_name.clone(), fields);
+        for nested in &message.nested_type {
+            self.add_message(&full_name, nested);
+        }
+    }
+
+    pub fn parse(lock: &str) -> anyhow::Result<Self> {
+        let mut schema = Schema::default();
+        for (index, line) in lock.lines().enumerate() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                [] => {}
+                [comment, ..] if comment.starts_with('#') => {}
+                ["envelope", envelope, id, message] => {
+                    schema.envelopes.insert((envelope.to_string(), id.parse()?), message.to_string());
+                }
+                ["message", message] => {
+                    schema.messages.insert(message.to_string(), BTreeMap::new());
+                }
+                ["field", message, number, name, label, type_] => {
+                    schema.messages.entry(message.to_string()).or_default()
+                        .insert(number.parse()?, (name.to_string(), label.to_string(), type_.to_string()));
+                }
+                _ => return Err(anyhow!("{} line {} invalid: {}", SCHEMA_LOCK, index + 1, line)),
+            }
+        }
+        Ok(schema)
+    }
+
+    pub fn to_lock(&self) -> anyhow::Result<String> {
+        let mut lock = String::new();
+        writeln!(lock, "# generated by `cargo run -p schema`, the wire ids and field layouts deployed clients depend on")?;
+        writeln!(lock, "# the build fails on incompatible changes against it, accepting them needs --allow-incompatible")?;
+        for ((envelope, id), message) in &self.envelopes {
+            writeln!(lock, "envelope {} {} {}", envelope, id, message)?;
+        }
+        for (message, fields) in &self.messages {
+            writeln!(lock, "message {}", message)?;
+            for (number, (name, label, type_)) in fields {
+                writeln!(lock, "field {} {} {} {} {}", message, number, name, label, type_)?;
+            }
+        }
+        Ok(lock)
+    }
+}
+
+/// field numbers removed from a message are only compatible if they are reserved
+pub fn reserved_numbers(descriptor_set: &FileDescriptorSet) -> HashMap<String, Vec<(i32, i32)>> {
+    fn add(reserved: &mut HashMap<String, Vec<(i32, i32)>>, parent: &str, message: &DescriptorProto) {
+        let full_name = format!("{}.{}", parent, message.name());
+        //reserved ranges are end exclusive in the descriptor
+        let ranges = message.reserved_range.iter().map(|range| (range.start(), range.end())).collect();
+        reserved.insert(full_name.clone(), ranges);
+        for nested in &message.nested_type {
+            add(reserved, &full_name, nested);
+        }
+    }
+    let mut reserved = HashMap::new();
+    for file in &descriptor_set.file {
+        let package = format!(".{}", file.package());
+        for message in &file.message_type {
+            add(&mut reserved, &package, message);
+        }
+    }
+    reserved
+}
+
+/// every way `current` breaks clients built from `locked`
+pub fn incompatible_changes(locked: &Schema, current: &Schema, reserved: &HashMap<String, Vec<(i32, i32)>>) -> Vec<String> {
+    let mut changes = vec![];
+    for ((envelope, id), message) in &locked.envelopes {
+        match current.envelopes.get(&(envelope.clone(), *id)) {
+            None => changes.push(format!("{} id {} ({}) removed", envelope, id, message)),
+            Some(current_message) if current_message != message => {
+                changes.push(format!("{} id {} reused, {} -> {}", envelope, id, message, current_message));
+            }
+            Some(_) => {}
+        }
+    }
+    for (message, fields) in &locked.messages {
+        let Some(current_fields) = current.messages.get(message) else {
+            changes.push(format!("message {} removed", message));
+            continue;
+        };
+        for (number, (name, label, type_)) in fields {
+            match current_fields.get(number) {
+                None => {
+                    let is_reserved = reserved.get(message)
+                        .map(|ranges| ranges.iter().any(|(start, end)| (*start..*end).contains(number)))
+                        .unwrap_or(false);
+                    if is_reserved.not() {
+                        changes.push(format!("{} field {} ({}) removed without reserving the number", message, number, name));
+                    }
+                }
+                Some((_, current_label, current_type)) if current_label != label || current_type != type_ => {
+                    changes.push(format!("{} field {} ({}) type changed, {} {} -> {} {}", message, number, name, label, type_, current_label, current_type));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    changes
+}
+
+/// compare the schema with the checked in lock without touching it, incompatible changes fail,
+/// false if the lock is only behind compatible ones like a new message
+pub fn check_lock(lock_path: &Path, descriptor_set: &FileDescriptorSet, directions: &[Vec<Variant>]) -> anyhow::Result<bool> {
+    let current = Schema::from_descriptor_set(descriptor_set, directions);
+    if lock_path.exists().not() {
+        return Err(anyhow!("{} not found, create it with `cargo run -p schema`", lock_path.display()));
+    }
+    let locked = Schema::parse(&fs::read_to_string(lock_path)?)?;
+    if locked == current {
+        return Ok(true);
+    }
+    let changes = incompatible_changes(&locked, &current, &reserved_numbers(descriptor_set));
+    if changes.is_empty() {
+        return Ok(false);
+    }
+    Err(anyhow!(incompatible_message(&changes)?))
+}
+
+/// rewrite the lock from the schema, incompatible changes are refused unless allowed
+pub fn update_lock(lock_path: &Path, descriptor_set: &FileDescriptorSet, directions: &[Vec<Variant>], allow_incompatible: bool) -> anyhow::Result<()> {
+    let current = Schema::from_descriptor_set(descriptor_set, directions);
+    if lock_path.exists() && allow_incompatible.not() {
+        let locked = Schema::parse(&fs::read_to_string(lock_path)?)?;
+        let changes = incompatible_changes(&locked, &current, &reserved_numbers(descriptor_set));
+        if changes.is_empty().not() {
+            return Err(anyhow!(incompatible_message(&changes)?));
+        }
+    }
+    fs::write(lock_path, current.to_lock()?)?;
+    Ok(())
+}
+
+fn incompatible_message(changes: &[String]) -> anyhow::Result<String> {
+    let mut message = format!("incompatible protocol changes against {}:", SCHEMA_LOCK);
+    for change in changes {
+        write!(message, "\n  {}", change)?;
+    }
+    write!(message, "\nrevert them, or run `cargo run -p schema -- --allow-incompatible` if every deployed client will be upgraded")?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use protobuf::descriptor::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+    use protobuf::descriptor::field_descriptor_proto::Type;
+
+    use crate::{check_lock, collect_message_ids, DIRECTION_OPTION, MSG_ID_OPTION, update_lock};
+
+    fn message(name: &str, id: u64) -> DescriptorProto {
+        let mut message = DescriptorProto::new();
+        message.set_name(name.to_string());
+        let mut field = FieldDescriptorProto::new();
+        field.set_name("id".to_string());
+        field.set_number(1);
+        field.set_type(Type::TYPE_INT32);
+        message.field.push(field);
+        let unknown = message.options.mut_or_insert_default().special_fields.mut_unknown_fields();
+        unknown.add_varint(MSG_ID_OPTION, id);
+        unknown.add_varint(DIRECTION_OPTION, 1);
+        message
+    }
+
+    fn descriptor_set(messages: Vec<DescriptorProto>) -> FileDescriptorSet {
+        let mut file = FileDescriptorProto::new();
+        file.set_name("test.proto".to_string());
+        file.set_package("com.example".to_string());
+        file.message_type = messages;
+        let mut set = FileDescriptorSet::new();
+        set.file.push(file);
+        set
+    }
+
+    #[test]
+    fn test_check_lock() {
+        let lock = std::env::temp_dir().join(format!("schema-test-{}.lock", std::process::id()));
+        let locked = descriptor_set(vec![message("LoginReq", 1)]);
+        update_lock(&lock, &locked, &collect_message_ids(&locked).unwrap(), false).unwrap();
+        assert!(check_lock(&lock, &locked, &collect_message_ids(&locked).unwrap()).unwrap());
+
+        //a new message is compatible, the lock is only behind
+        let added = descriptor_set(vec![message("LoginReq", 1), message("MoveReq", 2)]);
+        assert!(!check_lock(&lock, &added, &collect_message_ids(&added).unwrap()).unwrap());
+
+        //its id taken by another message isn't
+        let reused = descriptor_set(vec![message("MoveReq", 1)]);
+        let err = check_lock(&lock, &reused, &collect_message_ids(&reused).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("reused"), "{}", err);
+        fs::remove_file(lock).unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+use std::path::Path;
+
+use schema::{collect_message_ids, descriptor_set, PROTO_PATH, SCHEMA_LOCK, update_lock};
+
+/// rewrite protocol/schema.lock after a protocol change, pass `--allow-incompatible` to accept
+/// changes which break deployed clients
+fn main() -> anyhow::Result<()> {
+    let allow_incompatible = std::env::args().skip(1).any(|arg| arg == "--allow-incompatible");
+    let protocol = Path::new(env!("CARGO_MANIFEST_DIR")).join("../protocol");
+    let descriptor_set = descriptor_set(&protocol.join(PROTO_PATH))?;
+    let directions = collect_message_ids(&descriptor_set)?;
+    let lock_path = protocol.join(SCHEMA_LOCK);
+    update_lock(&lock_path, &descriptor_set, &directions, allow_incompatible)?;
+    println!("{} updated", SCHEMA_LOCK);
+    Ok(())
+}